@@ -0,0 +1,533 @@
+//! Remote xtender agents. An xtender running the templates, the controller, can dispatch checks
+//! to agents in network zones it can't reach the devices of itself. The controller resolves the
+//! variables of a check and sends the resulting invocation to the agent over mutual TLS. The
+//! agent runs it with its own executors and returns the status and output, which the controller
+//! processes like the result of any local check, so all results end up in one Toolkit output.
+//!
+//! Each message is a JSON document preceded by its length as a 32-bit big-endian integer.
+
+use crate::check::TimeoutMessage;
+use crate::executor::{default_executor_name, executor, Executor, ExecutorOutput, Invocation};
+use crate::net::{connect, tls_accept, tls_connect};
+use crate::nrpe::server::tls_acceptor;
+use crate::ssh::SSH_EXECUTOR;
+use futures::future::BoxFuture;
+use log::{debug, error, info};
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{error::Elapsed, timeout};
+
+pub const DEFAULT_AGENT_PORT: u16 = 5667;
+pub const DEFAULT_AGENT_LISTEN: &str = "0.0.0.0:5667";
+/// Prefix of the names the executors of the agents are registered under, such as
+/// `agent:zone-a`.
+pub const AGENT_EXECUTOR_PREFIX: &str = "agent:";
+const PROTOCOL_VERSION: u32 = 1;
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// How long connecting, the TLS handshake and sending a message may take.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time given to an agent to answer on top of the timeout of the check.
+const RESPONSE_MARGIN: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize, Serialize)]
+struct AgentRequest {
+    version: u32,
+    invocation: Invocation,
+}
+
+/// The name of the executor that dispatches checks to the named agent.
+pub fn agent_executor_name(agent: &str) -> String {
+    format!("{}{}", AGENT_EXECUTOR_PREFIX, agent)
+}
+
+/// An agent given as `name=host` or `name=host:port`, such as `zone-a=10.1.0.5:5667`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgentAddress {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AgentAddressParseError(String);
+
+impl std::error::Error for AgentAddressParseError {}
+
+impl fmt::Display for AgentAddressParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid agent: {}, expected <name>=<host> or <name>=<host>:<port>",
+            self.0
+        )
+    }
+}
+
+impl FromStr for AgentAddress {
+    type Err = AgentAddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AgentAddressParseError(s.to_string());
+        let (name, address) = s.split_once('=').ok_or_else(invalid)?;
+        let (name, address) = (name.trim(), address.trim());
+
+        // IPv6 addresses are given in brackets, like in URLs.
+        let (host, port) = match address.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+                match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None if rest.is_empty() => (host, None),
+                    None => return Err(invalid()),
+                }
+            }
+            None => match address.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse::<u16>().map_err(|_| invalid())?,
+            None => DEFAULT_AGENT_PORT,
+        };
+
+        if name.is_empty() || host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for AgentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// A TLS connector for the controller, which presents its certificate to the agents and only
+/// trusts agents with a certificate signed by the CA.
+pub fn controller_connector(
+    cert: &Path,
+    key: &Path,
+    ca: &Path,
+) -> Result<SslConnector, openssl::error::ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    builder.set_certificate_chain_file(cert)?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_ca_file(ca)?;
+    builder.set_verify(SslVerifyMode::PEER);
+    Ok(builder.build())
+}
+
+/// A TLS acceptor for an agent, which only accepts controllers with a client certificate signed
+/// by the CA.
+pub fn agent_acceptor(
+    cert: &Path,
+    key: &Path,
+    ca: &Path,
+) -> Result<SslAcceptor, openssl::error::ErrorStack> {
+    tls_acceptor(cert, key, Some(ca))
+}
+
+async fn write_message<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> io::Result<()> {
+    let json = serde_json::to_vec(message)?;
+    if json.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The message is too large",
+        ));
+    }
+    writer.write_all(&(json.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&json).await?;
+    writer.flush().await
+}
+
+async fn read_message<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R) -> io::Result<T> {
+    let mut length = [0; 4];
+    reader.read_exact(&mut length).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The message is too large",
+        ));
+    }
+    let mut json = vec![0; length];
+    reader.read_exact(&mut json).await?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+fn timed_out(_: Elapsed) -> io::Error {
+    io::Error::from(io::ErrorKind::TimedOut)
+}
+
+/// Dispatches checks to an agent. It's registered under `agent_executor_name` of the agent.
+pub struct AgentExecutor {
+    address: AgentAddress,
+    connector: SslConnector,
+}
+
+impl AgentExecutor {
+    pub fn new(address: AgentAddress, connector: SslConnector) -> Self {
+        Self { address, connector }
+    }
+}
+
+impl Executor for AgentExecutor {
+    fn execute<'a>(&'a self, invocation: &'a Invocation) -> BoxFuture<'a, ExecutorOutput> {
+        Box::pin(async move {
+            let request = AgentRequest {
+                version: PROTOCOL_VERSION,
+                invocation: invocation.clone(),
+            };
+
+            let dispatched = dispatch(&self.address, &self.connector, &request).await;
+
+            dispatched.unwrap_or_else(|e| {
+                debug!(
+                    "Failed to dispatch {} to {}: {}",
+                    invocation.name, self.address, e
+                );
+                ExecutorOutput::message(
+                    3,
+                    &format!(
+                        "AGENT UNKNOWN - Failed to run the check on agent {} ({}): {}",
+                        self.address.name, self.address, e
+                    ),
+                )
+            })
+        })
+    }
+}
+
+async fn dispatch(
+    address: &AgentAddress,
+    connector: &SslConnector,
+    request: &AgentRequest,
+) -> io::Result<ExecutorOutput> {
+    let mut stream = timeout(CONNECTION_TIMEOUT, async {
+        let stream = connect(&address.host, address.port).await?;
+        let config = connector.configure().map_err(io::Error::other)?;
+        let mut stream = tls_connect(config, &address.host, stream).await?;
+        write_message(&mut stream, request).await?;
+        Ok::<_, io::Error>(stream)
+    })
+    .await
+    .map_err(timed_out)??;
+
+    timeout(
+        request.invocation.timeout + RESPONSE_MARGIN,
+        read_message(&mut stream),
+    )
+    .await
+    .map_err(timed_out)?
+}
+
+/// Run an invocation received from a controller with the executors of this xtender, within its
+/// timeout.
+pub async fn run_invocation(invocation: &Invocation) -> ExecutorOutput {
+    let Some(executable) = invocation.argv.first() else {
+        return ExecutorOutput::message(3, "UNKNOWN: Empty command");
+    };
+    let executor_name = match invocation.ssh {
+        Some(_) => SSH_EXECUTOR,
+        None => default_executor_name(executable),
+    };
+    let Some(executor) = executor(executor_name) else {
+        return ExecutorOutput::message(
+            3,
+            &format!("UNKNOWN: Unknown executor: {}", executor_name),
+        );
+    };
+
+    timeout(invocation.timeout, executor.execute(invocation))
+        .await
+        .unwrap_or_else(|_| {
+            ExecutorOutput::message(3, &TimeoutMessage::from(invocation.timeout).to_string())
+        })
+}
+
+/// Runs the checks sent by controllers that present a certificate signed by the CA of the
+/// acceptor.
+pub struct AgentServer {
+    acceptor: Arc<SslAcceptor>,
+}
+
+impl AgentServer {
+    pub fn new(acceptor: SslAcceptor) -> Self {
+        Self {
+            acceptor: Arc::new(acceptor),
+        }
+    }
+
+    /// Listen on the address and run checks until SIGINT or SIGTERM is received.
+    pub async fn run(self, listen: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(listen).await?;
+        info!("Listening for controllers on {}", listener.local_addr()?);
+        self.serve(listener).await
+    }
+
+    /// Run checks sent to a bound listener until SIGINT or SIGTERM is received.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(a) => a,
+                        Err(e) => {
+                            error!("Failed to accept a connection: {}", e);
+                            continue;
+                        }
+                    };
+                    let acceptor = Arc::clone(&self.acceptor);
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, acceptor).await {
+                            error!("Failed to run the check sent by {}: {}", peer, e);
+                        }
+                    });
+                }
+                _ = sigint.recv() => {
+                    info!("Received SIGINT, stopping");
+                    return Ok(());
+                }
+                _ = sigterm.recv() => {
+                    info!("Received SIGTERM, stopping");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, acceptor: Arc<SslAcceptor>) -> io::Result<()> {
+    let (mut stream, request) = timeout(CONNECTION_TIMEOUT, async {
+        let mut stream = tls_accept(&acceptor, stream).await?;
+        let request: AgentRequest = read_message(&mut stream).await?;
+        Ok::<_, io::Error>((stream, request))
+    })
+    .await
+    .map_err(timed_out)??;
+
+    // The invocation holds decrypted secrets, so only its name is logged.
+    debug!("Running {} for a controller", request.invocation.name);
+    let output = match request.version {
+        PROTOCOL_VERSION => run_invocation(&request.invocation).await,
+        version => ExecutorOutput::message(
+            3,
+            &format!("AGENT UNKNOWN - Unsupported protocol version {}", version),
+        ),
+    };
+
+    timeout(CONNECTION_TIMEOUT, write_message(&mut stream, &output))
+        .await
+        .map_err(timed_out)?
+}
+
+#[cfg(test)]
+mod agent_test {
+    use super::*;
+    use crate::builtin::self_signed_certificate;
+    use crate::check::CheckBuilder;
+    use crate::executor::register_executor;
+    use pretty_assertions::assert_eq;
+
+    /// Certificate and key files for the agent and the controller. Each trusts the self-signed
+    /// certificate of the other.
+    struct Certificates {
+        _dir: tempfile::TempDir,
+        agent: (std::path::PathBuf, std::path::PathBuf),
+        controller: (std::path::PathBuf, std::path::PathBuf),
+    }
+
+    fn certificates() -> Certificates {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str| {
+            let (key, certificate) = self_signed_certificate(30);
+            let cert_path = dir.path().join(format!("{}.pem", name));
+            let key_path = dir.path().join(format!("{}.key", name));
+            std::fs::write(&cert_path, certificate.to_pem().unwrap()).unwrap();
+            std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+            (cert_path, key_path)
+        };
+        let agent = write("agent");
+        let controller = write("controller");
+        Certificates {
+            _dir: dir,
+            agent,
+            controller,
+        }
+    }
+
+    async fn start_agent(certificates: &Certificates) -> u16 {
+        let (cert, key) = &certificates.agent;
+        let acceptor = agent_acceptor(cert, key, &certificates.controller.0).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(AgentServer::new(acceptor).serve(listener));
+        port
+    }
+
+    #[test]
+    fn test_agent_address_from_str() {
+        assert_eq!(
+            AgentAddress::from_str("zone-a=10.1.0.5:6000"),
+            Ok(AgentAddress {
+                name: "zone-a".to_string(),
+                host: "10.1.0.5".to_string(),
+                port: 6000
+            })
+        );
+        let address = AgentAddress::from_str("zone-b=[::1]").unwrap();
+        assert_eq!(
+            (address.host.as_str(), address.port),
+            ("::1", DEFAULT_AGENT_PORT)
+        );
+        assert_eq!(address.to_string(), "[::1]:5667");
+        assert!(AgentAddress::from_str("agent.example.com").is_err());
+        assert!(AgentAddress::from_str("zone-a=host:port").is_err());
+        assert!(AgentAddress::from_str("=host").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dispatches_checks_to_agent() -> Result<(), Box<dyn std::error::Error>> {
+        let certificates = certificates();
+        let port = start_agent(&certificates).await;
+        let (cert, key) = &certificates.controller;
+        let connector = controller_connector(cert, key, &certificates.agent.0)?;
+        let address = AgentAddress::from_str(&format!("zone-a=localhost:{}", port))?;
+        register_executor(
+            &agent_executor_name("zone-a"),
+            AgentExecutor::new(address, connector),
+        );
+
+        let result = CheckBuilder::new()
+            .name("remote")
+            .command("sh -c 'echo \"DISK WARNING on $NAGIOS_CHECK_NAME|used=80%\"; exit 1'")
+            .executor(&agent_executor_name("zone-a"))
+            .build()?
+            .run_async()
+            .await;
+
+        assert_eq!(result.status(), Some(1));
+        assert_eq!(result.short_output(), "DISK WARNING on remote");
+        assert_eq!(result.performance_data(), "used=80%");
+
+        let result = CheckBuilder::new()
+            .name("remote file")
+            .command("builtin:file --file /nonexistent/xtender")
+            .executor(&agent_executor_name("zone-a"))
+            .build()?
+            .run_async()
+            .await;
+
+        assert_eq!(result.status(), Some(2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_agent_rejects_controller_without_trusted_certificate(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let certificates = certificates();
+        let port = start_agent(&certificates).await;
+        // A controller presenting the certificate of the agent, which the agent doesn't trust.
+        let (cert, key) = &certificates.agent;
+        let connector = controller_connector(cert, key, &certificates.agent.0)?;
+        let address = AgentAddress::from_str(&format!("zone-b=localhost:{}", port))?;
+        register_executor(
+            &agent_executor_name("zone-b"),
+            AgentExecutor::new(address, connector),
+        );
+
+        let result = CheckBuilder::new()
+            .name("remote")
+            .command("echo hello")
+            .executor(&agent_executor_name("zone-b"))
+            .build()?
+            .run_async()
+            .await;
+
+        assert_eq!(result.status(), Some(3));
+        assert!(result
+            .short_output()
+            .starts_with("AGENT UNKNOWN - Failed to run the check on agent zone-b"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timeout_closes_the_connection_to_a_stuck_agent(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let certificates = certificates();
+        // A listener that accepts the connection but never completes the handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let (cert, key) = &certificates.controller;
+        let connector = controller_connector(cert, key, &certificates.agent.0)?;
+        let address = AgentAddress::from_str(&format!("zone-c=localhost:{}", port))?;
+        register_executor(
+            &agent_executor_name("zone-c"),
+            AgentExecutor::new(address, connector),
+        );
+
+        let check = CheckBuilder::new()
+            .name("remote")
+            .command("echo hello")
+            .executor(&agent_executor_name("zone-c"))
+            .timeout(1)
+            .build()?;
+        let (result, accepted) = tokio::join!(check.run_async(), listener.accept());
+
+        assert_eq!(result.status(), Some(3));
+        // The timeout cancelled the handshake, so the socket is closed once the client hello
+        // has been read.
+        let (mut stream, _) = accepted?;
+        let mut buf = Vec::new();
+        let read = timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(_))), "{:?}", read);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_invocation_times_out() {
+        let invocation = Invocation {
+            name: "sleeping".to_string(),
+            argv: vec!["sleep".to_string(), "10".to_string()],
+            timeout: Duration::from_secs(1),
+            limits: Default::default(),
+            env: vec![],
+            ssh: None,
+        };
+
+        assert_eq!(
+            run_invocation(&invocation).await,
+            ExecutorOutput::message(3, "UNKNOWN: Timed out after 1 second")
+        );
+    }
+}
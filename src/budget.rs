@@ -0,0 +1,294 @@
+use crate::check::{wall_time, Checks, ChecksExt};
+use crate::daemon::write_atomically;
+use crate::result::{escape_chars, CheckResults};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Number of measured execution times kept per check.
+pub const HISTORY_SAMPLES: usize = 20;
+/// Number of checks listed as dominating the expected time.
+const DOMINANT_CHECKS: usize = 5;
+
+/// The most recent execution times of each check, by check name.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ExecutionHistory {
+    checks: BTreeMap<String, Vec<f64>>,
+}
+
+/// How long a set of checks can take, and is expected to take, compared to the sample interval.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunBudget {
+    pub checks: usize,
+    pub max_parallel: Option<usize>,
+    pub sample_interval: Duration,
+    pub total_time_from_timeouts: Duration,
+    pub worst_case_time: Duration,
+    pub expected_time: Option<Duration>,
+    pub checks_with_history: usize,
+    /// The checks with the longest expected execution times, with their share of the total.
+    pub dominant_checks: Vec<(String, Duration, f64)>,
+}
+
+impl ExecutionHistory {
+    /// Load the history from a file. A missing file is an empty history.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_atomically(path.as_ref(), &serde_json::to_string(self)?)
+    }
+
+    /// Add the execution times of the checks that ran. Results served from the result cache
+    /// didn't run, so they're skipped.
+    pub fn record(&mut self, results: &CheckResults) {
+        for result in results.0.iter().filter(|r| r.cache_age().is_none()) {
+            if let Some(secs) = parse_execution_time(&result.execution_time()) {
+                let samples = self.checks.entry(result.name()).or_default();
+                samples.push(secs);
+                if samples.len() > HISTORY_SAMPLES {
+                    samples.drain(..samples.len() - HISTORY_SAMPLES);
+                }
+            }
+        }
+    }
+
+    /// The median of the recorded execution times of a check.
+    pub fn expected_time(&self, name: &str) -> Option<Duration> {
+        let mut samples = self.checks.get(name)?.clone();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_by(f64::total_cmp);
+        let middle = samples.len() / 2;
+        let median = if samples.len() % 2 == 0 {
+            (samples[middle - 1] + samples[middle]) / 2.0
+        } else {
+            samples[middle]
+        };
+        Some(Duration::from_secs_f64(median.max(0.0)))
+    }
+}
+
+impl RunBudget {
+    /// Calculate the budget of the checks. Without a history of a check, its timeout is used as
+    /// its expected time.
+    pub fn new(
+        checks: &Checks,
+        max_parallel: Option<usize>,
+        sample_interval: Duration,
+        history: Option<&ExecutionHistory>,
+    ) -> Self {
+        let mut checks_with_history = 0;
+        let expected = checks
+            .iter()
+            .map(|check| {
+                // The history is keyed by the name of the result, where commas and newlines
+                // are escaped.
+                let expected = history.and_then(|h| h.expected_time(&escape_chars(check.name())));
+                if expected.is_some() {
+                    checks_with_history += 1;
+                }
+                (
+                    check.name().to_string(),
+                    expected.unwrap_or(Duration::from_secs(check.timeout())),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let expected_time =
+            history.map(|_| wall_time(expected.iter().map(|(_, d)| *d), max_parallel));
+
+        let total_expected: Duration = expected.iter().map(|(_, d)| *d).sum();
+        let mut dominant_checks = match history {
+            Some(_) => expected
+                .into_iter()
+                .map(|(name, d)| {
+                    let share = match total_expected.is_zero() {
+                        true => 0.0,
+                        false => d.as_secs_f64() / total_expected.as_secs_f64(),
+                    };
+                    (name, d, share)
+                })
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        dominant_checks.sort_by_key(|(_, d, _)| std::cmp::Reverse(*d));
+        dominant_checks.truncate(DOMINANT_CHECKS);
+
+        Self {
+            checks: checks.len(),
+            max_parallel,
+            sample_interval,
+            total_time_from_timeouts: checks.total_time_from_timeouts(),
+            worst_case_time: checks.worst_case_time(max_parallel),
+            expected_time,
+            checks_with_history,
+            dominant_checks,
+        }
+    }
+
+    /// Whether a sample could take longer than the sample interval.
+    pub fn can_overrun(&self) -> bool {
+        self.worst_case_time > self.sample_interval
+    }
+
+    pub fn as_text(&self) -> String {
+        let mut lines = vec![
+            format!("Checks: {}", self.checks),
+            format!(
+                "Max parallel: {}",
+                self.max_parallel
+                    .map(|n| n.to_string())
+                    .unwrap_or_else(|| "unlimited".to_string())
+            ),
+            format!("Sample interval: {}", format_seconds(self.sample_interval)),
+            format!(
+                "Sum of timeouts: {}",
+                format_seconds(self.total_time_from_timeouts)
+            ),
+            format!("Worst case: {}", format_seconds(self.worst_case_time)),
+        ];
+
+        if self.can_overrun() {
+            lines.push(format!(
+                "Warning: The worst case is {} longer than the sample interval, so a sample could overrun",
+                format_seconds(self.worst_case_time - self.sample_interval)
+            ));
+        }
+
+        if let Some(expected_time) = self.expected_time {
+            lines.push(format!(
+                "Expected: {}, from the history of {} of {} checks",
+                format_seconds(expected_time),
+                self.checks_with_history,
+                self.checks
+            ));
+            if expected_time > self.sample_interval {
+                lines.push(
+                    "Warning: The expected time is longer than the sample interval".to_string(),
+                );
+            }
+            lines.push(String::new());
+            lines.push("Dominant checks:".to_string());
+            for (name, expected, share) in &self.dominant_checks {
+                lines.push(format!(
+                    "  {} {} ({:.0}%)",
+                    name,
+                    format_seconds(*expected),
+                    share * 100.0
+                ));
+            }
+        }
+
+        lines.join("\n") + "\n"
+    }
+}
+
+fn parse_execution_time(s: &str) -> Option<f64> {
+    s.trim().strip_suffix(" s")?.parse().ok()
+}
+
+fn format_seconds(d: Duration) -> String {
+    format!("{:.1} s", d.as_secs_f64())
+}
+
+#[cfg(test)]
+mod budget_test {
+    use super::*;
+    use crate::check::CheckBuilder;
+    use crate::result::CheckResultBuilder;
+    use pretty_assertions::assert_eq;
+
+    fn result(name: &str, secs: u64) -> crate::result::CheckResult {
+        CheckResultBuilder::new()
+            .name(name)
+            .with_execution_time(Duration::from_secs(secs))
+            .build()
+    }
+
+    #[test]
+    fn test_history_keeps_recent_samples() {
+        let mut history = ExecutionHistory::default();
+        for secs in 0..30 {
+            history.record(&CheckResults(vec![result("a", secs)]));
+        }
+        history.record(&CheckResults(vec![
+            result("b", 100).with_cache_age(Duration::from_secs(1), Duration::from_secs(60))
+        ]));
+
+        assert_eq!(history.checks["a"].len(), HISTORY_SAMPLES);
+        assert_eq!(history.checks["a"][0], 10.0);
+        assert_eq!(history.expected_time("b"), None);
+        // The median of 10..=29.
+        assert_eq!(
+            history.expected_time("a"),
+            Some(Duration::from_secs_f64(19.5))
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        let checks = vec![
+            CheckBuilder::new().name("slow").timeout(30).build_raw(),
+            CheckBuilder::new().name("fast").timeout(30).build_raw(),
+            CheckBuilder::new().name("new").timeout(5).build_raw(),
+        ];
+        let mut history = ExecutionHistory::default();
+        history.record(&CheckResults(vec![result("slow", 12), result("fast", 1)]));
+
+        let budget = RunBudget::new(&checks, Some(1), Duration::from_secs(60), Some(&history));
+
+        assert_eq!(budget.worst_case_time, Duration::from_secs(65));
+        assert!(budget.can_overrun());
+        assert_eq!(budget.expected_time, Some(Duration::from_secs(18)));
+        assert_eq!(budget.checks_with_history, 2);
+        assert_eq!(
+            budget
+                .dominant_checks
+                .iter()
+                .map(|(name, _, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["slow", "new", "fast"]
+        );
+        assert!(budget
+            .as_text()
+            .contains("Warning: The worst case is 5.0 s longer than the sample interval"));
+    }
+
+    #[test]
+    fn test_budget_with_escaped_name() {
+        let checks = vec![CheckBuilder::new()
+            .name("disk /var, /tmp")
+            .timeout(30)
+            .build_raw()];
+        let mut history = ExecutionHistory::default();
+        history.record(&CheckResults(vec![result("disk /var, /tmp", 2)]));
+
+        let budget = RunBudget::new(&checks, None, Duration::from_secs(60), Some(&history));
+
+        assert_eq!(budget.checks_with_history, 1);
+        assert_eq!(budget.expected_time, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_budget_without_history() {
+        let checks = vec![CheckBuilder::new().name("a").timeout(10).build_raw()];
+        let budget = RunBudget::new(&checks, None, Duration::from_secs(60), None);
+
+        assert!(!budget.can_overrun());
+        assert_eq!(budget.expected_time, None);
+        assert_eq!(
+            budget.as_text(),
+            "Checks: 1\nMax parallel: unlimited\nSample interval: 60.0 s\nSum of timeouts: 10.0 s\nWorst case: 10.0 s\n"
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Checks implemented inside xtender, run without spawning a plugin process. A builtin check is
+//! a command starting with `builtin:` followed by the name of the check, with its options as
+//! arguments, for example `builtin:tcp --host example.com --port 443`. They produce the same
+//! output as the equivalent Nagios plugin would.
+
+mod dns;
+mod file;
+mod http;
+mod nrpe;
+mod tcp;
+mod tls;
+
+#[cfg(test)]
+pub(crate) use tls::{self_signed_acceptor, self_signed_certificate};
+
+use crate::result::status_name;
+use crate::threshold::Threshold;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const BUILTIN_PREFIX: &str = "builtin:";
+
+const OK: i32 = 0;
+const WARNING: i32 = 1;
+const CRITICAL: i32 = 2;
+const UNKNOWN: i32 = 3;
+
+/// The exit status and the plugin output of a builtin check.
+pub type BuiltinOutput = (i32, String);
+
+/// Whether the executable of a command names a builtin check.
+pub fn is_builtin(executable: &str) -> bool {
+    executable.starts_with(BUILTIN_PREFIX)
+}
+
+/// Run the builtin check named by the first word of `argv`, with the rest as its options. Checks
+/// that talk to a remote service give up after the timeout, and report it in their output.
+pub async fn run_builtin(argv: &[String], timeout: Duration) -> BuiltinOutput {
+    let name = argv
+        .first()
+        .and_then(|a| a.strip_prefix(BUILTIN_PREFIX))
+        .unwrap_or_default();
+
+    let (short_options, known): (fn(&str) -> Option<&'static str>, _) = match name {
+        "dns" => (long_option, dns::OPTIONS),
+        "file" => (long_option, file::OPTIONS),
+        "http" => (long_option, http::OPTIONS),
+        "nrpe" => (nrpe::long_option, nrpe::OPTIONS),
+        "tcp" => (long_option, tcp::OPTIONS),
+        "tls" => (long_option, tls::OPTIONS),
+        _ => {
+            let e = OptionError::UnknownCheck(name.to_string());
+            return (UNKNOWN, format!("UNKNOWN: {}", e));
+        }
+    };
+    let options = match Options::parse_with(&argv[1.min(argv.len())..], short_options)
+        .and_then(|o| o.only(known).map(|_| o))
+    {
+        Ok(o) => o,
+        Err(e) => return (UNKNOWN, format!("UNKNOWN: {}", e)),
+    };
+
+    let result = match name {
+        "dns" => dns::check(&options).await,
+        "file" => file::check(&options),
+        "http" => http::check(&options, timeout).await,
+        "nrpe" => nrpe::check(&options, timeout).await,
+        "tcp" => tcp::check(&options).await,
+        "tls" => tls::check(&options, timeout).await,
+        _ => Err(OptionError::UnknownCheck(name.to_string())),
+    };
+
+    result.unwrap_or_else(|e| (UNKNOWN, format!("UNKNOWN: {}", e)))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OptionError {
+    UnknownCheck(String),
+    MissingValue(String),
+    Missing(String),
+    Invalid(String, String),
+    Unexpected(String),
+}
+
+impl std::error::Error for OptionError {}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionError::UnknownCheck(name) => write!(f, "Unknown builtin check: {}", name),
+            OptionError::MissingValue(option) => write!(f, "Missing value for --{}", option),
+            OptionError::Missing(option) => write!(f, "Missing required option --{}", option),
+            OptionError::Invalid(option, value) => {
+                write!(f, "Invalid value for --{}: {}", option, value)
+            }
+            OptionError::Unexpected(arg) => write!(f, "Unexpected argument: {}", arg),
+        }
+    }
+}
+
+/// The `--name value` options of a builtin check. The short options of the Nagios plugins are
+/// accepted as well. `--args` takes every argument after it, joined with `!` like the arguments
+/// of an NRPE query.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Options(HashMap<String, String>);
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Self, OptionError> {
+        Self::parse_with(args, long_option)
+    }
+
+    /// Parse the options, with the long names of the short options given by `short_option`.
+    pub fn parse_with(
+        args: &[String],
+        short_option: fn(&str) -> Option<&'static str>,
+    ) -> Result<Self, OptionError> {
+        let mut options = HashMap::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let name = match (arg.strip_prefix("--"), arg.strip_prefix('-')) {
+                (Some(long), _) => long.to_string(),
+                (None, Some(short)) => short_option(short)
+                    .ok_or_else(|| OptionError::Unexpected(arg.to_string()))?
+                    .to_string(),
+                _ => return Err(OptionError::Unexpected(arg.to_string())),
+            };
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None if name == "args" => {
+                    let rest = args.by_ref().cloned().collect::<Vec<_>>();
+                    (name, rest.join("!"))
+                }
+                None => match args.next() {
+                    Some(value) => (name, value.to_string()),
+                    None => return Err(OptionError::MissingValue(name)),
+                },
+            };
+            options.insert(name, value);
+        }
+
+        Ok(Self(options))
+    }
+
+    /// Check that every option is one of the known long names, so that a misspelled option
+    /// isn't silently ignored.
+    pub fn only(&self, known: &[&str]) -> Result<(), OptionError> {
+        let mut unknown = self
+            .0
+            .keys()
+            .filter(|name| !known.contains(&name.as_str()))
+            .collect::<Vec<_>>();
+        unknown.sort();
+        match unknown.first() {
+            Some(name) => Err(OptionError::Unexpected(format!("--{}", name))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|s| s.as_str())
+    }
+
+    pub fn required(&self, name: &str) -> Result<&str, OptionError> {
+        self.get(name)
+            .ok_or_else(|| OptionError::Missing(name.to_string()))
+    }
+
+    pub fn parsed<T: FromStr>(&self, name: &str) -> Result<Option<T>, OptionError> {
+        self.get(name)
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| OptionError::Invalid(name.to_string(), v.to_string()))
+            })
+            .transpose()
+    }
+}
+
+fn long_option(short: &str) -> Option<&'static str> {
+    match short {
+        "H" => Some("host"),
+        "p" => Some("port"),
+        "u" => Some("url"),
+        "e" => Some("expect"),
+        "s" => Some("string"),
+        "a" => Some("address"),
+        "f" => Some("file"),
+        "w" => Some("warning"),
+        "c" => Some("critical"),
+        _ => None,
+    }
+}
+
+/// The status of a value against warning and critical ranges in the format of the Nagios plugin
+/// guidelines, so that `5` alerts above 5 and `10:` below 10.
+pub(crate) fn status(value: f64, warning: Option<&Threshold>, critical: Option<&Threshold>) -> i32 {
+    if critical.is_some_and(|c| c.is_alert(value)) {
+        CRITICAL
+    } else if warning.is_some_and(|w| w.is_alert(value)) {
+        WARNING
+    } else {
+        OK
+    }
+}
+
+/// A single performance data value in the format of the Nagios plugins.
+pub(crate) fn perfdata(
+    label: &str,
+    value: impl fmt::Display,
+    uom: &str,
+    warning: Option<&Threshold>,
+    critical: Option<&Threshold>,
+    min: Option<f64>,
+) -> String {
+    let opt = |v: Option<&Threshold>| v.map(|v| v.to_string()).unwrap_or_default();
+    format!(
+        "{}={}{};{};{};{}",
+        label,
+        value,
+        uom,
+        opt(warning),
+        opt(critical),
+        min.map(|v| v.to_string()).unwrap_or_default()
+    )
+}
+
+#[cfg(test)]
+mod builtin_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn args(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options =
+            Options::parse(&args(&["-H", "localhost", "--port=80", "--warning", "1"])).unwrap();
+
+        assert_eq!(options.get("host"), Some("localhost"));
+        assert_eq!(options.parsed::<u16>("port"), Ok(Some(80)));
+        assert_eq!(options.parsed::<f64>("warning"), Ok(Some(1.0)));
+        assert_eq!(options.parsed::<f64>("critical"), Ok(None));
+        assert_eq!(
+            options.required("url"),
+            Err(OptionError::Missing("url".to_string()))
+        );
+        assert_eq!(
+            Options::parse(&args(&["--host"])),
+            Err(OptionError::MissingValue("host".to_string()))
+        );
+        assert_eq!(
+            Options::parse(&args(&["--args", "20%", "10%"]))
+                .unwrap()
+                .get("args"),
+            Some("20%!10%")
+        );
+        assert_eq!(
+            Options::parse(&args(&["localhost"])),
+            Err(OptionError::Unexpected("localhost".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_status_from_thresholds() {
+        let range = |s: &str| Threshold::from_str(s).unwrap();
+        let (warning, critical) = (range("1"), range("2"));
+        assert_eq!(status(0.5, Some(&warning), Some(&critical)), OK);
+        assert_eq!(status(1.5, Some(&warning), Some(&critical)), WARNING);
+        assert_eq!(status(2.5, Some(&warning), Some(&critical)), CRITICAL);
+
+        let (warning, critical) = (range("30:"), range("14:"));
+        assert_eq!(status(20.0, Some(&warning), Some(&critical)), WARNING);
+        assert_eq!(status(10.0, Some(&warning), Some(&critical)), CRITICAL);
+        assert_eq!(status(10.0, None, None), OK);
+
+        let (warning, critical) = (range("10:20"), range("@5"));
+        assert_eq!(status(15.0, Some(&warning), Some(&critical)), OK);
+        assert_eq!(status(25.0, Some(&warning), Some(&critical)), WARNING);
+        assert_eq!(status(3.0, Some(&warning), Some(&critical)), CRITICAL);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_builtin() {
+        assert_eq!(
+            run_builtin(&args(&["builtin:foo"]), Duration::from_secs(1)).await,
+            (UNKNOWN, "UNKNOWN: Unknown builtin check: foo".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_option() {
+        assert_eq!(
+            run_builtin(
+                &args(&["builtin:tcp", "-H", "localhost", "--prot", "80"]),
+                Duration::from_secs(1)
+            )
+            .await,
+            (UNKNOWN, "UNKNOWN: Unexpected argument: --prot".to_string())
+        );
+        assert_eq!(
+            Options::parse(&args(&["--host", "a", "--port=1"]))
+                .unwrap()
+                .only(&["host", "port"]),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use super::{perfdata, status, status_name, BuiltinOutput, OptionError, Options, CRITICAL};
+use crate::threshold::Threshold;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// The long names of the options of the check.
+pub const OPTIONS: &[&str] = &["host", "address", "warning", "critical"];
+
+/// Resolve a host name with the system resolver, like check_dns.
+pub async fn check(options: &Options) -> Result<BuiltinOutput, OptionError> {
+    let host = options.required("host")?;
+    let expected = options
+        .get("address")
+        .map(|a| {
+            a.split(',')
+                .map(|a| {
+                    a.trim()
+                        .parse::<IpAddr>()
+                        .map_err(|_| OptionError::Invalid("address".to_string(), a.to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?;
+    let warning = options.parsed::<Threshold>("warning")?;
+    let critical = options.parsed::<Threshold>("critical")?;
+
+    let start = Instant::now();
+    let mut addresses = match tokio::net::lookup_host((host, 0)).await {
+        Ok(addrs) => addrs.map(|a| a.ip()).collect::<Vec<_>>(),
+        Err(e) => {
+            return Ok((
+                CRITICAL,
+                format!("DNS CRITICAL - Unable to resolve {}: {}", host, e),
+            ))
+        }
+    };
+    let elapsed = start.elapsed().as_secs_f64();
+    addresses.sort();
+    addresses.dedup();
+
+    let joined = addresses
+        .iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    if let Some(expected) = expected {
+        if let Some(missing) = expected.iter().find(|a| !addresses.contains(a)) {
+            return Ok((
+                CRITICAL,
+                format!(
+                    "DNS CRITICAL - Expected {} but {} returns {}",
+                    missing, host, joined
+                ),
+            ));
+        }
+    }
+
+    let status = status(elapsed, warning.as_ref(), critical.as_ref());
+    Ok((
+        status,
+        format!(
+            "DNS {} - {:.3} seconds response time. {} returns {}|{}",
+            status_name(status),
+            elapsed,
+            host,
+            joined,
+            perfdata(
+                "time",
+                format!("{:.6}", elapsed),
+                "s",
+                warning.as_ref(),
+                critical.as_ref(),
+                Some(0.0)
+            )
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod dns_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resolve_localhost() -> Result<(), Box<dyn std::error::Error>> {
+        let (status, output) = check(&options(&["-H", "localhost", "-a", "127.0.0.1"])).await?;
+
+        assert_eq!(status, 0, "{}", output);
+        assert!(output.starts_with("DNS OK - "));
+        assert!(output.contains("localhost returns "));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_address() -> Result<(), Box<dyn std::error::Error>> {
+        let (status, output) = check(&options(&["-H", "localhost", "-a", "192.0.2.1"])).await?;
+
+        assert_eq!(status, 2);
+        assert!(output.starts_with("DNS CRITICAL - Expected 192.0.2.1 but localhost returns "));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use super::{perfdata, status, status_name, BuiltinOutput, OptionError, Options, CRITICAL};
+use crate::threshold::Threshold;
+use std::fs;
+use std::time::SystemTime;
+
+/// The long names of the options of the check.
+pub const OPTIONS: &[&str] = &[
+    "file",
+    "warning",
+    "critical",
+    "warning-size",
+    "critical-size",
+];
+
+/// Check the age and size of a local file, like check_file_age.
+pub fn check(options: &Options) -> Result<BuiltinOutput, OptionError> {
+    let path = options.required("file")?;
+    let warning_age = options.parsed::<Threshold>("warning")?;
+    let critical_age = options.parsed::<Threshold>("critical")?;
+    let warning_size = options.parsed::<Threshold>("warning-size")?;
+    let critical_size = options.parsed::<Threshold>("critical-size")?;
+
+    let metadata = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(e) => return Ok((CRITICAL, format!("FILE CRITICAL - {}: {}", path, e))),
+    };
+
+    let age = metadata
+        .modified()
+        .ok()
+        .and_then(|m| SystemTime::now().duration_since(m).ok())
+        .unwrap_or_default()
+        .as_secs();
+    let size = metadata.len();
+
+    let status = status(age as f64, warning_age.as_ref(), critical_age.as_ref()).max(status(
+        size as f64,
+        warning_size.as_ref(),
+        critical_size.as_ref(),
+    ));
+
+    Ok((
+        status,
+        format!(
+            "FILE {} - {} is {} seconds old and {} bytes|{} {}",
+            status_name(status),
+            path,
+            age,
+            size,
+            perfdata(
+                "age",
+                age,
+                "s",
+                warning_age.as_ref(),
+                critical_age.as_ref(),
+                Some(0.0)
+            ),
+            perfdata(
+                "size",
+                size,
+                "B",
+                warning_size.as_ref(),
+                critical_size.as_ref(),
+                Some(0.0)
+            )
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod file_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn test_file_age_and_size() -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(b"hello")?;
+        let path = file.path().to_str().unwrap();
+
+        let (status, output) = check(&options(&["-f", path, "-w", "60", "-c", "120"]))?;
+        assert_eq!(status, 0);
+        assert_eq!(
+            output,
+            format!(
+                "FILE OK - {} is 0 seconds old and 5 bytes|age=0s;60;120;0 size=5B;;;0",
+                path
+            )
+        );
+
+        let (status, _) = check(&options(&["-f", path, "--critical-size", "4"]))?;
+        assert_eq!(status, 2);
+
+        let (status, output) = check(&options(&["-f", path, "-w", "@0:60", "-c", "10:20"]))?;
+        assert_eq!(status, 2);
+        assert!(output.ends_with("|age=0s;@0:60;10:20;0 size=5B;;;0"));
+
+        let (status, _) = check(&options(&["-f", path, "--warning-size", "@0:10"]))?;
+        assert_eq!(status, 1);
+
+        assert_eq!(
+            check(&options(&["-f", path, "-w", "10:5"])),
+            Err(OptionError::Invalid(
+                "warning".to_string(),
+                "10:5".to_string()
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_file() -> Result<(), Box<dyn std::error::Error>> {
+        let (status, output) = check(&options(&["-f", "/nonexistent/xtender-file"]))?;
+
+        assert_eq!(status, 2);
+        assert!(output.starts_with("FILE CRITICAL - /nonexistent/xtender-file: "));
+
+        Ok(())
+    }
+}
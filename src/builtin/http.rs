@@ -0,0 +1,417 @@
+use super::tls::handshake;
+use super::{
+    perfdata, status, status_name, BuiltinOutput, OptionError, Options, CRITICAL, OK, WARNING,
+};
+use crate::net::connect;
+use crate::threshold::Threshold;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Responses are only read up to this size.
+const MAX_RESPONSE_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Url {
+    https: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    /// The value of the Host header, with IPv6 addresses in brackets and the port unless it's
+    /// the default of the scheme.
+    fn host_header(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match (self.https, self.port) {
+            (true, 443) | (false, 80) => host,
+            (_, port) => format!("{}:{}", host, port),
+        }
+    }
+}
+
+struct Response {
+    status_line: String,
+    code: u16,
+    body: Vec<u8>,
+    size: usize,
+}
+
+/// The long names of the options of the check.
+pub const OPTIONS: &[&str] = &["url", "expect", "string", "verify", "warning", "critical"];
+
+/// Request a URL and check the status code, and optionally the body, like check_http.
+pub async fn check(options: &Options, timeout: Duration) -> Result<BuiltinOutput, OptionError> {
+    let url_string = options.required("url")?.to_string();
+    let url = parse_url(&url_string)?;
+    let expect = options
+        .get("expect")
+        .map(|e| {
+            e.split(',')
+                .map(|c| {
+                    c.trim()
+                        .parse::<u16>()
+                        .map_err(|_| OptionError::Invalid("expect".to_string(), e.to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?;
+    let string = options.get("string").map(|s| s.to_string());
+    let verify = options.parsed::<bool>("verify")?.unwrap_or(true);
+    let warning = options.parsed::<Threshold>("warning")?;
+    let critical = options.parsed::<Threshold>("critical")?;
+
+    let start = Instant::now();
+    let response = tokio::time::timeout(timeout, request(&url, verify))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let response = match response {
+        Ok(r) => r,
+        Err(e) => {
+            return Ok((
+                CRITICAL,
+                format!("HTTP CRITICAL - Unable to request {}: {}", url_string, e),
+            ))
+        }
+    };
+
+    let code_status = match &expect {
+        Some(codes) if codes.contains(&response.code) => OK,
+        Some(_) => CRITICAL,
+        None => match response.code {
+            200..=399 => OK,
+            400..=499 => WARNING,
+            _ => CRITICAL,
+        },
+    };
+
+    let perf = format!(
+        "{} {}",
+        perfdata(
+            "time",
+            format!("{:.6}", elapsed),
+            "s",
+            warning.as_ref(),
+            critical.as_ref(),
+            Some(0.0)
+        ),
+        perfdata("size", response.size, "B", None, None, Some(0.0))
+    );
+
+    if let Some(string) = &string {
+        if !String::from_utf8_lossy(&response.body).contains(string.as_str()) {
+            return Ok((
+                CRITICAL,
+                format!(
+                    "HTTP CRITICAL: {} - string '{}' not found on '{}'|{}",
+                    response.status_line, string, url_string, perf
+                ),
+            ));
+        }
+    }
+
+    let status = code_status.max(status(elapsed, warning.as_ref(), critical.as_ref()));
+    Ok((
+        status,
+        format!(
+            "HTTP {}: {} - {} bytes in {:.3} second response time|{}",
+            status_name(status),
+            response.status_line,
+            response.size,
+            elapsed,
+            perf
+        ),
+    ))
+}
+
+fn parse_url(s: &str) -> Result<Url, OptionError> {
+    let invalid = || OptionError::Invalid("url".to_string(), s.to_string());
+
+    let (https, rest) = match (s.strip_prefix("http://"), s.strip_prefix("https://")) {
+        (Some(rest), _) => (false, rest),
+        (_, Some(rest)) => (true, rest),
+        _ => return Err(invalid()),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let default_port = if https { 443 } else { 80 };
+    let (host, port) = match authority.strip_prefix('[') {
+        // An IPv6 address, with an optional port after the brackets.
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, "")) => (host, default_port),
+            Some((host, port)) => (
+                host,
+                port.strip_prefix(':')
+                    .and_then(|p| p.parse().ok())
+                    .ok_or_else(invalid)?,
+            ),
+            None => return Err(invalid()),
+        },
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, default_port),
+        },
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(Url {
+        https,
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+async fn request(url: &Url, verify: bool) -> io::Result<Response> {
+    let mut stream = connect(&url.host, url.port).await?;
+    // HTTP/1.0 keeps the response from being chunked, and the server closes the connection.
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: xtender\r\nAccept: */*\r\n\r\n",
+        url.path,
+        url.host_header()
+    );
+
+    let raw = match url.https {
+        true => {
+            let mut stream = handshake(stream, &url.host, verify).await?;
+            exchange(&mut stream, &request).await?
+        }
+        false => exchange(&mut stream, &request).await?,
+    };
+
+    parse_response(&raw)
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &str,
+) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes()).await?;
+    let mut raw = Vec::new();
+    read_response(stream, &mut raw).await?;
+    Ok(raw)
+}
+
+async fn read_response<R: AsyncRead + Unpin>(stream: &mut R, buf: &mut Vec<u8>) -> io::Result<()> {
+    match stream.take(MAX_RESPONSE_SIZE).read_to_end(buf).await {
+        Ok(_) => Ok(()),
+        // Some TLS servers close the connection without a close_notify.
+        Err(e) if !buf.is_empty() && e.kind() != io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_response(raw: &[u8]) -> io::Result<Response> {
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| i + 4)
+        .unwrap_or(raw.len());
+    let head = String::from_utf8_lossy(&raw[..header_end]);
+    let status_line = head.lines().next().unwrap_or_default().trim().to_string();
+
+    let code = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|c| c.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid status line: '{}'", status_line),
+            )
+        })?;
+
+    Ok(Response {
+        status_line,
+        code,
+        body: raw[header_end..].to_vec(),
+        size: raw.len(),
+    })
+}
+
+#[cfg(test)]
+mod http_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    /// Serve a single response on a local port, and return the port.
+    fn serve(response: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("https://example.com:8443/status?x=1"),
+            Ok(Url {
+                https: true,
+                host: "example.com".to_string(),
+                port: 8443,
+                path: "/status?x=1".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_url("http://[::1]"),
+            Ok(Url {
+                https: false,
+                host: "::1".to_string(),
+                port: 80,
+                path: "/".to_string(),
+            })
+        );
+        assert!(parse_url("ftp://example.com").is_err());
+        assert!(parse_url("http://example.com:http/").is_err());
+    }
+
+    #[test]
+    fn test_host_header() {
+        let header = |url: &str| parse_url(url).unwrap().host_header();
+        assert_eq!(header("https://example.com/"), "example.com");
+        assert_eq!(header("http://example.com:8080/"), "example.com:8080");
+        assert_eq!(header("https://example.com:80/"), "example.com:80");
+        assert_eq!(header("http://[::1]"), "[::1]");
+        assert_eq!(header("https://[::1]:8443/x"), "[::1]:8443");
+    }
+
+    #[tokio::test]
+    async fn test_http_status_and_body() -> Result<(), Box<dyn std::error::Error>> {
+        let port = serve("HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nall good");
+        let url = format!("http://127.0.0.1:{}/health", port);
+
+        let (status, output) = check(
+            &options(&["-u", &url, "-s", "good"]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(status, 0, "{}", output);
+        assert!(output.starts_with("HTTP OK: HTTP/1.0 200 OK - 53 bytes in "));
+        assert!(output.ends_with(";;;0 size=53B;;;0"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_unexpected_status() -> Result<(), Box<dyn std::error::Error>> {
+        let port = serve("HTTP/1.0 404 Not Found\r\n\r\n");
+        let url = format!("http://127.0.0.1:{}/", port);
+
+        let (status, output) = check(&options(&["-u", &url]), Duration::from_secs(5)).await?;
+        assert_eq!(status, 1, "{}", output);
+
+        let port = serve("HTTP/1.0 404 Not Found\r\n\r\n");
+        let url = format!("http://127.0.0.1:{}/", port);
+
+        let (status, _) = check(
+            &options(&["-u", &url, "-e", "200,301"]),
+            Duration::from_secs(5),
+        )
+        .await?;
+        assert_eq!(status, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_response_time_range() -> Result<(), Box<dyn std::error::Error>> {
+        let port = serve("HTTP/1.0 200 OK\r\n\r\n");
+        let url = format!("http://127.0.0.1:{}/", port);
+
+        // Faster than the start of the warning range.
+        let (status, output) = check(
+            &options(&["-u", &url, "-w", "10:20", "-c", "@30:"]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(status, 1, "{}", output);
+        assert!(output.contains(";10:20;@30:;0 size="), "{}", output);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_missing_string() -> Result<(), Box<dyn std::error::Error>> {
+        let port = serve("HTTP/1.0 200 OK\r\n\r\nsomething else");
+        let url = format!("http://127.0.0.1:{}/", port);
+
+        let (status, output) = check(
+            &options(&["-u", &url, "-s", "good"]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(status, 2);
+        assert!(output.starts_with("HTTP CRITICAL: HTTP/1.0 200 OK - string 'good' not found"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_times_out() -> Result<(), Box<dyn std::error::Error>> {
+        // A server that accepts the connection but never responds.
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("https://127.0.0.1:{}/", listener.local_addr()?.port());
+
+        let (status, output) = check(&options(&["-u", &url]), Duration::from_secs(1)).await?;
+
+        assert_eq!(status, 2);
+        assert!(output.ends_with(": timed out"), "{}", output);
+        drop(listener);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_https_without_verification() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let acceptor = super::super::tls::self_signed_acceptor(30);
+        std::thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                if let Ok(mut stream) = acceptor.accept(stream) {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf);
+                    let _ = stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nsecure");
+                    let _ = stream.shutdown();
+                }
+            }
+        });
+        let url = format!("https://localhost:{}/", port);
+
+        let (status, output) = check(
+            &options(&["-u", &url, "--verify", "false", "-s", "secure"]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(status, 0, "{}", output);
+
+        Ok(())
+    }
+}
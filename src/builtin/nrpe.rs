@@ -0,0 +1,290 @@
+use super::{BuiltinOutput, OptionError, Options, CRITICAL, UNKNOWN};
+use crate::net::connect;
+use crate::nrpe::{query, NrpeError, Packet, TlsOptions, DEFAULT_PORT};
+use std::io;
+use std::time::Duration;
+
+/// The short options of check_nrpe, where `-c` is the remote command rather than a threshold.
+pub(super) fn long_option(short: &str) -> Option<&'static str> {
+    match short {
+        "H" => Some("host"),
+        "p" => Some("port"),
+        "c" => Some("command"),
+        "a" => Some("args"),
+        "A" => Some("ca-cert"),
+        "C" => Some("client-cert"),
+        "K" => Some("client-key"),
+        "P" => Some("protocol"),
+        _ => None,
+    }
+}
+
+/// The long names of the options of the check.
+pub const OPTIONS: &[&str] = &[
+    "host",
+    "port",
+    "command",
+    "args",
+    "protocol",
+    "ssl",
+    "ciphers",
+    "ca-cert",
+    "client-cert",
+    "client-key",
+];
+
+/// Run a command on a remote NRPE daemon, like check_nrpe. Without `--protocol`, version 3 is
+/// tried first, and version 2 if the daemon closes the connection.
+pub async fn check(options: &Options, timeout: Duration) -> Result<BuiltinOutput, OptionError> {
+    let host = options.required("host")?.to_string();
+    let port = options.parsed::<u16>("port")?.unwrap_or(DEFAULT_PORT);
+    let command = options.required("command")?;
+    let query_string = match options.get("args") {
+        Some(args) if !args.is_empty() => format!("{}!{}", command, args),
+        _ => command.to_string(),
+    };
+    let versions = match options.parsed::<u16>("protocol")? {
+        Some(v @ (2 | 3)) => vec![v],
+        Some(v) => return Err(OptionError::Invalid("protocol".to_string(), v.to_string())),
+        None => vec![3, 2],
+    };
+    let tls = match options.parsed::<bool>("ssl")?.unwrap_or(true) {
+        true => Some(TlsOptions {
+            ciphers: options.get("ciphers").map(|s| s.to_string()),
+            ca_cert: options.get("ca-cert").map(|s| s.into()),
+            client_cert: options.get("client-cert").map(|s| s.into()),
+            client_key: options.get("client-key").map(|s| s.into()),
+        }),
+        false => None,
+    };
+
+    let queried = tokio::time::timeout(timeout, async {
+        let mut result = Err(NrpeError::UnsupportedVersion(0));
+        for (i, version) in versions.iter().enumerate() {
+            result = match connect(&host, port).await {
+                Ok(stream) => query(stream, &host, tls.as_ref(), *version, &query_string).await,
+                Err(e) => Err(NrpeError::Io(e)),
+            };
+            match &result {
+                Err(e) if i + 1 < versions.len() && closed_by_daemon(e) => continue,
+                _ => break,
+            }
+        }
+        result
+    })
+    .await;
+    let response =
+        queried.unwrap_or_else(|_| Err(NrpeError::Io(io::Error::from(io::ErrorKind::TimedOut))));
+
+    Ok(match response {
+        Ok(packet) => response_output(&packet),
+        Err(e @ (NrpeError::Io(_) | NrpeError::Tls(_))) => (
+            CRITICAL,
+            format!("CHECK_NRPE: Error - {} port {}: {}", host, port, e),
+        ),
+        Err(e) => (UNKNOWN, format!("CHECK_NRPE: {}", e)),
+    })
+}
+
+/// A daemon that doesn't speak the protocol version closes the connection without a response.
+fn closed_by_daemon(e: &NrpeError) -> bool {
+    match e {
+        NrpeError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::BrokenPipe
+        ),
+        NrpeError::Tls(_) | NrpeError::UnsupportedVersion(_) => true,
+        _ => false,
+    }
+}
+
+fn response_output(packet: &Packet) -> BuiltinOutput {
+    let status = match packet.result_code {
+        code @ 0..=3 => code as i32,
+        _ => UNKNOWN,
+    };
+    let output = match packet.buffer.is_empty() {
+        true => "CHECK_NRPE: No output returned from daemon.".to_string(),
+        false => packet.buffer_string(),
+    };
+    (status, output)
+}
+
+#[cfg(test)]
+mod nrpe_test {
+    use super::*;
+    use crate::net::tls_accept;
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse_with(
+            &args.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+            long_option,
+        )
+        .unwrap()
+    }
+
+    /// Answer a single query, echoing it back, on a local port. A daemon that only speaks
+    /// version 2 closes the connection on any other version, like nrpe 2.x.
+    async fn serve(v2_only: bool, connections: usize) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for _ in 0..connections {
+                if let Ok((stream, _)) = listener.accept().await {
+                    answer(stream, v2_only).await;
+                }
+            }
+        });
+        port
+    }
+
+    async fn answer<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, v2_only: bool) {
+        if let Ok(query) = Packet::read(&mut stream).await {
+            if v2_only && query.version != 2 {
+                return;
+            }
+            let output = format!("OK - v{} {}|time=1s", query.version, query.buffer_string());
+            let response = Packet::response(query.version, 1, &output);
+            let _ = stream.write_all(&response.encode().unwrap()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nrpe_query() -> Result<(), Box<dyn std::error::Error>> {
+        let port = serve(false, 1).await.to_string();
+
+        let (status, output) = check(
+            &options(&[
+                "-H",
+                "127.0.0.1",
+                "-p",
+                &port,
+                "--ssl",
+                "false",
+                "-c",
+                "check_load",
+                "-a",
+                "5",
+                "10",
+            ]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(status, 1);
+        assert_eq!(output, "OK - v3 check_load!5!10|time=1s");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nrpe_falls_back_to_v2() -> Result<(), Box<dyn std::error::Error>> {
+        let port = serve(true, 2).await.to_string();
+
+        let (_, output) = check(
+            &options(&[
+                "-H",
+                "127.0.0.1",
+                "-p",
+                &port,
+                "--ssl",
+                "false",
+                "-c",
+                "check_users",
+            ]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(output, "OK - v2 check_users|time=1s");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nrpe_over_tls() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        let acceptor = super::super::tls::self_signed_acceptor(30);
+        tokio::spawn(async move {
+            if let Ok((stream, _)) = listener.accept().await {
+                if let Ok(stream) = tls_accept(&acceptor, stream).await {
+                    answer(stream, false).await;
+                }
+            }
+        });
+
+        let (status, output) = check(
+            &options(&[
+                "-H",
+                "localhost",
+                "-p",
+                &port,
+                "-P",
+                "3",
+                "-c",
+                "check_disk",
+            ]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(status, 1, "{}", output);
+        assert_eq!(output, "OK - v3 check_disk|time=1s");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nrpe_times_out() -> Result<(), Box<dyn std::error::Error>> {
+        use tokio::io::AsyncReadExt;
+
+        // A daemon that reads the query but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        let options = options(&["-H", "127.0.0.1", "-p", &port, "--ssl", "false", "-c", "x"]);
+        let (checked, accepted) =
+            tokio::join!(check(&options, Duration::from_secs(1)), listener.accept());
+
+        let (status, output) = checked?;
+        assert_eq!(status, 2);
+        assert!(output.ends_with("timed out"), "{}", output);
+        // The connection is closed once the check has timed out.
+        let (mut stream, _) = accepted?;
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf));
+        assert!(matches!(read.await, Ok(Ok(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nrpe_connection_refused() -> Result<(), Box<dyn std::error::Error>> {
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        // Make sure nothing listens on the port.
+        assert!(connect("127.0.0.1", port).await.is_err());
+
+        let (status, output) = check(
+            &options(&[
+                "-H",
+                "127.0.0.1",
+                "-p",
+                &port.to_string(),
+                "-c",
+                "check_load",
+            ]),
+            Duration::from_secs(1),
+        )
+        .await?;
+
+        assert_eq!(status, 2);
+        assert!(output.starts_with("CHECK_NRPE: Error - 127.0.0.1 port "));
+
+        Ok(())
+    }
+}
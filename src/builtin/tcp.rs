@@ -0,0 +1,89 @@
+use super::{perfdata, status, status_name, BuiltinOutput, OptionError, Options, CRITICAL};
+use crate::threshold::Threshold;
+use std::time::Instant;
+use tokio::net::TcpStream;
+
+/// The long names of the options of the check.
+pub const OPTIONS: &[&str] = &["host", "port", "warning", "critical"];
+
+/// Connect to a TCP port, like check_tcp.
+pub async fn check(options: &Options) -> Result<BuiltinOutput, OptionError> {
+    let host = options.required("host")?;
+    let port = options
+        .parsed::<u16>("port")?
+        .ok_or_else(|| OptionError::Missing("port".to_string()))?;
+    let warning = options.parsed::<Threshold>("warning")?;
+    let critical = options.parsed::<Threshold>("critical")?;
+
+    let start = Instant::now();
+    if let Err(e) = TcpStream::connect((host, port)).await {
+        return Ok((
+            CRITICAL,
+            format!(
+                "TCP CRITICAL - Connection to {} port {} failed: {}",
+                host, port, e
+            ),
+        ));
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let status = status(elapsed, warning.as_ref(), critical.as_ref());
+    Ok((
+        status,
+        format!(
+            "TCP {} - {:.3} second response time on {} port {}|{}",
+            status_name(status),
+            elapsed,
+            host,
+            port,
+            perfdata(
+                "time",
+                format!("{:.6}", elapsed),
+                "s",
+                warning.as_ref(),
+                critical.as_ref(),
+                Some(0.0)
+            )
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tcp_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connect() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port().to_string();
+
+        let (status, output) = check(&options(&["-H", "127.0.0.1", "-p", &port])).await?;
+
+        assert_eq!(status, 0);
+        assert!(output.starts_with("TCP OK - "));
+        assert!(output.contains(&format!(" on 127.0.0.1 port {}|time=", port)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connection_refused() -> Result<(), Box<dyn std::error::Error>> {
+        // Bind and drop a listener to find a port that's very likely closed.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port()
+            .to_string();
+
+        let (status, output) = check(&options(&["-H", "127.0.0.1", "-p", &port])).await?;
+
+        assert_eq!(status, 2);
+        assert!(output.starts_with("TCP CRITICAL - Connection to 127.0.0.1 port "));
+
+        Ok(())
+    }
+}
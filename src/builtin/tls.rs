@@ -0,0 +1,286 @@
+use super::{perfdata, status, status_name, BuiltinOutput, OptionError, Options, CRITICAL};
+use crate::net::{connect, tls_connect};
+use crate::threshold::Threshold;
+use openssl::asn1::Asn1Time;
+use openssl::nid::Nid;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+const DEFAULT_PORT: u16 = 443;
+const DEFAULT_WARNING_DAYS: &str = "30";
+const DEFAULT_CRITICAL_DAYS: &str = "14";
+
+/// The long names of the options of the check.
+pub const OPTIONS: &[&str] = &["host", "port", "warning", "critical"];
+
+/// Check how many days are left until the certificate of a TLS server expires, like
+/// check_ssl_cert. The certificate isn't verified, so that expired certificates can be reported.
+pub async fn check(options: &Options, timeout: Duration) -> Result<BuiltinOutput, OptionError> {
+    let host = options.required("host")?.to_string();
+    let port = options.parsed::<u16>("port")?.unwrap_or(DEFAULT_PORT);
+    let warning = days_threshold(options, "warning", DEFAULT_WARNING_DAYS)?;
+    let critical = days_threshold(options, "critical", DEFAULT_CRITICAL_DAYS)?;
+
+    let certificate = tokio::time::timeout(timeout, async {
+        let stream = connect(&host, port).await?;
+        let stream = handshake(stream, &host, false).await?;
+        stream
+            .ssl()
+            .peer_certificate()
+            .ok_or_else(|| io::Error::other("The server sent no certificate"))
+    })
+    .await
+    .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+
+    let certificate = match certificate {
+        Ok(c) => c,
+        Err(e) => {
+            return Ok((
+                CRITICAL,
+                format!(
+                    "TLS CRITICAL - Connection to {} port {} failed: {}",
+                    host, port, e
+                ),
+            ))
+        }
+    };
+
+    let days = match days_until_expiry(&certificate) {
+        Ok(d) => d,
+        Err(e) => return Ok((CRITICAL, format!("TLS CRITICAL - {}", e))),
+    };
+    let subject = common_name(&certificate).unwrap_or_else(|| host.clone());
+    let not_after = certificate.not_after().to_string();
+    let perf = perfdata("days", days, "", Some(&warning), Some(&critical), None);
+
+    if days < 0 {
+        return Ok((
+            CRITICAL,
+            format!(
+                "TLS CRITICAL - Certificate '{}' expired {} days ago ({})|{}",
+                subject, -days, not_after, perf
+            ),
+        ));
+    }
+
+    let status = status(days as f64, Some(&warning), Some(&critical));
+    Ok((
+        status,
+        format!(
+            "TLS {} - Certificate '{}' expires in {} days ({})|{}",
+            status_name(status),
+            subject,
+            days,
+            not_after,
+            perf
+        ),
+    ))
+}
+
+/// A threshold on the days until the certificate expires. A plain number of days alerts below
+/// it, like check_ssl_cert, while ranges such as `@0:7` are used as given.
+fn days_threshold(options: &Options, name: &str, default: &str) -> Result<Threshold, OptionError> {
+    let days = options.get(name).unwrap_or(default).trim();
+    let range = match days.parse::<f64>() {
+        Ok(_) => format!("{}:", days),
+        Err(_) => days.to_string(),
+    };
+    range
+        .parse()
+        .map_err(|_| OptionError::Invalid(name.to_string(), days.to_string()))
+}
+
+/// Perform the TLS handshake, with SNI. The certificate chain and host name are only verified
+/// if `verify` is set.
+pub(super) async fn handshake(
+    stream: TcpStream,
+    host: &str,
+    verify: bool,
+) -> io::Result<SslStream<TcpStream>> {
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(io::Error::other)?;
+    if !verify {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+    let mut config = builder.build().configure().map_err(io::Error::other)?;
+    config.set_verify_hostname(verify);
+    tls_connect(config, host, stream).await
+}
+
+fn days_until_expiry(certificate: &X509) -> Result<i32, openssl::error::ErrorStack> {
+    let now = Asn1Time::days_from_now(0)?;
+    Ok(now.diff(certificate.not_after())?.days)
+}
+
+fn common_name(certificate: &X509) -> Option<String> {
+    certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|e| e.data().to_string().ok())
+}
+
+/// A self-signed certificate for `localhost` and its key, valid for the given number of days,
+/// for testing against local listeners.
+#[cfg(test)]
+pub(crate) fn self_signed_certificate(
+    days: u32,
+) -> (openssl::pkey::PKey<openssl::pkey::Private>, X509) {
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::X509NameBuilder;
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+        .unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+        .unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    (key, builder.build())
+}
+
+/// A TLS acceptor with a self-signed certificate for `localhost`, valid for the given number of
+/// days, for testing against local listeners.
+#[cfg(test)]
+pub(crate) fn self_signed_acceptor(days: u32) -> openssl::ssl::SslAcceptor {
+    use openssl::ssl::SslAcceptor;
+
+    let (key, certificate) = self_signed_certificate(days);
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&certificate).unwrap();
+    acceptor.build()
+}
+
+#[cfg(test)]
+mod tls_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::net::TcpListener;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_certificate_expiry() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port().to_string();
+        let acceptor = self_signed_acceptor(10);
+        std::thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                let _ = acceptor.accept(stream);
+            }
+        });
+
+        let (status, output) = check(
+            &options(&["-H", "localhost", "-p", &port]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(status, 2, "{}", output);
+        assert!(output.starts_with("TLS CRITICAL - Certificate 'localhost' expires in "));
+        assert!(output.ends_with("|days=9;30:;14:;") || output.ends_with("|days=10;30:;14:;"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_threshold_ranges() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port().to_string();
+        let acceptor = self_signed_acceptor(10);
+        std::thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                let _ = acceptor.accept(stream);
+            }
+        });
+
+        let (status, output) = check(
+            &options(&["-H", "localhost", "-p", &port, "-w", "@0:7", "-c", "3"]),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+        assert_eq!(status, 0, "{}", output);
+        assert!(output.ends_with(";@0:7;3:;"), "{}", output);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_thresholds_alert_below() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::result::{
+            CheckResultBuilder, ProcessOptions, ProcessedCheckResults, ProcessedCheckResultsExt,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port().to_string();
+        let acceptor = self_signed_acceptor(200);
+        std::thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                let _ = acceptor.accept(stream);
+            }
+        });
+
+        let (status, output) = check(
+            &options(&["-H", "localhost", "-p", &port]),
+            Duration::from_secs(5),
+        )
+        .await?;
+        assert_eq!(status, 0, "{}", output);
+
+        let result = CheckResultBuilder::new()
+            .name("cert")
+            .status(status)
+            .parse_output(&output)
+            .build();
+        let options = ProcessOptions {
+            threshold_state: true,
+            ..ProcessOptions::default()
+        };
+        let csv =
+            ProcessedCheckResults::from_check_result_with(&result, &options).as_csv_string()?;
+
+        assert!(csv.contains(",days,"), "{}", csv);
+        assert!(csv.contains(",30:,14:,,,OK,,"), "{}", csv);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_failure() -> Result<(), Box<dyn std::error::Error>> {
+        let port = TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port()
+            .to_string();
+
+        let (status, output) = check(
+            &options(&["-H", "127.0.0.1", "-p", &port]),
+            Duration::from_secs(1),
+        )
+        .await?;
+
+        assert_eq!(status, 2);
+        assert!(output.starts_with("TLS CRITICAL - Connection to 127.0.0.1 port "));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use crate::check::{
+    run_all_checks_in_parallel, run_all_checks_sequentially, run_all_checks_with_max_parallel,
+    Check, Checks,
+};
+use crate::result::{CheckResult, CheckResults};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Results of checks with an interval, kept on disk so that they can be served between runs.
+/// Each check has its own file, named after a hash of the check name and command.
+#[derive(Clone, Debug)]
+pub struct ResultCache {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheEntry {
+    /// When the check finished, in seconds since the Unix epoch.
+    timestamp: u64,
+    result: CheckResult,
+}
+
+impl ResultCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The cached result of the check, if there is one and the check isn't due to run yet.
+    pub fn fresh_result(&self, check: &Check) -> Option<CheckResult> {
+        let interval = Duration::from_secs(check.interval().filter(|i| *i > 0)?);
+        let path = self.path(check);
+
+        let entry: CacheEntry = match fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    error!(
+                        "Ignoring unreadable cached result {}: {}",
+                        path.display(),
+                        e
+                    );
+                    return None;
+                }
+            },
+            Err(e) => {
+                debug!("No cached result for check {}: {}", check.name(), e);
+                return None;
+            }
+        };
+
+        let age = now().saturating_sub(Duration::from_secs(entry.timestamp));
+        if age >= interval {
+            debug!("Cached result for check {} is due", check.name());
+            return None;
+        }
+
+        debug!("Serving cached result for check {}", check.name());
+        Some(entry.result.with_cache_age(age, interval))
+    }
+
+    /// Store the result of a check. Decrypted values are never written to disk.
+    pub fn store(&self, check: &Check, result: &CheckResult) -> io::Result<()> {
+        let entry = CacheEntry {
+            timestamp: now().as_secs(),
+            result: result.without_secrets(),
+        };
+        let json = serde_json::to_vec(&entry)?;
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(check);
+        let tmp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+
+        // Write to a temporary file first, so that a concurrent run never reads a partial entry.
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    fn path(&self, check: &Check) -> PathBuf {
+        let key = format!("{}\0{}", check.name(), check.display_command());
+        let hash = hex::encode(openssl::sha::sha256(key.as_bytes()));
+        self.dir.join(format!("{}.json", hash))
+    }
+}
+
+/// Run the checks, serving the cached result of every check with an interval that isn't due yet.
+/// At most `max_parallel` checks run at the same time, with no limit if `None`. The results are
+/// returned in the same order as the checks.
+pub async fn run_all_checks_with_cache(
+    checks: Checks,
+    cache: &ResultCache,
+    max_parallel: Option<usize>,
+) -> Result<CheckResults, Box<dyn std::error::Error>> {
+    let mut results: Vec<Option<CheckResult>> = Vec::with_capacity(checks.len());
+    let mut due = Checks::new();
+
+    for check in checks {
+        match cache.fresh_result(&check) {
+            Some(result) => results.push(Some(result)),
+            None => {
+                results.push(None);
+                due.push(check);
+            }
+        }
+    }
+
+    let due_results = match max_parallel {
+        Some(1) => run_all_checks_sequentially(due.clone()).await?,
+        Some(n) => run_all_checks_with_max_parallel(due.clone(), n).await?,
+        None => run_all_checks_in_parallel(due.clone()).await?,
+    };
+
+    let mut due_results = due.iter().zip(due_results.0);
+    for slot in results.iter_mut().filter(|r| r.is_none()) {
+        if let Some((check, result)) = due_results.next() {
+            if check.interval().is_some_and(|i| i > 0) {
+                if let Err(e) = cache.store(check, &result) {
+                    error!(
+                        "Unable to cache the result of check {} in {}: {}",
+                        check.name(),
+                        cache.dir.display(),
+                        e
+                    );
+                }
+            }
+            *slot = Some(result);
+        }
+    }
+
+    Ok(CheckResults(results.into_iter().flatten().collect()))
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::*;
+    use crate::check::CheckBuilder;
+    use pretty_assertions::assert_eq;
+
+    fn check_with_interval(interval: u64) -> Check {
+        CheckBuilder::new()
+            .name("cached")
+            .command("echo OK")
+            .interval(interval)
+            .build_raw()
+    }
+
+    #[test]
+    fn test_fresh_result_is_served_from_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let cache = ResultCache::new(dir.path());
+        let check = check_with_interval(3600);
+
+        assert!(cache.fresh_result(&check).is_none());
+
+        cache.store(&check, &check.run())?;
+        let cached = cache.fresh_result(&check).expect("A cached result");
+
+        assert_eq!(cached.short_output(), "OK");
+        assert!(cached.cache_age().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_results_without_interval_are_not_served() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let cache = ResultCache::new(dir.path());
+        let check = check_with_interval(0);
+
+        cache.store(&check, &check.run())?;
+
+        assert!(cache.fresh_result(&check).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_file_is_private() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let cache = ResultCache::new(dir.path().join("state"));
+        let check = check_with_interval(60);
+
+        cache.store(&check, &check.run())?;
+        let mode = fs::metadata(cache.path(&check))?.permissions().mode();
+
+        assert_eq!(mode & 0o777, 0o600);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_all_checks_with_cache_keeps_order() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let dir = tempfile::tempdir()?;
+        let cache = ResultCache::new(dir.path());
+        let checks = vec![
+            CheckBuilder::new()
+                .name("first")
+                .command("echo first")
+                .build_raw(),
+            CheckBuilder::new()
+                .name("second")
+                .command("date +%s%N")
+                .interval(3600)
+                .build_raw(),
+            CheckBuilder::new()
+                .name("third")
+                .command("echo third")
+                .build_raw(),
+        ];
+
+        let first_run = run_all_checks_with_cache(checks.clone(), &cache, None).await?;
+        let second_run = run_all_checks_with_cache(checks, &cache, Some(1)).await?;
+
+        let names = |r: &CheckResults| r.0.iter().map(|r| r.name()).collect::<Vec<_>>();
+        assert_eq!(names(&second_run), vec!["first", "second", "third"]);
+        assert_eq!(
+            first_run.0[1].short_output(),
+            second_run.0[1].short_output()
+        );
+        assert!(first_run.0[1].cache_age().is_none());
+        assert!(second_run.0[1].cache_age().is_some());
+        assert!(second_run.0[0].cache_age().is_none());
+
+        Ok(())
+    }
+}
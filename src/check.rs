@@ -1,15 +1,25 @@
+use crate::builtin::is_builtin;
+use crate::executor::{default_executor_name, executor, Invocation, PluginOutput};
+use crate::extract::ExtractRules;
+use crate::limit::ResourceLimits;
+use crate::macros::{self, RuntimeMacros};
 use crate::range::{Range, Ranges, RangesExt};
 use crate::result::{CheckResult, CheckResultBuilder, CheckResults};
-use crate::variable::{VariableError, VariableString, Variables};
+use crate::ssh::{SshTarget, SSH_EXECUTOR};
+use crate::status_map::StatusMap;
+use crate::threshold::Thresholds;
+use crate::variable::{
+    shell_quote, Variable, VariableError, VariableKind, VariableString, Variables,
+};
+use futures::StreamExt;
 use log::{debug, error};
 use serde::{Deserialize, Serialize};
 use shellwords;
 use std::fmt;
-use std::io::Read;
-use std::process::{Command, Stdio};
 use std::str::FromStr;
 use std::time::{Duration, Instant};
-use wait_timeout::ChildExt;
+use tokio::runtime::RuntimeFlavor;
+use tokio::time::timeout;
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Check {
@@ -17,7 +27,33 @@ pub struct Check {
     command: String,
     #[serde(skip)]
     secret_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    args: Option<Vec<String>>,
+    #[serde(skip)]
+    secret_args: Option<Vec<String>>,
     timeout: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shell: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limits: Option<ResourceLimits>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    executor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssh: Option<SshTarget>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thresholds: Option<Thresholds>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    worst_status: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status_map: Option<StatusMap>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extract: Option<ExtractRules>,
+    #[serde(skip)]
+    range_values: Vec<(String, i32)>,
     #[serde(skip)]
     variables_found: Option<Variables>,
     #[serde(skip)]
@@ -29,18 +65,39 @@ pub struct CheckBuilder {
     name: Option<String>,
     command: Option<String>,
     secret_command: Option<String>,
+    args: Option<Vec<String>>,
+    secret_args: Option<Vec<String>>,
     timeout: Option<u64>,
+    shell: Option<String>,
+    limits: Option<ResourceLimits>,
+    interval: Option<u64>,
+    executor: Option<String>,
+    template: Option<String>,
+    ssh: Option<SshTarget>,
+    thresholds: Option<Thresholds>,
+    worst_status: bool,
+    status_map: Option<StatusMap>,
+    extract: Option<ExtractRules>,
     variables_found: Option<Variables>,
     variables_not_found: Option<Variables>,
 }
 
 pub type Checks = Vec<Check>;
 
+/// The interpreter used when a check asks for shell execution without naming one.
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+
 pub trait ChecksExt {
     fn total_time_from_timeouts(&self) -> Duration;
+    /// The longest the checks can take to run when at most `max_parallel` run at the same time,
+    /// with no limit if `None`.
+    fn worst_case_time(&self, max_parallel: Option<usize>) -> Duration;
 }
 
-enum TimeoutMessage {
+/// How long an executor has past the timeout of a check to stop and report on its own.
+const EXECUTOR_TIMEOUT_GRACE: Duration = Duration::from_millis(500);
+
+pub(crate) enum TimeoutMessage {
     Single,
     Multi(u64),
 }
@@ -56,13 +113,35 @@ impl fmt::Display for TimeoutMessage {
     }
 }
 
+impl From<Duration> for TimeoutMessage {
+    fn from(timeout: Duration) -> Self {
+        match timeout.as_secs() {
+            1 => TimeoutMessage::Single,
+            secs => TimeoutMessage::Multi(secs),
+        }
+    }
+}
+
 impl Default for Check {
     fn default() -> Self {
         Self {
             name: String::new(),
             command: String::new(),
             secret_command: None,
+            args: None,
+            secret_args: None,
             timeout: 5,
+            shell: None,
+            limits: None,
+            interval: None,
+            executor: None,
+            template: None,
+            ssh: None,
+            thresholds: None,
+            worst_status: false,
+            status_map: None,
+            extract: None,
+            range_values: Vec::new(),
             variables_found: None,
             variables_not_found: None,
         }
@@ -83,12 +162,115 @@ impl Check {
             name: name.to_string(),
             command: command.to_string(),
             secret_command,
+            args: None,
+            secret_args: None,
             timeout,
+            shell: None,
+            limits: None,
+            interval: None,
+            executor: None,
+            template: None,
+            ssh: None,
+            thresholds: None,
+            worst_status: false,
+            status_map: None,
+            extract: None,
+            range_values: Vec::new(),
             variables_found: None,
             variables_not_found: None,
         }
     }
 
+    pub fn timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    pub fn variables_found(&self) -> Option<&Variables> {
+        self.variables_found.as_ref()
+    }
+
+    pub fn variables_not_found(&self) -> Option<&Variables> {
+        self.variables_not_found.as_ref()
+    }
+
+    pub fn shell(&self) -> Option<&str> {
+        self.shell.as_deref()
+    }
+
+    pub fn args(&self) -> Option<&[String]> {
+        self.args.as_deref()
+    }
+
+    pub fn limits(&self) -> Option<&ResourceLimits> {
+        self.limits.as_ref()
+    }
+
+    /// Minimum number of seconds between two runs of the check.
+    pub fn interval(&self) -> Option<u64> {
+        self.interval
+    }
+
+    /// The name of the executor that runs the check, if it doesn't use the default one.
+    pub fn executor(&self) -> Option<&str> {
+        self.executor.as_deref()
+    }
+
+    /// The name of the template the check was loaded from.
+    pub fn template(&self) -> Option<&str> {
+        self.template.as_deref()
+    }
+
+    /// The remote host the check runs on through SSH, if any.
+    pub fn ssh(&self) -> Option<&SshTarget> {
+        self.ssh.as_ref()
+    }
+
+    /// Thresholds for metrics of the performance data by label, replacing those of the plugin.
+    pub fn thresholds(&self) -> Option<&Thresholds> {
+        self.thresholds.as_ref()
+    }
+
+    /// Whether the status of the check is the worst of the exit code of the plugin and the
+    /// states of the metrics with thresholds in the check.
+    pub fn worst_status(&self) -> bool {
+        self.worst_status
+    }
+
+    /// Rules that change the status of the check, applied in order until one matches.
+    pub fn status_map(&self) -> Option<&StatusMap> {
+        self.status_map.as_ref()
+    }
+
+    /// Rules that turn text in the output of the check into metrics.
+    pub fn extract(&self) -> Option<&ExtractRules> {
+        self.extract.as_ref()
+    }
+
+    /// The values of the ranges this check was expanded from, by range name.
+    pub fn range_values(&self) -> &[(String, i32)] {
+        &self.range_values
+    }
+
+    /// The values of `$TIMEOUT$`, `$CHECK_NAME$` and the other macros resolved when the check
+    /// runs.
+    pub fn runtime_macros(&self) -> RuntimeMacros {
+        RuntimeMacros::new(
+            &self.name,
+            self.template.as_deref(),
+            self.timeout,
+            &self.range_values,
+        )
+    }
+
+    /// The command as it should be displayed, with any argument list joined onto the executable.
+    /// Encrypted variables remain obfuscated.
+    pub fn display_command(&self) -> String {
+        match &self.args {
+            Some(args) => join_argv(&self.command, args),
+            None => self.command.clone(),
+        }
+    }
+
     pub fn secret_command_or_command(&self) -> &str {
         match &self.secret_command {
             Some(secret_command) => {
@@ -107,6 +289,17 @@ impl Check {
         name_ranges.dedup();
 
         let mut command_ranges = Ranges::from_str(&self.command);
+        for arg in self.args.iter().flatten() {
+            command_ranges.extend(Ranges::from_str(arg));
+        }
+        // A command may use the value of a range of the name through its runtime macro instead.
+        let uses_macro = |range: &Range| {
+            let range_macro = macros::marked(&format!("RANGE_{}", range.name));
+            std::iter::once(&self.command)
+                .chain(self.args.iter().flatten())
+                .any(|s| s.contains(&range_macro))
+        };
+        command_ranges.extend(name_ranges.iter().filter(|r| uses_macro(r)).cloned());
         command_ranges.sort();
         command_ranges.dedup();
 
@@ -135,30 +328,126 @@ impl Check {
         panic!("Only 1 or 2 ranges are supported");
     }
 
+    // A copy of this check with a single step of a range substituted everywhere it occurs.
+    fn with_range_value(&self, range: &Range, value: i32) -> Self {
+        let placeholder = range.placeholder();
+        let replace = |s: &String| s.replace(&placeholder, &value.to_string());
+
+        Self {
+            name: replace(&self.name),
+            command: replace(&self.command),
+            secret_command: self.secret_command.as_ref().map(replace),
+            args: self.args.as_ref().map(|a| a.iter().map(replace).collect()),
+            secret_args: self
+                .secret_args
+                .as_ref()
+                .map(|a| a.iter().map(replace).collect()),
+            range_values: self
+                .range_values
+                .iter()
+                .cloned()
+                .chain(std::iter::once((range.name.clone(), value)))
+                .collect(),
+            variables_found: None,
+            variables_not_found: None,
+            ..self.clone()
+        }
+    }
+
+    /// Run the check to completion, blocking the current thread. Inside a multi-threaded Tokio
+    /// runtime the check runs on that runtime, otherwise on a temporary one, on a separate
+    /// thread if the caller is inside a single-threaded runtime. Prefer `run_async` when already
+    /// running inside a Tokio runtime.
     pub fn run(&self) -> CheckResult {
-        let safe_data = CheckResultBuilder::new()
+        let run_on_new_runtime = || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build a runtime for running the check")
+                .block_on(self.run_async())
+        };
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| handle.block_on(self.run_async()))
+            }
+            // A runtime can't be started on a thread that's already running one.
+            Ok(_) => std::thread::scope(|scope| {
+                scope
+                    .spawn(run_on_new_runtime)
+                    .join()
+                    .expect("The check thread panicked")
+            }),
+            Err(_) => run_on_new_runtime(),
+        }
+    }
+
+    /// Run the check without occupying a thread while waiting for the plugin. If the returned
+    /// future is dropped before it completes, the plugin process is killed. Must be called from
+    /// within a Tokio runtime with IO and time enabled.
+    pub async fn run_async(&self) -> CheckResult {
+        let secret_args = self.secret_args.as_ref().or(self.args.as_ref());
+
+        // Runtime macros are substituted into each word after splitting, so their values are
+        // never split, and quoted when the command is run through a shell. Only those marked in
+        // the template when the check was built are resolved.
+        let macros = self.runtime_macros();
+        let shell_mode = secret_args.is_none() && self.shell.is_some();
+        let resolve = |s: &str| match shell_mode {
+            true => macros.apply_shell_quoted(s),
+            false => macros.apply(s),
+        };
+
+        let mut safe_data = CheckResultBuilder::new()
             .name(&self.name)
-            .command(&self.command)
+            .command(&resolve(&self.display_command()))
+            .worst_status(self.worst_status)
             .variables_found(&self.variables_found)
             .variables_not_found(&self.variables_not_found);
 
+        if let Some(thresholds) = &self.thresholds {
+            safe_data = safe_data.thresholds(thresholds);
+        }
+        if let Some(status_map) = &self.status_map {
+            safe_data = safe_data.status_map(status_map);
+        }
+        if let Some(rules) = &self.extract {
+            safe_data = safe_data.extract(rules);
+        }
+
         debug!("Processing check: {:#?}", safe_data);
 
-        let maybe_secret_data = safe_data
-            .clone()
-            .secret_command(self.secret_command_or_command());
+        let mut maybe_secret_data =
+            safe_data
+                .clone()
+                .secret_command(&resolve(&match secret_args {
+                    Some(args) => join_argv(self.secret_command_or_command(), args),
+                    None => self.secret_command_or_command().to_string(),
+                }));
 
-        let cmd_vec = match shellwords::split(self.secret_command_or_command()) {
-            Ok(v) => v,
-            Err(_) => {
-                error!("Failed to split the command. Bailing.");
-                return maybe_secret_data
-                    .status(3)
-                    .short_output("UNKNOWN: Command split error")
-                    .build();
-            }
+        let cmd_vec: Vec<String> = match (secret_args, &self.shell) {
+            (Some(args), _) => std::iter::once(self.secret_command_or_command().to_string())
+                .chain(args.iter().cloned())
+                .collect(),
+            (None, Some(shell)) => vec![
+                shell.to_string(),
+                "-c".to_string(),
+                self.secret_command_or_command().to_string(),
+            ],
+            (None, None) => match shellwords::split(self.secret_command_or_command()) {
+                Ok(v) => v,
+                Err(_) => {
+                    error!("Failed to split the command. Bailing.");
+                    return maybe_secret_data
+                        .status(3)
+                        .short_output("UNKNOWN: Command split error")
+                        .build();
+                }
+            },
         };
 
+        let cmd_vec = cmd_vec.iter().map(|word| resolve(word)).collect::<Vec<_>>();
+
         if cmd_vec.is_empty() {
             error!("After splitting the command by words, the command is empty. Bailing.");
             return maybe_secret_data
@@ -167,70 +456,67 @@ impl Check {
                 .build();
         }
 
-        let cmd = &cmd_vec[0];
-        let args = &cmd_vec[1..];
+        let executor_name = match (&self.executor, &self.ssh) {
+            (Some(name), _) => name.as_str(),
+            (None, Some(_)) => SSH_EXECUTOR,
+            (None, None) => default_executor_name(&cmd_vec[0]),
+        };
+        let Some(executor) = executor(executor_name) else {
+            error!("No executor registered as \"{}\". Bailing.", executor_name);
+            return maybe_secret_data
+                .status(3)
+                .short_output(&format!("UNKNOWN: Unknown executor: {}", executor_name))
+                .build();
+        };
 
-        let mut child = Command::new(cmd)
-            .args(args)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn();
+        let invocation = Invocation {
+            name: self.name.clone(),
+            argv: cmd_vec,
+            timeout: Duration::from_secs(self.timeout),
+            limits: ResourceLimits::effective(self.limits.as_ref()),
+            env: macros.env_vars(),
+            ssh: self.ssh.clone(),
+        };
 
-        let secs = Duration::from_secs(self.timeout);
         let start_time = Instant::now();
-        let execution_time: Duration;
-        let mut output = String::new();
-        let mut status_code = 3;
-
-        match child {
-            Ok(ref mut child_proc) => {
-                match child_proc.wait_timeout(secs).unwrap() {
-                    Some(status) => {
-                        execution_time = start_time.elapsed();
-                        if let Some(code) = status.code() {
-                            status_code = code;
-                            child_proc
-                                .stdout
-                                .as_mut()
-                                .unwrap()
-                                .read_to_string(&mut output)
-                                .unwrap();
-                        }
-                    }
-                    None => {
-                        child_proc.kill().unwrap();
-                        execution_time = start_time.elapsed();
-                        let timeout_msg = match secs.as_secs() {
-                            1 => TimeoutMessage::Single,
-                            _ => TimeoutMessage::Multi(secs.as_secs()),
-                        };
-                        let _kill_status = child_proc.wait().unwrap();
-                        child_proc
-                            .stderr
-                            .as_mut()
-                            .unwrap()
-                            .read_to_string(&mut output)
-                            .unwrap();
-                        return maybe_secret_data
-                            .status(3)
-                            .short_output(&timeout_msg.to_string())
-                            .with_execution_time(execution_time)
-                            .build();
-                    }
-                };
+        // Dropping the executor's future on timeout stops whatever it was running. Executors
+        // that stop it themselves, like the process executor, get a moment to report what it
+        // used before that.
+        let completed = timeout(
+            invocation.timeout + EXECUTOR_TIMEOUT_GRACE,
+            executor.execute(&invocation),
+        )
+        .await;
+        let execution_time = start_time.elapsed();
+
+        let output = match completed {
+            Ok(output) => output,
+            Err(_) => {
+                return maybe_secret_data
+                    .status(3)
+                    .short_output(&TimeoutMessage::from(invocation.timeout).to_string())
+                    .with_execution_time(execution_time)
+                    .build();
             }
-            Err(e) => {
-                debug!("Failed to spawn command: {}'", e);
-                execution_time = start_time.elapsed();
-                status_code = 3;
-                output = format!("Failed to execute command with error: '{}'", e);
+        };
+
+        if let Some(usage) = output.resource_usage {
+            maybe_secret_data = maybe_secret_data.with_resource_usage(usage);
+        }
+        maybe_secret_data = match &output.output {
+            PluginOutput::Raw(raw) => maybe_secret_data.parse_output(raw),
+            PluginOutput::Message { short, long } => {
+                let data = maybe_secret_data.short_output(short);
+                match long {
+                    Some(long) => data.long_output(long),
+                    None => data,
+                }
             }
         };
 
         // Build the check result based on the output and the status code
         maybe_secret_data
-            .status(status_code)
-            .parse_output(&output)
+            .status(output.status)
             .with_execution_time(execution_time)
             .build()
     }
@@ -242,7 +528,19 @@ impl Default for CheckBuilder {
             name: None,
             command: None,
             secret_command: None,
+            args: None,
+            secret_args: None,
             timeout: Some(5),
+            shell: None,
+            limits: None,
+            interval: None,
+            executor: None,
+            template: None,
+            ssh: None,
+            thresholds: None,
+            worst_status: false,
+            status_map: None,
+            extract: None,
             variables_found: None,
             variables_not_found: None,
         }
@@ -264,11 +562,86 @@ impl CheckBuilder {
         self
     }
 
+    /// Pass each entry to the command as exactly one argument. The command is then the
+    /// executable only and is never split into words.
+    pub fn args(mut self, args: &[String]) -> Self {
+        self.args = Some(args.to_vec());
+        self
+    }
+
     pub fn timeout(mut self, timeout: u64) -> Self {
         self.timeout = Some(timeout);
         self
     }
 
+    /// Resource limits for the plugin process, taking precedence over the global limits.
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Run the check at most once every `interval` seconds. Between runs, the last result is
+    /// served from the result cache.
+    pub fn interval(mut self, interval: u64) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Run the check with the executor registered under the given name, instead of spawning a
+    /// plugin process. See `executor::register_executor`.
+    pub fn executor(mut self, executor: &str) -> Self {
+        self.executor = Some(executor.to_string());
+        self
+    }
+
+    /// The name of the template the check is loaded from, for `$TEMPLATE_NAME$`.
+    pub fn template(mut self, template: &str) -> Self {
+        self.template = Some(template.to_string());
+        self
+    }
+
+    /// Run the command on a remote host through the system `ssh` client, instead of locally.
+    pub fn ssh(mut self, target: SshTarget) -> Self {
+        self.ssh = Some(target);
+        self
+    }
+
+    /// Evaluate the metrics of the performance data with these labels against the given
+    /// thresholds, instead of those printed by the plugin.
+    pub fn thresholds(mut self, thresholds: Thresholds) -> Self {
+        self.thresholds = Some(thresholds);
+        self
+    }
+
+    /// Make the status of the check the worst of the exit code of the plugin and the states of
+    /// the metrics with thresholds in the check.
+    pub fn worst_status(mut self, worst_status: bool) -> Self {
+        self.worst_status = worst_status;
+        self
+    }
+
+    /// Change the status of the check with the first of these rules that matches its exit code
+    /// and short output. The status before the change is kept as the original status.
+    pub fn status_map(mut self, status_map: StatusMap) -> Self {
+        self.status_map = Some(status_map);
+        self
+    }
+
+    /// Add a metric to the performance data for every match of these rules in the output of the
+    /// check.
+    pub fn extract(mut self, rules: ExtractRules) -> Self {
+        self.extract = Some(rules);
+        self
+    }
+
+    /// Run the command through the given interpreter with `-c` instead of splitting it into
+    /// words and executing it directly. Variable values are shell-quoted when substituted.
+    /// Ignored if the check has an argument list.
+    pub fn shell(mut self, shell: &str) -> Self {
+        self.shell = Some(shell.to_string());
+        self
+    }
+
     pub fn with_variables(mut self) -> Result<Self, VariableError> {
         if let Some(name) = &self.name {
             let variable_string = VariableString::from_str(name)?;
@@ -280,7 +653,11 @@ impl CheckBuilder {
         }
 
         if let Some(command) = &self.command {
-            let new_command = VariableString::from_str(command)?;
+            let command = macros::mark(command);
+            let new_command = match self.shell {
+                Some(_) => VariableString::from_str_shell_quoted(&command)?,
+                None => VariableString::from_str(&command)?,
+            };
             self.command = match new_command.obfuscated_string {
                 Some(ref obfuscated_string) => Some(obfuscated_string.to_string()),
                 None => new_command.clear_string(),
@@ -293,6 +670,74 @@ impl CheckBuilder {
             self.variables_not_found = new_command.variables_not_found;
         }
 
+        if let Some(args) = &self.args {
+            let mut clear_args = Vec::with_capacity(args.len());
+            let mut obfuscated_args = Vec::with_capacity(args.len());
+            let mut has_secret = false;
+
+            for arg in args {
+                let new_arg = VariableString::from_str(&macros::mark(arg))?;
+                has_secret |= new_arg
+                    .variables_found
+                    .iter()
+                    .flatten()
+                    .any(|v| matches!(v, VariableKind::Secret(_)));
+                clear_args.push(new_arg.clear_string().unwrap_or_default());
+                obfuscated_args.push(
+                    new_arg
+                        .obfuscated_string
+                        .clone()
+                        .unwrap_or_else(|| new_arg.clear_string().unwrap_or_default()),
+                );
+                self.variables_found =
+                    merge_variables(self.variables_found.take(), new_arg.variables_found);
+                self.variables_not_found =
+                    merge_variables(self.variables_not_found.take(), new_arg.variables_not_found);
+            }
+
+            self.args = Some(obfuscated_args);
+            self.secret_args = if has_secret { Some(clear_args) } else { None };
+        }
+
+        // The SSH settings are arguments of the local ssh, visible in the process list, so
+        // they can't contain encrypted variables.
+        if let Some(mut target) = self.ssh.take() {
+            let mut resolve = |s: &mut String| -> Result<(), VariableError> {
+                let new_string = VariableString::from_str(s)?;
+                if let Some(VariableKind::Secret(Variable::Found(name, _, _))) = new_string
+                    .variables_found
+                    .iter()
+                    .flatten()
+                    .find(|v| matches!(v, VariableKind::Secret(_)))
+                {
+                    return Err(VariableError::SecretNotAllowed(
+                        name.clone(),
+                        "the SSH settings",
+                    ));
+                }
+                if let Some(resolved) = new_string.clear_string() {
+                    *s = resolved;
+                }
+                self.variables_found =
+                    merge_variables(self.variables_found.take(), new_string.variables_found);
+                self.variables_not_found = merge_variables(
+                    self.variables_not_found.take(),
+                    new_string.variables_not_found,
+                );
+                Ok(())
+            };
+            resolve(&mut target.host)?;
+            for s in target
+                .user
+                .iter_mut()
+                .chain(target.key.iter_mut())
+                .chain(target.options.iter_mut())
+            {
+                resolve(s)?;
+            }
+            self.ssh = Some(target);
+        }
+
         Ok(self)
     }
 
@@ -301,19 +746,72 @@ impl CheckBuilder {
             name: self.name.unwrap_or_default(),
             command: self.command.unwrap_or_default(),
             secret_command: self.secret_command,
+            args: self.args,
+            secret_args: self.secret_args,
             timeout: self.timeout.unwrap_or_default(),
+            shell: self.shell,
+            limits: self.limits,
+            interval: self.interval,
+            executor: self.executor,
+            template: self.template,
+            ssh: self.ssh,
+            thresholds: self.thresholds,
+            worst_status: self.worst_status,
+            status_map: self.status_map,
+            extract: self.extract,
+            range_values: Vec::new(),
             variables_found: None,
             variables_not_found: None,
         }
     }
 
     pub fn build(mut self) -> Result<Check, VariableError> {
+        // Builtin checks run inside xtender, so there's no process for a shell or limits.
+        let builtin = self.executor.is_none()
+            && self
+                .command
+                .as_deref()
+                .is_some_and(|c| is_builtin(c.trim_start()));
+        if builtin && (self.shell.is_some() || self.limits.is_some()) {
+            return Err(VariableError::ParseError(
+                format!(
+                    "The builtin check {} can't have shell or limits",
+                    self.name.unwrap_or_default()
+                )
+                .into(),
+            ));
+        }
+
+        // The args are passed to the program as they are, so there's no command line for a shell.
+        if self.args.is_some() && self.shell.is_some() {
+            return Err(VariableError::ParseError(
+                format!(
+                    "The check {} can't have both shell and args",
+                    self.name.unwrap_or_default()
+                )
+                .into(),
+            ));
+        }
+
         self = self.with_variables()?;
         Ok(Check {
             name: self.name.unwrap_or_default(),
             command: self.command.unwrap_or_default(),
             secret_command: self.secret_command,
+            args: self.args,
+            secret_args: self.secret_args,
             timeout: self.timeout.unwrap_or_default(),
+            shell: self.shell,
+            limits: self.limits,
+            interval: self.interval,
+            executor: self.executor,
+            template: self.template,
+            ssh: self.ssh,
+            thresholds: self.thresholds,
+            worst_status: self.worst_status,
+            status_map: self.status_map,
+            extract: self.extract,
+            range_values: Vec::new(),
             variables_found: self.variables_found,
             variables_not_found: self.variables_not_found,
         })
@@ -327,71 +825,91 @@ impl ChecksExt for Checks {
             .map(Duration::from_secs)
             .sum()
     }
+
+    fn worst_case_time(&self, max_parallel: Option<usize>) -> Duration {
+        match max_parallel {
+            Some(1) => self.total_time_from_timeouts(),
+            _ => wall_time(
+                self.iter().map(|check| Duration::from_secs(check.timeout)),
+                max_parallel,
+            ),
+        }
+    }
 }
 
-fn expand_checks_from_single_range(check: &Check, range: &Range) -> Checks {
-    let mut checks = Checks::new();
-    for i in range.start..=range.end {
-        let name = check.name.replace(
-            &format!("!!{}:{}..{}!!", range.name, range.start, range.end),
-            &i.to_string(),
-        );
-        let command = check.command.replace(
-            &format!("!!{}:{}..{}!!", range.name, range.start, range.end),
-            &i.to_string(),
-        );
-        let secret_command: Option<String> = check.secret_command.as_ref().map(|cmd| {
-            cmd.replace(
-                &format!("!!{}:{}..{}!!", range.name, range.start, range.end),
-                &i.to_string(),
-            )
-        });
-        checks.push(Check::new(&name, &command, secret_command, check.timeout));
+/// The time it takes to run jobs of the given durations, started in order as soon as one of at
+/// most `max_parallel` slots is free.
+pub fn wall_time(
+    durations: impl IntoIterator<Item = Duration>,
+    max_parallel: Option<usize>,
+) -> Duration {
+    let durations = durations.into_iter();
+    let max_parallel = match max_parallel {
+        Some(n) => n.max(1),
+        None => return durations.max().unwrap_or_default(),
+    };
+
+    let mut slots = vec![Duration::ZERO; max_parallel];
+    for duration in durations {
+        let earliest = slots
+            .iter_mut()
+            .min()
+            .expect("There is always at least one slot");
+        *earliest += duration;
     }
-    checks
+    slots.into_iter().max().unwrap_or_default()
+}
+
+fn expand_checks_from_single_range(check: &Check, range: &Range) -> Checks {
+    (range.start..=range.end)
+        .map(|i| check.with_range_value(range, i))
+        .collect()
 }
 
 fn expand_checks_from_double_range(check: &Check, range1: &Range, range2: &Range) -> Checks {
     let mut checks = Checks::new();
     for i in range1.start..=range1.end {
         for j in range2.start..=range2.end {
-            let name = check.name.replace(
-                &format!("!!{}:{}..{}!!", range1.name, range1.start, range1.end),
-                &i.to_string(),
-            );
-            let name = name.replace(
-                &format!("!!{}:{}..{}!!", range2.name, range2.start, range2.end),
-                &j.to_string(),
-            );
-
-            let command = check.command.replace(
-                &format!("!!{}:{}..{}!!", range1.name, range1.start, range1.end),
-                &i.to_string(),
-            );
-            let command = command.replace(
-                &format!("!!{}:{}..{}!!", range2.name, range2.start, range2.end),
-                &j.to_string(),
+            checks.push(
+                check
+                    .with_range_value(range1, i)
+                    .with_range_value(range2, j),
             );
+        }
+    }
+    checks
+}
 
-            let secret_command = match &check.secret_command {
-                Some(cmd) => {
-                    let new_cmd = cmd.replace(
-                        &format!("!!{}:{}..{}!!", range1.name, range1.start, range1.end),
-                        &i.to_string(),
-                    );
-                    let new_cmd = new_cmd.replace(
-                        &format!("!!{}:{}..{}!!", range2.name, range2.start, range2.end),
-                        &j.to_string(),
-                    );
-                    Some(new_cmd)
-                }
-                None => None,
-            };
+// Join an executable and its arguments into a single line for display, quoting only the words
+// that would otherwise be ambiguous.
+fn join_argv(command: &str, args: &[String]) -> String {
+    std::iter::once(command)
+        .chain(args.iter().map(|a| a.as_str()))
+        .map(|word| {
+            if !word.is_empty()
+                && word
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,*$!".contains(c))
+            {
+                word.to_string()
+            } else {
+                shell_quote(word)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
 
-            checks.push(Check::new(&name, &command, secret_command, check.timeout));
+fn merge_variables(a: Option<Variables>, b: Option<Variables>) -> Option<Variables> {
+    match (a, b) {
+        (Some(mut a), Some(b)) => {
+            a.extend(b);
+            a.sort();
+            a.dedup();
+            Some(a)
         }
+        (a, b) => a.or(b),
     }
-    checks
 }
 
 pub async fn run_all_checks_in_parallel(
@@ -399,7 +917,7 @@ pub async fn run_all_checks_in_parallel(
 ) -> Result<CheckResults, Box<dyn std::error::Error>> {
     let futures = checks
         .into_iter()
-        .map(|check| tokio::task::spawn_blocking(move || check.run()));
+        .map(|check| tokio::spawn(async move { check.run_async().await }));
     let results = futures::future::join_all(futures)
         .await
         .into_iter()
@@ -407,10 +925,37 @@ pub async fn run_all_checks_in_parallel(
     Ok(CheckResults(results))
 }
 
-pub fn run_all_checks_sequentially(
+/// Run the checks in parallel, but never more than `max_parallel` at the same time. A check
+/// starts as soon as another one finishes, in the order of the checks.
+pub async fn run_all_checks_with_max_parallel(
+    checks: Checks,
+    max_parallel: usize,
+) -> Result<CheckResults, Box<dyn std::error::Error>> {
+    let mut results = futures::stream::iter(checks.into_iter().enumerate())
+        .map(|(index, check)| async move {
+            tokio::spawn(async move { check.run_async().await })
+                .await
+                .map(|result| (index, result))
+        })
+        .buffer_unordered(max_parallel.max(1))
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    results.sort_by_key(|(index, _)| *index);
+    Ok(CheckResults(
+        results.into_iter().map(|(_, result)| result).collect(),
+    ))
+}
+
+pub async fn run_all_checks_sequentially(
     checks: Checks,
 ) -> Result<CheckResults, Box<dyn std::error::Error>> {
-    let results = checks.into_iter().map(|check| check.run()).collect();
+    let mut results = Vec::with_capacity(checks.len());
+    for check in checks {
+        results.push(check.run_async().await);
+    }
     Ok(CheckResults(results))
 }
 
@@ -418,6 +963,22 @@ pub fn run_all_checks_sequentially(
 mod check_test {
     use super::*;
     use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_worst_case_time() {
+        let checks = [10, 5, 5, 5, 20]
+            .iter()
+            .map(|t| CheckBuilder::new().timeout(*t).build_raw())
+            .collect::<Checks>();
+
+        assert_eq!(checks.worst_case_time(None), Duration::from_secs(20));
+        assert_eq!(checks.worst_case_time(Some(1)), Duration::from_secs(45));
+        // The last check can't start until 10 seconds in, when both slots have been busy.
+        assert_eq!(checks.worst_case_time(Some(2)), Duration::from_secs(30));
+        assert_eq!(checks.worst_case_time(Some(10)), Duration::from_secs(20));
+        assert_eq!(Checks::new().worst_case_time(Some(2)), Duration::ZERO);
+    }
+
     #[test]
     fn test_existing_var_in_command_name() -> Result<(), Box<dyn std::error::Error>> {
         std::env::set_var("FOO", "bar");
@@ -446,4 +1007,134 @@ mod check_test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_shell_check_quotes_variables_in_command() -> Result<(), Box<dyn std::error::Error>> {
+        std::env::set_var("SHELL_CHECK_VAR", "a b");
+
+        let check = CheckBuilder::new()
+            .name("test $SHELL_CHECK_VAR$")
+            .command("ls $SHELL_CHECK_VAR$ | head -n 1")
+            .shell(DEFAULT_SHELL)
+            .build()?;
+
+        // Only the command is handed to the shell, so the name is left as is.
+        assert_eq!(check.name, "test a b");
+        assert_eq!(check.command, "ls 'a b' | head -n 1");
+        assert_eq!(check.shell(), Some(DEFAULT_SHELL));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_builtin_check_without_process_settings() {
+        let builder = || {
+            CheckBuilder::new()
+                .name("web")
+                .command("builtin:tcp -H a -p 80")
+        };
+
+        assert!(builder().build().is_ok());
+        assert_eq!(
+            builder()
+                .shell(DEFAULT_SHELL)
+                .build()
+                .unwrap_err()
+                .to_string(),
+            "Parse error: The builtin check web can't have shell or limits"
+        );
+        assert!(builder().limits(ResourceLimits::default()).build().is_err());
+    }
+
+    #[test]
+    fn test_check_with_args_rejects_shell() {
+        let builder = || {
+            CheckBuilder::new()
+                .name("list")
+                .command("ls")
+                .args(&["-l".to_string(), "/tmp".to_string()])
+        };
+
+        assert!(builder().build().is_ok());
+        assert_eq!(
+            builder()
+                .shell(DEFAULT_SHELL)
+                .build()
+                .unwrap_err()
+                .to_string(),
+            "Parse error: The check list can't have both shell and args"
+        );
+    }
+
+    #[test]
+    fn test_ssh_target_with_variables() -> Result<(), Box<dyn std::error::Error>> {
+        std::env::set_var("SSH_CHECK_HOST", "db1.example.com");
+
+        let check = CheckBuilder::new()
+            .name("remote")
+            .command("check_load")
+            .ssh(SshTarget::new("$SSH_CHECK_HOST$").user("$SSH_CHECK_MISSING$"))
+            .build()?;
+
+        let target = check.ssh().unwrap();
+        assert_eq!(target.host, "db1.example.com");
+        assert_eq!(check.variables_found().map(|v| v.len()), Some(1));
+        assert_eq!(check.variables_not_found().map(|v| v.len()), Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_ranges_keeps_shell() {
+        let check = CheckBuilder::new()
+            .name("test !!A:1..2!!")
+            .command("echo !!A:1..2!! | cat")
+            .shell("/bin/bash")
+            .build_raw();
+
+        let checks = check.expand_ranges();
+
+        assert_eq!(checks.len(), 2);
+        assert!(checks.iter().all(|c| c.shell() == Some("/bin/bash")));
+        assert_eq!(checks[1].command, "echo 2 | cat");
+    }
+
+    #[test]
+    fn test_args_are_substituted_one_by_one() -> Result<(), Box<dyn std::error::Error>> {
+        std::env::set_var("ARGS_CHECK_VAR", "it's \"quoted\" and spaced");
+
+        let check = CheckBuilder::new()
+            .name("test args")
+            .command("printf")
+            .args(&["%s|".to_string(), "$ARGS_CHECK_VAR$".to_string()])
+            .build()?;
+
+        assert_eq!(
+            check.args(),
+            Some(&["%s|".to_string(), "it's \"quoted\" and spaced".to_string()][..])
+        );
+        assert_eq!(check.secret_args, None);
+        assert_eq!(check.variables_found.as_ref().map(|v| v.len()), Some(1),);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_ranges_in_args() {
+        let check = CheckBuilder::new()
+            .name("test !!A:1..2!! !!B:3..4!!")
+            .command("echo")
+            .args(&["!!A:1..2!!".to_string(), "x !!B:3..4!!".to_string()])
+            .build_raw();
+
+        let checks = check.expand_ranges();
+
+        assert_eq!(checks.len(), 4);
+        assert_eq!(checks[3].name, "test 2 4");
+        assert_eq!(
+            checks[3].args(),
+            Some(&["2".to_string(), "x 4".to_string()][..])
+        );
+        assert_eq!(checks[3].display_command(), "echo 2 'x 4'");
+    }
 }
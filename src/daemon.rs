@@ -0,0 +1,298 @@
+use crate::check::{Check, Checks};
+use crate::result::{CheckResult, CheckResults};
+use log::{debug, error, info};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, sleep_until, Instant};
+
+/// Seconds between runs of checks that don't have an interval of their own.
+pub const DEFAULT_DAEMON_INTERVAL: u64 = 60;
+/// Maximum number of seconds of random delay added before each run.
+pub const DEFAULT_DAEMON_JITTER: u64 = 5;
+
+type Render = dyn Fn(&CheckResults) -> String + Send + Sync;
+
+/// Runs every check on its own schedule and keeps the rendered results of the latest runs in a
+/// file, so that a sampler only has to read the file.
+pub struct Daemon {
+    checks: Checks,
+    output: PathBuf,
+    default_interval: Duration,
+    jitter: Duration,
+    render: Arc<Render>,
+}
+
+/// The latest result of every check, numbered by how often they were updated.
+struct DaemonState {
+    results: Vec<Option<CheckResult>>,
+    generation: u64,
+}
+
+/// Writes the output file, one snapshot of the results at a time.
+struct OutputWriter {
+    output: PathBuf,
+    render: Arc<Render>,
+    /// The generation of the results last written.
+    written: Mutex<u64>,
+}
+
+impl Daemon {
+    /// `render` turns the latest results, in the same order as the checks, into the contents of
+    /// the output file.
+    pub fn new<P, F>(checks: Checks, output: P, render: F) -> Self
+    where
+        P: AsRef<Path>,
+        F: Fn(&CheckResults) -> String + Send + Sync + 'static,
+    {
+        Self {
+            checks,
+            output: output.as_ref().to_path_buf(),
+            default_interval: Duration::from_secs(DEFAULT_DAEMON_INTERVAL),
+            jitter: Duration::from_secs(DEFAULT_DAEMON_JITTER),
+            render: Arc::new(render),
+        }
+    }
+
+    pub fn default_interval(mut self, default_interval: u64) -> Self {
+        self.default_interval = Duration::from_secs(default_interval);
+        self
+    }
+
+    pub fn jitter(mut self, jitter: u64) -> Self {
+        self.jitter = Duration::from_secs(jitter);
+        self
+    }
+
+    /// Schedule the checks and keep running them until SIGINT or SIGTERM is received. Plugins
+    /// that are still running at that point are killed.
+    pub async fn run(self) -> io::Result<()> {
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        let state = Arc::new(Mutex::new(DaemonState {
+            results: vec![None; self.checks.len()],
+            generation: 0,
+        }));
+        let writer = Arc::new(OutputWriter {
+            output: self.output,
+            render: self.render,
+            written: Mutex::new(0),
+        });
+        let mut tasks = JoinSet::new();
+
+        info!(
+            "Scheduling {} checks, writing results to {}",
+            self.checks.len(),
+            writer.output.display()
+        );
+
+        for (index, check) in self.checks.into_iter().enumerate() {
+            let state = Arc::clone(&state);
+            let writer = Arc::clone(&writer);
+            let interval = check_interval(&check, self.default_interval);
+            let jitter = self.jitter;
+
+            tasks.spawn(async move {
+                // Spread the first runs, so that all plugins don't start at the same time.
+                sleep(random_delay(jitter)).await;
+
+                loop {
+                    let started = Instant::now();
+                    let result = check.run_async().await;
+                    debug!("Check {} finished, updating the output", check.name());
+                    let (generation, results) = state.lock().unwrap().update(index, result);
+                    // Rendering and syncing the file block, so they're done outside of the lock
+                    // and off the async worker threads.
+                    let writer = Arc::clone(&writer);
+                    let _ = tokio::task::spawn_blocking(move || writer.write(generation, &results))
+                        .await;
+                    sleep_until(started + interval + random_delay(jitter)).await;
+                }
+            });
+        }
+
+        tokio::select! {
+            _ = sigint.recv() => info!("Received SIGINT, stopping"),
+            _ = sigterm.recv() => info!("Received SIGTERM, stopping"),
+        }
+
+        tasks.shutdown().await;
+        Ok(())
+    }
+}
+
+impl DaemonState {
+    /// Store the result of a check, and return a snapshot of the latest results.
+    fn update(&mut self, index: usize, result: CheckResult) -> (u64, CheckResults) {
+        self.results[index] = Some(result);
+        self.generation += 1;
+
+        let results = CheckResults(self.results.iter().flatten().cloned().collect());
+        (self.generation, results)
+    }
+}
+
+impl OutputWriter {
+    /// Write the results, unless newer ones have been written already.
+    fn write(&self, generation: u64, results: &CheckResults) {
+        let mut written = self.written.lock().unwrap();
+        if *written >= generation {
+            return;
+        }
+
+        if let Err(e) = write_atomically(&self.output, &(self.render)(results)) {
+            error!(
+                "Unable to write results to {}: {}",
+                self.output.display(),
+                e
+            );
+        }
+        *written = generation;
+    }
+}
+
+fn check_interval(check: &Check, default_interval: Duration) -> Duration {
+    check
+        .interval()
+        .filter(|i| *i > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(default_interval)
+}
+
+fn random_delay(max: Duration) -> Duration {
+    let max_millis = max.as_millis() as u64;
+    if max_millis == 0 {
+        return Duration::ZERO;
+    }
+
+    let mut bytes = [0; 8];
+    match openssl::rand::rand_bytes(&mut bytes) {
+        Ok(()) => Duration::from_millis(u64::from_ne_bytes(bytes) % max_millis),
+        Err(e) => {
+            debug!("Unable to generate a random delay: {}", e);
+            Duration::ZERO
+        }
+    }
+}
+
+/// Replace the contents of a file, so that readers always see either the old or the new
+/// contents in full.
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file path"))?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.tmp.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod daemon_test {
+    use super::*;
+    use crate::check::CheckBuilder;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_write_atomically() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("results.csv");
+
+        write_atomically(&path, "first")?;
+        write_atomically(&path, "second")?;
+
+        assert_eq!(fs::read_to_string(&path)?, "second");
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_interval() {
+        let default_interval = Duration::from_secs(60);
+        let with_interval = CheckBuilder::new().interval(3600).build_raw();
+        let zero_interval = CheckBuilder::new().interval(0).build_raw();
+
+        assert_eq!(
+            check_interval(&with_interval, default_interval),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            check_interval(&zero_interval, default_interval),
+            default_interval
+        );
+        assert_eq!(
+            check_interval(&Check::default(), default_interval),
+            default_interval
+        );
+    }
+
+    #[test]
+    fn test_random_delay_is_below_max() {
+        assert_eq!(random_delay(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(random_delay(Duration::from_secs(1)) < Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn test_writer_skips_older_results() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("results.csv");
+        let writer = OutputWriter {
+            output: path.clone(),
+            render: Arc::new(|results: &CheckResults| results.0.len().to_string()),
+            written: Mutex::new(0),
+        };
+        let results = |n| CheckResults(vec![CheckResult::default(); n]);
+
+        writer.write(2, &results(2));
+        writer.write(1, &results(1));
+
+        assert_eq!(fs::read_to_string(&path)?, "2");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_daemon_writes_results() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("results.csv");
+        let checks = vec![
+            CheckBuilder::new()
+                .name("first")
+                .command("echo first")
+                .build_raw(),
+            CheckBuilder::new()
+                .name("second")
+                .command("echo second")
+                .build_raw(),
+        ];
+
+        let daemon = Daemon::new(checks, &path, |results| {
+            results
+                .0
+                .iter()
+                .map(|r| format!("{}={}\n", r.name(), r.short_output()))
+                .collect()
+        })
+        .jitter(0);
+
+        let _ = tokio::time::timeout(Duration::from_secs(2), daemon.run()).await;
+
+        assert_eq!(fs::read_to_string(&path)?, "first=first\nsecond=second\n");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+//! Executors run the command of a check and return its status and output. A check chooses an
+//! executor by name, and the result is processed the same way whichever executor ran it. The
+//! "process" executor spawns the plugin, the "builtin" executor runs the checks built into
+//! xtender, and the "ssh" executor runs the command on a remote host. Library users can
+//! register their own executors with `register_executor`.
+
+use crate::builtin::{is_builtin, run_builtin};
+use crate::check::TimeoutMessage;
+use crate::limit::ResourceLimits;
+use crate::process::{ChildProcess, ResourceUsage};
+use crate::ssh::{SshExecutor, SshTarget, SSH_EXECUTOR};
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+pub const PROCESS_EXECUTOR: &str = "process";
+pub const BUILTIN_EXECUTOR: &str = "builtin";
+
+lazy_static! {
+    static ref EXECUTORS: RwLock<HashMap<String, Arc<dyn Executor>>> = {
+        let mut executors: HashMap<String, Arc<dyn Executor>> = HashMap::new();
+        executors.insert(PROCESS_EXECUTOR.to_string(), Arc::new(ProcessExecutor));
+        executors.insert(BUILTIN_EXECUTOR.to_string(), Arc::new(BuiltinExecutor));
+        executors.insert(SSH_EXECUTOR.to_string(), Arc::new(SshExecutor::default()));
+        RwLock::new(executors)
+    };
+}
+
+/// Runs the command of a check. The future returned by `execute` is dropped if the check times
+/// out, so an executor must clean up anything it started when it's dropped.
+pub trait Executor: Send + Sync {
+    fn execute<'a>(&'a self, invocation: &'a Invocation) -> BoxFuture<'a, ExecutorOutput>;
+}
+
+/// What to run, with variables already substituted. Encrypted variables are decrypted in
+/// `argv`, so it must never be logged or stored.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Invocation {
+    pub name: String,
+    /// The executable and its arguments, or the interpreter, `-c` and the command for checks
+    /// run through a shell.
+    pub argv: Vec<String>,
+    pub timeout: Duration,
+    /// The limits of the check combined with the global limits.
+    pub limits: ResourceLimits,
+    /// Environment variables to set for the check, in addition to those of xtender, such as
+    /// the runtime macros as `NAGIOS_*` variables.
+    pub env: Vec<(String, String)>,
+    /// The remote host to run the command on, for executors that run it remotely.
+    pub ssh: Option<SshTarget>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PluginOutput {
+    /// Output in the Nagios plugin format, parsed into short output, long output and
+    /// performance data.
+    Raw(String),
+    /// Output that's used as is, without parsing.
+    Message { short: String, long: Option<String> },
+}
+
+/// The status and output of an executed check.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExecutorOutput {
+    pub status: i32,
+    pub output: PluginOutput,
+    pub resource_usage: Option<ResourceUsage>,
+}
+
+impl ExecutorOutput {
+    /// Output in the Nagios plugin format.
+    pub fn plugin(status: i32, output: &str) -> Self {
+        Self {
+            status,
+            output: PluginOutput::Raw(output.to_string()),
+            resource_usage: None,
+        }
+    }
+
+    /// A short output that isn't parsed, such as an error message.
+    pub fn message(status: i32, short: &str) -> Self {
+        Self {
+            status,
+            output: PluginOutput::Message {
+                short: short.to_string(),
+                long: None,
+            },
+            resource_usage: None,
+        }
+    }
+
+    pub fn with_long_output(mut self, long: &str) -> Self {
+        if let PluginOutput::Message { long: l, .. } = &mut self.output {
+            *l = Some(long.to_string());
+        }
+        self
+    }
+
+    pub fn with_resource_usage(mut self, resource_usage: ResourceUsage) -> Self {
+        self.resource_usage = Some(resource_usage);
+        self
+    }
+}
+
+/// Register an executor under a name, replacing any executor already registered with it.
+pub fn register_executor<E: Executor + 'static>(name: &str, executor: E) {
+    EXECUTORS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name.to_string(), Arc::new(executor));
+}
+
+pub fn executor(name: &str) -> Option<Arc<dyn Executor>> {
+    EXECUTORS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .cloned()
+}
+
+/// The executor used for a command when the check doesn't name one.
+pub fn default_executor_name(executable: &str) -> &'static str {
+    if is_builtin(executable) {
+        BUILTIN_EXECUTOR
+    } else {
+        PROCESS_EXECUTOR
+    }
+}
+
+/// Spawns the command as a plugin process, with the resource limits applied.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessExecutor;
+
+impl Executor for ProcessExecutor {
+    fn execute<'a>(&'a self, invocation: &'a Invocation) -> BoxFuture<'a, ExecutorOutput> {
+        Box::pin(run_process(invocation))
+    }
+}
+
+async fn run_process(invocation: &Invocation) -> ExecutorOutput {
+    let mut command = Command::new(&invocation.argv[0]);
+    command
+        .args(&invocation.argv[1..])
+        .envs(invocation.env.iter().cloned())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let limits = invocation.limits;
+    if !limits.is_empty() {
+        debug!("Applying resource limits: {:?}", limits);
+        // SAFETY: `apply` only calls setrlimit, which is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || limits.apply());
+        }
+    }
+
+    let collected = match ChildProcess::spawn(&mut command) {
+        Ok(mut child) => collect_output(&mut child, None, invocation.timeout).await,
+        Err(e) => Err(e),
+    };
+
+    match collected {
+        Ok(CollectedOutput {
+            timed_out: true,
+            usage,
+            ..
+        }) => ExecutorOutput::message(3, &TimeoutMessage::from(invocation.timeout).to_string())
+            .with_resource_usage(usage),
+        Ok(CollectedOutput {
+            status,
+            usage,
+            stdout,
+            stderr,
+            ..
+        }) => {
+            let all_output = format!("{}{}", stdout, stderr);
+            if let Some(msg) = limits.breach_message(&status, &all_output) {
+                return ExecutorOutput::message(3, &msg)
+                    .with_long_output(all_output.trim())
+                    .with_resource_usage(usage);
+            }
+            match status.code() {
+                Some(code) => ExecutorOutput::plugin(code, &stdout),
+                None => ExecutorOutput::plugin(3, ""),
+            }
+            .with_resource_usage(usage)
+        }
+        Err(e) => {
+            debug!("Failed to execute command: {}'", e);
+            ExecutorOutput::plugin(3, &format!("Failed to execute command with error: '{}'", e))
+        }
+    }
+}
+
+/// The exit status and output of a process that has been waited for.
+pub(crate) struct CollectedOutput {
+    pub status: ExitStatus,
+    pub usage: ResourceUsage,
+    pub stdout: String,
+    pub stderr: String,
+    /// Whether the process was killed because it didn't exit within the timeout.
+    pub timed_out: bool,
+}
+
+/// Write the input to the stdin of the child, if any, and wait for it to exit. Both pipes are
+/// drained while waiting, so a plugin writing a lot of output can never block on a full pipe.
+/// If it doesn't exit within the timeout, it's killed and reaped, so that the resources it
+/// used are still known.
+pub(crate) async fn collect_output(
+    child: &mut ChildProcess,
+    input: Option<&[u8]>,
+    timeout: Duration,
+) -> io::Result<CollectedOutput> {
+    let mut stdin = child.stdin.take();
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+    let mut stdout_buf = Vec::new();
+    let mut stderr_buf = Vec::new();
+
+    let write_stdin = async {
+        if let (Some(pipe), Some(input)) = (&mut stdin, input) {
+            if let Err(e) = pipe.write_all(input).await {
+                debug!("Failed to write to pipe: {}", e);
+            }
+        }
+        // Closing stdin tells the child that there's no more input.
+        drop(stdin);
+    };
+
+    let run = async {
+        let (status, _, _, _) = tokio::join!(
+            child.wait(),
+            write_stdin,
+            read_pipe(&mut stdout, &mut stdout_buf),
+            read_pipe(&mut stderr, &mut stderr_buf),
+        );
+        status
+    };
+
+    let (exited, timed_out) = match tokio::time::timeout(timeout, run).await {
+        Ok(exited) => (exited, false),
+        Err(_) => {
+            debug!("Killing the process after {:?}", timeout);
+            child.kill()?;
+            (child.wait().await, true)
+        }
+    };
+
+    exited.map(|(status, usage)| CollectedOutput {
+        status,
+        usage,
+        stdout: String::from_utf8_lossy(&stdout_buf).to_string(),
+        stderr: String::from_utf8_lossy(&stderr_buf).to_string(),
+        timed_out,
+    })
+}
+
+async fn read_pipe<R: AsyncRead + Unpin>(pipe: &mut Option<R>, buf: &mut Vec<u8>) {
+    if let Some(pipe) = pipe {
+        if let Err(e) = pipe.read_to_end(buf).await {
+            debug!("Failed to read from pipe: {}", e);
+        }
+    }
+}
+
+/// Runs the checks built into xtender, named by a command starting with `builtin:`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BuiltinExecutor;
+
+impl Executor for BuiltinExecutor {
+    fn execute<'a>(&'a self, invocation: &'a Invocation) -> BoxFuture<'a, ExecutorOutput> {
+        Box::pin(async move {
+            let (status, output) = run_builtin(&invocation.argv, invocation.timeout).await;
+            ExecutorOutput::plugin(status, &output)
+        })
+    }
+}
+
+#[cfg(test)]
+mod executor_test {
+    use super::*;
+    use crate::check::CheckBuilder;
+    use pretty_assertions::assert_eq;
+
+    struct UpperCaseExecutor;
+
+    impl Executor for UpperCaseExecutor {
+        fn execute<'a>(&'a self, invocation: &'a Invocation) -> BoxFuture<'a, ExecutorOutput> {
+            Box::pin(async move {
+                ExecutorOutput::plugin(
+                    1,
+                    &format!("{}|len={}", invocation.argv.join(" ").to_uppercase(), 5),
+                )
+            })
+        }
+    }
+
+    struct SleepingExecutor;
+
+    impl Executor for SleepingExecutor {
+        fn execute<'a>(&'a self, _: &'a Invocation) -> BoxFuture<'a, ExecutorOutput> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                ExecutorOutput::plugin(0, "done")
+            })
+        }
+    }
+
+    #[test]
+    fn test_default_executor_name() {
+        assert_eq!(default_executor_name("builtin:tcp"), BUILTIN_EXECUTOR);
+        assert_eq!(default_executor_name("check_ping"), PROCESS_EXECUTOR);
+    }
+
+    #[tokio::test]
+    async fn test_registered_executor() -> Result<(), Box<dyn std::error::Error>> {
+        register_executor("upper", UpperCaseExecutor);
+        std::env::set_var("EXECUTOR_TEST_WORD", "world");
+
+        let result = CheckBuilder::new()
+            .name("upper")
+            .command("hello $EXECUTOR_TEST_WORD$")
+            .executor("upper")
+            .build()?
+            .run_async()
+            .await;
+
+        assert_eq!(result.status(), Some(1));
+        assert_eq!(result.short_output(), "HELLO WORLD");
+        assert_eq!(result.performance_data(), "len=5");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_registered_executor_times_out() {
+        register_executor("sleeping", SleepingExecutor);
+
+        let result = CheckBuilder::new()
+            .name("sleeping")
+            .command("sleep")
+            .executor("sleeping")
+            .timeout(1)
+            .build_raw()
+            .run_async()
+            .await;
+
+        assert_eq!(result.status(), Some(3));
+        assert_eq!(result.short_output(), "UNKNOWN: Timed out after 1 second");
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_process_is_reaped() {
+        let invocation = Invocation {
+            name: "sleep".to_string(),
+            argv: vec!["sleep".to_string(), "10".to_string()],
+            timeout: Duration::from_millis(200),
+            limits: ResourceLimits::default(),
+            env: Vec::new(),
+            ssh: None,
+        };
+
+        let output = run_process(&invocation).await;
+
+        assert_eq!(output.status, 3);
+        assert!(matches!(
+            output.output,
+            PluginOutput::Message { ref short, .. } if short.starts_with("UNKNOWN: Timed out")
+        ));
+        assert!(output.resource_usage.is_some_and(|u| u.max_rss > 0));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_executor() {
+        let result = CheckBuilder::new()
+            .name("unknown")
+            .command("echo hello")
+            .executor("missing")
+            .build_raw()
+            .run_async()
+            .await;
+
+        assert_eq!(result.status(), Some(3));
+        assert_eq!(result.short_output(), "UNKNOWN: Unknown executor: missing");
+    }
+}
@@ -0,0 +1,222 @@
+//! Metrics extracted from the output text of plugins that print no performance data, such as
+//! `Queue depth is 421`. Each rule is a regular expression with a capture group named `value`,
+//! and optionally groups named `label` and `uom`. Every match becomes a metric that's added to
+//! the performance data of the check.
+
+use crate::perfdata::{parse_number, PerfDatum};
+use crate::status_map::OutputPattern;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtractRule {
+    pub regex: OutputPattern,
+    /// The label of the metric, unless the regular expression captures a `label`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// The unit of measurement of the metric, unless the regular expression captures a `uom`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uom: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crit: Option<String>,
+}
+
+pub type ExtractRules = Vec<ExtractRule>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExtractRuleError {
+    MissingValueGroup(String),
+    MissingLabel(String),
+}
+
+impl std::error::Error for ExtractRuleError {}
+
+impl fmt::Display for ExtractRuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractRuleError::MissingValueGroup(regex) => write!(
+                f,
+                "The extract regex {} has no capture group named value",
+                regex
+            ),
+            ExtractRuleError::MissingLabel(regex) => write!(
+                f,
+                "The extract regex {} has no label and no capture group named label",
+                regex
+            ),
+        }
+    }
+}
+
+impl ExtractRule {
+    pub fn new(regex: OutputPattern) -> Self {
+        Self {
+            regex,
+            label: None,
+            uom: None,
+            warn: None,
+            crit: None,
+        }
+    }
+
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn uom(mut self, uom: &str) -> Self {
+        self.uom = Some(uom.to_string());
+        self
+    }
+
+    pub fn warn(mut self, warn: &str) -> Self {
+        self.warn = Some(warn.to_string());
+        self
+    }
+
+    pub fn crit(mut self, crit: &str) -> Self {
+        self.crit = Some(crit.to_string());
+        self
+    }
+
+    /// Check that every match of the rule can become a metric.
+    pub fn validate(&self) -> Result<(), ExtractRuleError> {
+        let has_group = |name| {
+            self.regex
+                .regex()
+                .capture_names()
+                .flatten()
+                .any(|n| n == name)
+        };
+        if !has_group("value") {
+            return Err(ExtractRuleError::MissingValueGroup(
+                self.regex.as_str().to_string(),
+            ));
+        }
+        if self.label.is_none() && !has_group("label") {
+            return Err(ExtractRuleError::MissingLabel(
+                self.regex.as_str().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The metrics of all matches in the text. Matches with a value that isn't a number are
+    /// skipped.
+    pub fn extract(&self, text: &str) -> Vec<PerfDatum> {
+        self.regex
+            .regex()
+            .captures_iter(text)
+            .filter_map(|captures| {
+                let group = |name| captures.name(name).map(|m| m.as_str().trim());
+                let label = group("label")
+                    .filter(|l| !l.is_empty())
+                    .or(self.label.as_deref())?;
+                let uom = group("uom")
+                    .filter(|u| !u.is_empty())
+                    .or(self.uom.as_deref());
+
+                Some(PerfDatum {
+                    label: label.to_string(),
+                    value: Some(parse_number(group("value")?)?),
+                    uom: uom.map(|u| u.to_string()),
+                    warn: self.warn.clone(),
+                    crit: self.crit.clone(),
+                    ..PerfDatum::default()
+                })
+            })
+            .collect()
+    }
+}
+
+/// The metrics extracted from the text by all rules, in the order of the rules.
+pub fn extract_perfdata(rules: &[ExtractRule], text: &str) -> Vec<PerfDatum> {
+    rules.iter().flat_map(|rule| rule.extract(text)).collect()
+}
+
+#[cfg(test)]
+mod extract_test {
+    use super::*;
+    use crate::perfdata::parse_perfdata;
+    use pretty_assertions::assert_eq;
+
+    fn rule(regex: &str) -> ExtractRule {
+        ExtractRule::new(OutputPattern::new(regex).unwrap())
+    }
+
+    #[test]
+    fn test_extract_with_fixed_label() {
+        let rules = vec![rule(r"Queue depth is (?P<value>\d+)")
+            .label("queue_depth")
+            .warn("100")
+            .crit("500")];
+
+        assert_eq!(
+            extract_perfdata(&rules, "OK - Queue depth is 421")
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            vec!["queue_depth=421;100;500"]
+        );
+        assert_eq!(extract_perfdata(&rules, "Queue is empty"), vec![]);
+    }
+
+    #[test]
+    fn test_extract_with_captured_label_and_uom() {
+        let rules = vec![
+            rule(r"(?P<label>\w+) uses (?P<value>[\d,.]+) ?(?P<uom>[KMG]?B)").uom("B"),
+            rule(r"took (?P<value>\S+) s").label("duration").uom("s"),
+        ];
+
+        assert_eq!(
+            extract_perfdata(
+                &rules,
+                "OK\n/var uses 1,5 GB\n/tmp uses 20MB\ncleanup took 0.25 s\nbackup took long s"
+            )
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>(),
+            vec!["var=1.5GB", "tmp=20MB", "duration=0.25s"]
+        );
+    }
+
+    #[test]
+    fn test_extracted_metrics_are_parsed_back() {
+        let rules = vec![rule(r"(?P<label>\S+) is at (?P<value>[\d.]+) ?(?P<uom>°C)")];
+
+        let datums = extract_perfdata(&rules, "rack;1 is at 24.5 °C\nrack 2 is at 22 °C");
+        let perfdata = datums
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        assert_eq!(perfdata, "'rack;1'=24.5°C 2=22°C");
+        assert_eq!(
+            parse_perfdata(&perfdata)
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>(),
+            Ok(datums)
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(rule(r"(?P<value>\d+)").label("x").validate(), Ok(()));
+        assert_eq!(rule(r"(?P<label>\w+)=(?P<value>\d+)").validate(), Ok(()));
+        assert_eq!(
+            rule(r"(\d+)")
+                .label("x")
+                .validate()
+                .unwrap_err()
+                .to_string(),
+            r"The extract regex (\d+) has no capture group named value"
+        );
+        assert_eq!(
+            rule(r"(?P<value>\d+)").validate().unwrap_err().to_string(),
+            r"The extract regex (?P<value>\d+) has no label and no capture group named label"
+        );
+    }
+}
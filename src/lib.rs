@@ -1,5 +1,25 @@
+pub mod agent;
+pub mod budget;
+pub mod builtin;
+pub mod cache;
 pub mod check;
+pub mod daemon;
+pub mod executor;
+pub mod extract;
+pub mod limit;
+pub mod lock;
+pub mod macros;
+pub mod net;
+pub mod nrpe;
 pub mod opspack;
+pub mod perfdata;
+pub mod plan;
+pub mod process;
 pub mod range;
+pub mod resource;
 pub mod result;
+pub mod ssh;
+pub mod status_map;
+pub mod threshold;
+pub mod uom;
 pub mod variable;
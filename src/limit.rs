@@ -0,0 +1,275 @@
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+/// Limits applied to every check that doesn't set its own. Each limit set on a check takes
+/// precedence over the same global limit.
+pub static GLOBAL_RESOURCE_LIMITS: OnceCell<ResourceLimits> = OnceCell::new();
+
+const MEMORY_ERROR_PATTERNS: [&str; 5] = [
+    "Cannot allocate memory",
+    "out of memory",
+    "Out of memory",
+    "MemoryError",
+    "std::bad_alloc",
+];
+const OPEN_FILES_ERROR_PATTERNS: [&str; 2] = ["Too many open files", "EMFILE"];
+const PROCESSES_ERROR_PATTERNS: [&str; 3] =
+    ["Resource temporarily unavailable", "fork: retry", "EAGAIN"];
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type RlimitResource = libc::c_int;
+
+/// Resource limits applied with `setrlimit` in the plugin process right before it's executed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceLimits {
+    /// Maximum size of the virtual address space in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_space: Option<u64>,
+    /// Maximum CPU time in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_time: Option<u64>,
+    /// Maximum number of open file descriptors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
+    /// Maximum number of processes for the user running the plugin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processes: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LimitParseError {
+    InvalidNumber(String),
+    InvalidSuffix(String),
+}
+
+impl std::error::Error for LimitParseError {}
+
+impl fmt::Display for LimitParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitParseError::InvalidNumber(s) => write!(f, "Invalid number in limit: {}", s),
+            LimitParseError::InvalidSuffix(s) => {
+                write!(f, "Invalid size suffix in limit, expected K, M or G: {}", s)
+            }
+        }
+    }
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Fill in every limit that isn't set from `other`.
+    pub fn or(self, other: &Self) -> Self {
+        Self {
+            address_space: self.address_space.or(other.address_space),
+            cpu_time: self.cpu_time.or(other.cpu_time),
+            open_files: self.open_files.or(other.open_files),
+            processes: self.processes.or(other.processes),
+        }
+    }
+
+    /// The limits of a check combined with the global limits.
+    pub fn effective(limits: Option<&Self>) -> Self {
+        let global = GLOBAL_RESOURCE_LIMITS.get().copied().unwrap_or_default();
+        match limits {
+            Some(l) => l.or(&global),
+            None => global,
+        }
+    }
+
+    /// Apply the limits to the current process. This is meant to be called between fork and exec,
+    /// so it must not allocate.
+    pub fn apply(&self) -> std::io::Result<()> {
+        if let Some(bytes) = self.address_space {
+            set_rlimit(libc::RLIMIT_AS, bytes, bytes)?;
+        }
+        if let Some(secs) = self.cpu_time {
+            // A higher hard limit makes the kernel send SIGXCPU rather than SIGKILL, so that the
+            // breach can be told apart from other reasons for the plugin getting killed.
+            set_rlimit(libc::RLIMIT_CPU, secs, secs.saturating_add(1))?;
+        }
+        if let Some(files) = self.open_files {
+            set_rlimit(libc::RLIMIT_NOFILE, files, files)?;
+        }
+        if let Some(procs) = self.processes {
+            set_rlimit(libc::RLIMIT_NPROC, procs, procs)?;
+        }
+        Ok(())
+    }
+
+    /// Explain which limit a plugin most likely ran into, given how it exited and what it wrote.
+    /// The output is only taken as evidence when the plugin didn't report a status of its own,
+    /// so that a CRITICAL about a server running out of memory keeps its status.
+    pub fn breach_message(&self, status: &ExitStatus, output: &str) -> Option<String> {
+        if status.success() {
+            return None;
+        }
+        // Killed by a signal, UNKNOWN, or an exit code outside of the plugin statuses.
+        let abnormal = !matches!(status.code(), Some(0..=2));
+
+        if let (Some(secs), Some(libc::SIGXCPU)) = (self.cpu_time, status.signal()) {
+            return Some(format!(
+                "UNKNOWN: CPU time limit of {} {} exceeded",
+                secs,
+                if secs == 1 { "second" } else { "seconds" }
+            ));
+        }
+
+        if let Some(bytes) = self.address_space {
+            if abnormal && contains_any(output, &MEMORY_ERROR_PATTERNS) {
+                return Some(format!(
+                    "UNKNOWN: Address space limit of {} bytes exceeded",
+                    bytes
+                ));
+            }
+            if let Some(signal @ (libc::SIGSEGV | libc::SIGBUS | libc::SIGABRT)) = status.signal() {
+                return Some(format!(
+                    "UNKNOWN: Killed by signal {}, probably after exceeding the address space limit of {} bytes",
+                    signal, bytes
+                ));
+            }
+        }
+
+        if let Some(files) = self.open_files {
+            if abnormal && contains_any(output, &OPEN_FILES_ERROR_PATTERNS) {
+                return Some(format!("UNKNOWN: Open files limit of {} exceeded", files));
+            }
+        }
+
+        if let Some(procs) = self.processes {
+            if abnormal && contains_any(output, &PROCESSES_ERROR_PATTERNS) {
+                return Some(format!("UNKNOWN: Process limit of {} exceeded", procs));
+            }
+        }
+
+        None
+    }
+}
+
+/// Parse a size in bytes, optionally followed by a K, M or G suffix (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, LimitParseError> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                _ => return Err(LimitParseError::InvalidSuffix(s.to_string())),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| LimitParseError::InvalidNumber(s.to_string()))
+}
+
+fn set_rlimit(resource: RlimitResource, soft: u64, hard: u64) -> std::io::Result<()> {
+    let rlim = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    // SAFETY: `rlim` is a valid, initialized rlimit struct that outlives the call.
+    if unsafe { libc::setrlimit(resource, &rlim) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+fn contains_any(s: &str, patterns: &[&str]) -> bool {
+    patterns.iter().any(|p| s.contains(p))
+}
+
+#[cfg(test)]
+mod limit_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("512m"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size(" 2 G "), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+
+    #[test]
+    fn test_limits_or() {
+        let check = ResourceLimits {
+            cpu_time: Some(5),
+            ..ResourceLimits::default()
+        };
+        let global = ResourceLimits {
+            cpu_time: Some(60),
+            open_files: Some(256),
+            ..ResourceLimits::default()
+        };
+
+        assert_eq!(
+            check.or(&global),
+            ResourceLimits {
+                cpu_time: Some(5),
+                open_files: Some(256),
+                ..ResourceLimits::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_breach_message() {
+        let limits = ResourceLimits {
+            address_space: Some(1024),
+            cpu_time: Some(1),
+            open_files: Some(8),
+            processes: None,
+        };
+
+        assert_eq!(
+            limits.breach_message(&ExitStatus::from_raw(libc::SIGXCPU), ""),
+            Some("UNKNOWN: CPU time limit of 1 second exceeded".to_string())
+        );
+        assert_eq!(
+            limits.breach_message(&ExitStatus::from_raw(3 << 8), "MemoryError"),
+            Some("UNKNOWN: Address space limit of 1024 bytes exceeded".to_string())
+        );
+        assert_eq!(
+            limits.breach_message(&ExitStatus::from_raw(126 << 8), "foo: Too many open files"),
+            Some("UNKNOWN: Open files limit of 8 exceeded".to_string())
+        );
+        // The plugin reported a status of its own, which is kept.
+        assert_eq!(
+            limits.breach_message(
+                &ExitStatus::from_raw(2 << 8),
+                "CRITICAL - db1: Out of memory"
+            ),
+            None
+        );
+        // No process limit is set, so it can't have been hit.
+        assert_eq!(
+            limits.breach_message(&ExitStatus::from_raw(1 << 8), "fork: retry"),
+            None
+        );
+        // A successful plugin never breached a limit.
+        assert_eq!(
+            limits.breach_message(&ExitStatus::from_raw(0), "Too many open files"),
+            None
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// An exclusive lock on a file in the state directory, held until it's dropped. Used to stop
+/// two runs for the same sampler from running the same checks at the same time.
+#[derive(Debug)]
+pub struct RunLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Take the lock, or return `None` if another process holds it.
+    pub fn try_acquire<P: AsRef<Path>>(path: P) -> io::Result<Option<Self>> {
+        Self::acquire_with(path.as_ref(), libc::LOCK_EX | libc::LOCK_NB)
+    }
+
+    /// Take the lock, waiting for as long as another process holds it.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::acquire_with(path.as_ref(), libc::LOCK_EX)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::WouldBlock, "Lock held by another process")
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn acquire_with(path: &Path, operation: libc::c_int) -> io::Result<Option<Self>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        loop {
+            // SAFETY: The file descriptor is valid for as long as `file` is alive. The lock is
+            // released by the kernel when the file is closed.
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+
+        // The PID is only written to make it easier to find the process holding the lock.
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+
+        Ok(Some(Self {
+            file,
+            path: path.to_path_buf(),
+        }))
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// The lock name used when none is given, derived from the set of templates so that runs of
+/// the same templates share a lock regardless of their order.
+pub fn lock_name_for_templates(templates: &[String]) -> String {
+    let mut templates = templates.to_vec();
+    templates.sort();
+    templates.dedup();
+
+    let hash = openssl::sha::sha256(templates.join("\n").as_bytes());
+    format!("templates-{}", hex::encode(&hash[..8]))
+}
+
+/// Replace every character that isn't safe in a file name. A name without any letters or
+/// digits left, such as `///`, is replaced by a hash of it instead, so that such names don't
+/// all share the same lock.
+pub fn sanitize_lock_name(name: &str) -> String {
+    let sanitized = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_string();
+
+    if sanitized.chars().any(|c| c.is_ascii_alphanumeric()) {
+        sanitized
+    } else {
+        let hash = openssl::sha::sha256(name.as_bytes());
+        format!("name-{}", hex::encode(&hash[..8]))
+    }
+}
+
+#[cfg(test)]
+mod lock_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_lock_is_exclusive() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("state").join("sampler.lock");
+
+        let lock = RunLock::try_acquire(&path)?.expect("The lock is free");
+        assert!(RunLock::try_acquire(&path)?.is_none());
+
+        drop(lock);
+        assert!(RunLock::try_acquire(&path)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_name_for_templates_ignores_order() {
+        let a = lock_name_for_templates(&["network-base".to_string(), "linux".to_string()]);
+        let b = lock_name_for_templates(&["linux".to_string(), "network-base".to_string()]);
+        let c = lock_name_for_templates(&["linux".to_string()]);
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a.starts_with("templates-"));
+    }
+
+    #[test]
+    fn test_sanitize_lock_name() {
+        assert_eq!(sanitize_lock_name("my-sampler_1.a"), "my-sampler_1.a");
+        assert_eq!(sanitize_lock_name("../etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_lock_name("a b/c"), "a_b_c");
+
+        let unsafe_only = ["///", "???", "..", ""].map(sanitize_lock_name);
+        assert!(unsafe_only.iter().all(|n| n.starts_with("name-")));
+        assert_eq!(unsafe_only[0], sanitize_lock_name("///"));
+        let mut distinct = unsafe_only.to_vec();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), unsafe_only.len());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::variable::shell_quote;
+use once_cell::sync::{Lazy, OnceCell};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds subtracted from the timeout of a check for `$PLUGIN_TIMEOUT$`, so that a plugin
+/// given it as its own timeout gives up before xtender kills it.
+pub const TIMEOUT_MARGIN: u64 = 1;
+pub const DEFAULT_PLUGIN_DIR: &str = "/opt/itrs/xtender/plugins";
+/// Prefix of the environment variables that the runtime macros are exported as.
+pub const ENV_PREFIX: &str = "NAGIOS_";
+
+/// The plugin directory given on the command line, used for `$PLUGIN_DIR$`.
+pub static PLUGIN_DIR: OnceCell<String> = OnceCell::new();
+
+static RUN_ID: Lazy<String> = Lazy::new(|| {
+    let mut bytes = [0; 8];
+    match openssl::rand::rand_bytes(&mut bytes) {
+        Ok(()) => hex::encode(bytes),
+        Err(_) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            format!("{:x}{:08x}", now.as_nanos(), std::process::id())
+        }
+    }
+});
+
+const NAMES: [&str; 8] = [
+    "TIMEOUT",
+    "PLUGIN_TIMEOUT",
+    "CHECK_NAME",
+    "TEMPLATE_NAME",
+    "PLUGIN_DIR",
+    "RUN_ID",
+    "RANGE_A",
+    "RANGE_B",
+];
+
+/// Surrounds a runtime macro once it's marked. It can't be part of a variable value, since
+/// neither environment variables nor arguments can contain it.
+const MARKER: char = '\0';
+
+/// Whether a variable name is a macro resolved by xtender when the check runs, rather than
+/// looked up in the environment. `PLUGIN_DIR` is an ordinary variable when it's set in the
+/// environment and not given with `--plugin-dir`, so that it can be encrypted and shows up in
+/// the variables found.
+pub fn is_runtime_macro(name: &str) -> bool {
+    match name {
+        "PLUGIN_DIR" => PLUGIN_DIR.get().is_some() || std::env::var_os("PLUGIN_DIR").is_none(),
+        name => NAMES.contains(&name),
+    }
+}
+
+/// Mark the runtime macros in a template, before its variables are substituted. Only marked
+/// macros are resolved when the check runs, so a variable value that looks like a macro is
+/// left as is.
+pub fn mark(s: &str) -> String {
+    NAMES
+        .iter()
+        .filter(|name| is_runtime_macro(name))
+        .fold(s.to_string(), |s, name| {
+            s.replace(&format!("${}$", name), &marked(name))
+        })
+}
+
+/// A runtime macro as it's marked in a template.
+pub fn marked(name: &str) -> String {
+    format!("{}{}{}", MARKER, name, MARKER)
+}
+
+/// An ID shared by every check run by this xtender process.
+pub fn run_id() -> &'static str {
+    &RUN_ID
+}
+
+/// The plugin directory from `--plugin-dir`, the `PLUGIN_DIR` environment variable, or the
+/// default, in that order.
+pub fn plugin_dir() -> String {
+    PLUGIN_DIR
+        .get()
+        .cloned()
+        .or_else(|| std::env::var("PLUGIN_DIR").ok())
+        .unwrap_or_else(|| DEFAULT_PLUGIN_DIR.to_string())
+}
+
+/// The values of the runtime macros of a single check, by name without the dollar signs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RuntimeMacros(Vec<(String, String)>);
+
+impl RuntimeMacros {
+    pub fn new(
+        check_name: &str,
+        template_name: Option<&str>,
+        timeout: u64,
+        range_values: &[(String, i32)],
+    ) -> Self {
+        let mut macros = vec![
+            ("TIMEOUT".to_string(), timeout.to_string()),
+            (
+                "PLUGIN_TIMEOUT".to_string(),
+                timeout.saturating_sub(TIMEOUT_MARGIN).max(1).to_string(),
+            ),
+            ("CHECK_NAME".to_string(), check_name.to_string()),
+            (
+                "TEMPLATE_NAME".to_string(),
+                template_name.unwrap_or_default().to_string(),
+            ),
+            ("PLUGIN_DIR".to_string(), plugin_dir()),
+            ("RUN_ID".to_string(), run_id().to_string()),
+        ];
+        macros.extend(
+            range_values
+                .iter()
+                .map(|(name, value)| (format!("RANGE_{}", name), value.to_string())),
+        );
+        Self(macros)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Replace every marked runtime macro in the string with its value.
+    pub fn apply(&self, s: &str) -> String {
+        self.apply_with(s, |v| v.to_string())
+    }
+
+    /// Like `apply`, but every value is quoted for `/bin/sh`.
+    pub fn apply_shell_quoted(&self, s: &str) -> String {
+        self.apply_with(s, shell_quote)
+    }
+
+    /// The macros as `NAGIOS_*` environment variables, such as `NAGIOS_TIMEOUT`.
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (format!("{}{}", ENV_PREFIX, name), value.clone()))
+            .collect()
+    }
+
+    fn apply_with(&self, s: &str, quote: impl Fn(&str) -> String) -> String {
+        if !s.contains(MARKER) {
+            return s.to_string();
+        }
+        self.0.iter().fold(s.to_string(), |s, (name, value)| {
+            s.replace(&marked(name), &quote(value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod macros_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_is_runtime_macro() {
+        assert!(is_runtime_macro("TIMEOUT"));
+        assert!(is_runtime_macro("RANGE_A"));
+        assert!(!is_runtime_macro("RANGE_C"));
+        assert!(!is_runtime_macro("HOSTADDRESS"));
+    }
+
+    #[test]
+    fn test_apply_runtime_macros() {
+        let macros =
+            RuntimeMacros::new("disk /var", Some("linux-base"), 10, &[("A".to_string(), 2)]);
+
+        assert_eq!(
+            macros.apply(&mark(
+                "check_disk -t $PLUGIN_TIMEOUT$ -p $RANGE_A$ $TIMEOUT$ $OTHER$"
+            )),
+            "check_disk -t 9 -p 2 10 $OTHER$"
+        );
+        assert_eq!(
+            macros.apply_shell_quoted(&mark("echo $CHECK_NAME$ $TEMPLATE_NAME$")),
+            "echo 'disk /var' 'linux-base'"
+        );
+        // Only macros marked in the template are resolved, not those in variable values.
+        assert_eq!(
+            macros.apply(&format!("{} $TIMEOUT$", mark("$TIMEOUT$"))),
+            "10 $TIMEOUT$"
+        );
+        assert_eq!(macros.get("RUN_ID"), Some(run_id()));
+        assert_eq!(run_id().len(), 16);
+        assert!(macros
+            .env_vars()
+            .contains(&("NAGIOS_RANGE_A".to_string(), "2".to_string())));
+    }
+
+    #[test]
+    fn test_plugin_timeout_is_at_least_one_second() {
+        let macros = RuntimeMacros::new("a", None, 1, &[]);
+
+        assert_eq!(macros.get("PLUGIN_TIMEOUT"), Some("1"));
+        assert_eq!(macros.get("TEMPLATE_NAME"), Some(""));
+    }
+}
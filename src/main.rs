@@ -1,15 +1,39 @@
-use clap::Parser;
-use geneos_xtender::check::{
-    run_all_checks_in_parallel, run_all_checks_sequentially, CheckBuilder, Checks,
+use clap::{Parser, Subcommand, ValueEnum};
+use geneos_xtender::agent::{
+    agent_acceptor, agent_executor_name, controller_connector, AgentAddress, AgentExecutor,
+    AgentServer, DEFAULT_AGENT_LISTEN,
+};
+use geneos_xtender::budget::{ExecutionHistory, RunBudget};
+use geneos_xtender::cache::{run_all_checks_with_cache, ResultCache};
+use geneos_xtender::check::{Check, CheckBuilder, Checks, DEFAULT_SHELL};
+use geneos_xtender::daemon::{
+    write_atomically, Daemon, DEFAULT_DAEMON_INTERVAL, DEFAULT_DAEMON_JITTER,
+};
+use geneos_xtender::executor::register_executor;
+use geneos_xtender::extract::{ExtractRule, ExtractRules};
+use geneos_xtender::limit::{parse_size, ResourceLimits, GLOBAL_RESOURCE_LIMITS};
+use geneos_xtender::lock::{lock_name_for_templates, sanitize_lock_name, RunLock};
+use geneos_xtender::macros::PLUGIN_DIR;
+use geneos_xtender::nrpe::server::{
+    tls_acceptor, AllowList, NrpeServer, DEFAULT_ALLOWED_HOSTS, DEFAULT_LISTEN,
 };
 use geneos_xtender::opspack::Opspack;
-use geneos_xtender::result::ProcessedCheckResultsExt;
+use geneos_xtender::plan::CheckPlan;
+use geneos_xtender::process::ResourceUsage;
+use geneos_xtender::resource::{NagiosResources, RESOURCES};
+use geneos_xtender::result::{
+    format_cpu_time, format_max_rss, CheckResults, ProcessOptions, ProcessedCheckResultsExt,
+};
+use geneos_xtender::ssh::SshTarget;
+use geneos_xtender::status_map::{parse_status, OutputPattern, StatusMap, StatusRule};
+use geneos_xtender::threshold::{MetricThresholds, Threshold, Thresholds};
 use geneos_xtender::variable::{KeyFile, ALLOW_EMPTY_VARS, KEY_FILE};
 use log::{debug, error};
 use serde_yml::Value;
 use std::fs;
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 
 const ABOUT_XTENDER: &str = r#"
 Geneos Xtender:
@@ -34,6 +58,131 @@ The file format for Xtender Templates is YAML and the format is:
   command: |
     <command with args>
   timeout: <timeout> # (optional)
+  interval: <seconds between runs> # (optional)
+  shell: <true or path to interpreter> # (optional)
+  limits: # (optional)
+    address_space: <bytes, with an optional K, M or G suffix>
+    cpu_time: <seconds>
+    open_files: <number of file descriptors>
+    processes: <number of processes>
+  agent: <name of an agent given with --agent> # (optional)
+  ssh: # (optional, or just the host name)
+    host: <remote host>
+    user: <user> # (optional)
+    port: <port> # (optional)
+    key: <path to private key> # (optional)
+    options: [<ssh -o option>] # (optional)
+  thresholds: # (optional)
+    <perfdata label>:
+      warn: <range> # (optional)
+      crit: <range> # (optional)
+  worst_status: <true or false> # (optional)
+  status_map: # (optional)
+    - exit: <status> # (optional)
+      output: <regular expression> # (optional)
+      status: <status>
+  extract: # (optional)
+    - regex: <regular expression with a capture group named value>
+      label: <label> # (optional with a capture group named label)
+      uom: <unit of measurement> # (optional)
+      warn: <range> # (optional)
+      crit: <range> # (optional)
+
+Instead of a single command line, a check may name the executable
+in "command" and list its arguments separately:
+- name: <name>
+  command: <executable>
+  args:
+    - <argument>
+    - <argument>
+
+Each argument is passed to the executable as exactly one argument,
+even if it contains spaces or quotes after variable substitution.
+
+Commands are split into words and executed directly. Set "shell" to
+run a command through /bin/sh -c (or another interpreter) when it
+needs pipelines or redirections. Variable values are then quoted
+automatically, so don't put quotes around them yourself.
+
+Checks with "ssh" run their command on the remote host with the
+system ssh client, like check_by_ssh. The command is sent to the
+remote shell on its standard input, so decrypted secrets never show
+up in the local process list. Use --ssh-host with -c for the same.
+
+The "thresholds" of a check replace the warn and crit ranges printed
+by the plugin for the metrics with those labels, for plugins that
+take no thresholds. With "worst_status", the status of the check is
+the worst of the exit code and the states of those metrics.
+
+The "status_map" of a check changes its status with the first rule
+that matches both its exit status and its short output, if given.
+Statuses are 0 to 3 or OK, WARNING, CRITICAL and UNKNOWN. The status
+before the change is shown in the originalStatus column.
+
+Every match of an "extract" regular expression in the output of a
+check adds a metric to its performance data, for plugins that only
+print values as text, such as (?P<value>\d+) messages queued.
+
+Commands starting with "builtin:" run a check that's built into
+xtender instead of a plugin, for example
+"builtin:tcp --host example.com --port 443". The builtin checks are
+tcp, http, tls, dns, file and nrpe, which queries an NRPE daemon.
+
+Nagios $USERn$ macros, such as $USER1$, are resolved from a Nagios
+resource file given with --resource-file, or from
+/opt/itrs/xtender/resource.cfg if it exists. Encrypted values in it are
+decrypted with the key file. Macros missing from the resource file
+are read from the environment like any other variable.
+
+Some macros are resolved by xtender itself when a check runs:
+$TIMEOUT$ (the timeout of the check), $PLUGIN_TIMEOUT$ (the timeout
+minus 1 second, to pass on to the plugin), $CHECK_NAME$,
+$TEMPLATE_NAME$, $PLUGIN_DIR$ (set with --plugin-dir), $RANGE_A$ and
+$RANGE_B$ (the current range values) and $RUN_ID$. They're also
+exported to the plugin as NAGIOS_TIMEOUT, NAGIOS_CHECK_NAME and so on.
+Without --plugin-dir, a PLUGIN_DIR environment variable is used like
+any other variable, and may be encrypted. Macros are only resolved in
+the template, never in the values of variables.
+
+Checks with an interval run at most once per interval. Until they're
+due again, their last result is served from the state directory
+(/opt/itrs/xtender/state/ by default), and the resultAge and stale
+columns show how old it is.
+
+Use --dry-run to see which checks would run, with their ranges
+expanded, secrets obfuscated and the variables that were and weren't
+found, without running any plugin. Add "json" for JSON output.
+
+To stop a run from overlapping with a previous run that's still
+active, use --lock or --lock-name. With --on-locked, a second run
+either waits, prints only a "previous run still active" headline,
+or prints the output of the last completed run with that headline.
+
+Use "xtender budget --sample-interval <seconds> -- <templates>" to
+find out whether a sampler running the templates could overrun its
+interval, given the check timeouts and --max-parallel. With --history,
+the measured execution times of earlier runs are also used to estimate
+the expected time of a run, and the checks that dominate it.
+
+Use "xtender daemon --output <file> -- <templates>" to keep running
+the checks in the background, each on its own interval (60 seconds
+by default) with a random delay of up to --jitter seconds. The latest
+results are written to the output file, which a sampler can simply
+read with cat.
+
+Use "xtender nrpe-server --tls-cert <file> --tls-key <file> --
+<templates>" to answer NRPE queries from Nagios pollers. A query runs
+the check whose name, or name in lower case with other characters
+than letters and digits replaced by "_", matches its command. Only
+the hosts in --allowed-hosts (127.0.0.1 and ::1 by default) may
+connect, and --tls-ca requires pollers to present a client
+certificate. Use --no-tls for pollers using check_nrpe -n.
+
+Checks with "agent" are dispatched to an xtender agent in another
+network zone, started with "xtender agent --tls-cert <file> --tls-key
+<file> --tls-ca <file>". Give each agent as --agent <name>=<host>[:port]
+(5667 by default) with --agent-cert, --agent-key and --agent-ca for
+mutual TLS. Their results are part of the same output as all others.
 
 Example command that runs all checks contained in the templates
 "network-base" and a custom template located at
@@ -55,10 +204,16 @@ Make sure that each entry in the template follows this format:
 
 const TEMPLATES_DIR: &str = "/opt/itrs/xtender/templates/";
 const CUSTOM_TEMPLATES_DIR: &str = "/opt/itrs/xtender/templates/custom/";
+const STATE_DIR: &str = "/opt/itrs/xtender/state/";
+const RESOURCE_FILE: &str = "/opt/itrs/xtender/resource.cfg";
+const LOCKED_HEADLINE: &str = "<!>lockStatus,previous run still active";
 
 #[derive(Parser, Debug, Default)]
-#[command(about = ABOUT_XTENDER, author, version, long_about = None)]
+#[command(about = ABOUT_XTENDER, author, version, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
     /// Allow variables to be empty or unset. May cause unexpected behaviour.
     #[arg(long)]
     allow_empty_vars: bool,
@@ -80,6 +235,52 @@ struct Args {
     #[arg(short, long, requires = "name", conflicts_with = "templates")]
     timeout: Option<u64>,
 
+    /// Minimum number of seconds between runs of the check. This is only used if no templates are provided.
+    #[arg(short, long, requires = "name", conflicts_with = "templates")]
+    interval: Option<u64>,
+
+    /// Run the command through a shell (default /bin/sh). This is only used if no templates are provided.
+    #[arg(long, requires = "command", conflicts_with = "templates",
+          num_args = 0..=1, default_missing_value = DEFAULT_SHELL)]
+    shell: Option<String>,
+
+    /// Run the command on this host through ssh instead of locally. This is only used if no templates are provided.
+    #[arg(long, requires = "command", conflicts_with = "templates")]
+    ssh_host: Option<String>,
+
+    /// User to log in to the remote host as
+    #[arg(long, requires = "ssh_host")]
+    ssh_user: Option<String>,
+
+    /// Port of the SSH server on the remote host
+    #[arg(long, requires = "ssh_host")]
+    ssh_port: Option<u16>,
+
+    /// Private key to log in to the remote host with
+    #[arg(long, requires = "ssh_host")]
+    ssh_key: Option<String>,
+
+    /// Option passed to ssh -o, such as StrictHostKeyChecking=accept-new. Can be given more than once.
+    #[arg(long, requires = "ssh_host")]
+    ssh_option: Vec<String>,
+
+    /// Agent to dispatch the checks with "agent: <name>" to, as <name>=<host>[:<port>]. Can be given more than once.
+    #[arg(long, value_parser = AgentAddress::from_str,
+          requires_all = ["agent_cert", "agent_key", "agent_ca"])]
+    agent: Vec<AgentAddress>,
+
+    /// PEM file with the client certificate presented to the agents
+    #[arg(long, requires = "agent")]
+    agent_cert: Option<String>,
+
+    /// PEM file with the private key of the client certificate
+    #[arg(long, requires = "agent")]
+    agent_key: Option<String>,
+
+    /// PEM file with the CA certificates that the certificates of the agents must be signed by
+    #[arg(long, requires = "agent")]
+    agent_ca: Option<String>,
+
     /// Enable debug logging
     #[arg(short, long)]
     debug: bool,
@@ -92,9 +293,194 @@ struct Args {
     #[arg(short, long)]
     key_file: Option<String>,
 
+    /// Plugin directory for $PLUGIN_DIR$ [default: the PLUGIN_DIR environment variable, or /opt/itrs/xtender/plugins]
+    #[arg(long)]
+    plugin_dir: Option<String>,
+
+    /// Nagios resource file to resolve $USERn$ macros from [default: /opt/itrs/xtender/resource.cfg, if it exists]
+    #[arg(long)]
+    resource_file: Option<String>,
+
     /// Run checks sequentially instead of in parallel
     #[arg(short, long)]
     sequential: bool,
+
+    /// Run at most this many checks at the same time. --sequential is the same as 1.
+    #[arg(long, global = true,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_parallel: Option<usize>,
+
+    /// File with the measured execution times of recent runs. It's updated after every run, and
+    /// used to estimate the expected time of a run with the budget command.
+    #[arg(long, global = true)]
+    history: Option<String>,
+
+    /// Directory for cached results of checks with an interval
+    #[arg(long, default_value = STATE_DIR)]
+    state_dir: String,
+
+    /// Print the checks that would run, with ranges expanded and variables resolved, without
+    /// running any of them
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "table")]
+    dry_run: Option<DryRunFormat>,
+
+    /// Hold a lock in the state directory while running, keyed on the templates, so that
+    /// overlapping runs for the same sampler don't compete for the same devices
+    #[arg(long)]
+    lock: bool,
+
+    /// Name of the lock to hold while running, instead of one derived from the templates.
+    /// Implies --lock.
+    #[arg(long)]
+    lock_name: Option<String>,
+
+    /// What to do when a previous run still holds the lock
+    #[arg(long, value_enum, default_value_t = OnLocked::Headline)]
+    on_locked: OnLocked,
+
+    /// Add columns with the CPU time and memory used by each plugin, and totals in headlines
+    #[arg(long)]
+    resource_usage: bool,
+
+    /// Add columns with the state of each metric for its warn and crit ranges, and the reason
+    #[arg(long)]
+    threshold_state: bool,
+
+    /// Add columns with the value, unit and thresholds of each metric in its base unit, such as
+    /// bytes for MB and seconds for ms
+    #[arg(long)]
+    normalize_units: bool,
+
+    /// Limit the address space of each plugin, in bytes with an optional K, M or G suffix
+    #[arg(long, value_parser = parse_size)]
+    limit_address_space: Option<u64>,
+
+    /// Limit the CPU time of each plugin in seconds
+    #[arg(long)]
+    limit_cpu_time: Option<u64>,
+
+    /// Limit the number of open files of each plugin
+    #[arg(long)]
+    limit_open_files: Option<u64>,
+
+    /// Limit the number of processes of the user running the plugins
+    #[arg(long)]
+    limit_processes: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum OnLocked {
+    /// Wait for the previous run to finish
+    Wait,
+    /// Exit right away with a headline saying that the previous run is still active
+    #[default]
+    Headline,
+    /// Print the output of the last completed run, with the same headline
+    Cached,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum DryRunFormat {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Keep running the checks in the templates, each on its own schedule, and write the latest
+    /// results to a file
+    Daemon(DaemonArgs),
+    /// Calculate how long a run of the checks in the templates can take, and warn if it could
+    /// overrun the sample interval
+    Budget(BudgetArgs),
+    /// Answer NRPE queries from Nagios pollers with the checks in the templates, named by the
+    /// command of the query
+    NrpeServer(NrpeServerArgs),
+    /// Run the checks sent by a controller, an xtender started with --agent, and return their
+    /// results to it
+    Agent(AgentArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct BudgetArgs {
+    /// Seconds between two samples of the sampler running the templates
+    #[arg(long)]
+    sample_interval: u64,
+
+    /// Xtender Templates containing the checks of the sampler
+    #[arg(required = true)]
+    templates: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DaemonArgs {
+    /// File to write the results to. It's replaced atomically after every finished check.
+    #[arg(long)]
+    output: String,
+
+    /// Seconds between runs of checks without an interval
+    #[arg(long, default_value_t = DEFAULT_DAEMON_INTERVAL)]
+    default_interval: u64,
+
+    /// Maximum random delay in seconds added before each run, to spread the checks out
+    #[arg(long, default_value_t = DEFAULT_DAEMON_JITTER)]
+    jitter: u64,
+
+    /// Xtender Templates containing checks to schedule
+    #[arg(required = true)]
+    templates: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct NrpeServerArgs {
+    /// Address and port to listen on
+    #[arg(long, default_value = DEFAULT_LISTEN)]
+    listen: String,
+
+    /// Comma separated IP addresses and networks, such as 10.0.0.0/8, allowed to connect
+    #[arg(long, default_value = DEFAULT_ALLOWED_HOSTS)]
+    allowed_hosts: String,
+
+    /// PEM file with the certificate of the server
+    #[arg(long, required_unless_present = "no_tls", requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// PEM file with the private key of the server
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// PEM file with the CA certificates that client certificates must be signed by. Without
+    /// it, pollers don't need a client certificate.
+    #[arg(long, requires = "tls_cert")]
+    tls_ca: Option<String>,
+
+    /// Accept plain NRPE queries without TLS, as sent by check_nrpe -n
+    #[arg(long, conflicts_with_all = ["tls_cert", "tls_key", "tls_ca"])]
+    no_tls: bool,
+
+    /// Xtender Templates containing the checks to serve
+    #[arg(required = true)]
+    templates: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct AgentArgs {
+    /// Address and port to listen on
+    #[arg(long, default_value = DEFAULT_AGENT_LISTEN)]
+    listen: String,
+
+    /// PEM file with the certificate of the agent
+    #[arg(long)]
+    tls_cert: String,
+
+    /// PEM file with the private key of the agent
+    #[arg(long)]
+    tls_key: String,
+
+    /// PEM file with the CA certificates that the client certificates of controllers must be
+    /// signed by
+    #[arg(long)]
+    tls_ca: String,
 }
 
 struct ParsedTemplates {
@@ -142,6 +528,8 @@ async fn main() {
     }
 
     let parsed_args = Args::parse();
+    let run_lock_name = run_lock_name(&parsed_args);
+    let max_parallel = max_parallel(&parsed_args);
 
     stderrlog::new()
         .module(module_path!())
@@ -158,6 +546,21 @@ async fn main() {
         debug!("The option --allow-empty-vars is set. Allowing empty or unset variables!");
     }
 
+    let global_limits = ResourceLimits {
+        address_space: parsed_args.limit_address_space,
+        cpu_time: parsed_args.limit_cpu_time,
+        open_files: parsed_args.limit_open_files,
+        processes: parsed_args.limit_processes,
+    };
+
+    if !global_limits.is_empty() {
+        debug!("Global resource limits: {:?}", global_limits);
+        if GLOBAL_RESOURCE_LIMITS.set(global_limits).is_err() {
+            error!("Warning: Attempted to set GLOBAL_RESOURCE_LIMITS more than once.");
+            std::process::exit(1)
+        }
+    }
+
     if let Some(key_file_path) = parsed_args.key_file {
         let key_file_string = fs::read_to_string(&key_file_path).unwrap_or_else(|_| {
             error!("Failed to read key file: {}", key_file_path);
@@ -199,6 +602,21 @@ async fn main() {
         debug!("--key-file option not used, and no default key file found. No decryption will be possible.");
     }
 
+    load_resource_file(parsed_args.resource_file.as_deref());
+    register_agents(
+        &parsed_args.agent,
+        parsed_args.agent_cert.as_deref(),
+        parsed_args.agent_key.as_deref(),
+        parsed_args.agent_ca.as_deref(),
+    );
+
+    if let Some(plugin_dir) = parsed_args.plugin_dir.as_ref() {
+        if PLUGIN_DIR.set(plugin_dir.clone()).is_err() {
+            error!("Warning: Attempted to set PLUGIN_DIR more than once.");
+            std::process::exit(1)
+        }
+    }
+
     if let Some(opspack_file) = parsed_args.opspack {
         let opspack_json = match fs::read_to_string(opspack_file) {
             Ok(s) => s,
@@ -228,113 +646,504 @@ async fn main() {
         }
     }
 
+    if let Some(mode) = &parsed_args.mode {
+        match mode {
+            Mode::Daemon(daemon_args) => {
+                let process_options = ProcessOptions {
+                    resource_usage: parsed_args.resource_usage,
+                    threshold_state: parsed_args.threshold_state,
+                    normalized_units: parsed_args.normalize_units,
+                    ..ProcessOptions::default()
+                };
+                run_daemon(daemon_args, process_options).await;
+                std::process::exit(0);
+            }
+            Mode::Budget(budget_args) => {
+                print_budget(budget_args, max_parallel, parsed_args.history.as_deref());
+                std::process::exit(0);
+            }
+            Mode::NrpeServer(nrpe_server_args) => {
+                run_nrpe_server(nrpe_server_args).await;
+                std::process::exit(0);
+            }
+            Mode::Agent(agent_args) => {
+                run_agent(agent_args).await;
+                std::process::exit(0);
+            }
+        }
+    }
+
     let mut checks = Checks::new();
     let mut parsed_templates = ParsedTemplates::new();
 
     if let (Some(command), Some(name)) = (parsed_args.command, parsed_args.name) {
-        let check = CheckBuilder::new()
+        let mut check_builder = CheckBuilder::new()
             .name(&name)
             .command(&command)
-            .timeout(parsed_args.timeout.unwrap_or(DEFAULT_TIMEOUT))
-            .build();
+            .timeout(parsed_args.timeout.unwrap_or(DEFAULT_TIMEOUT));
 
-        let range_checks = match check {
-            Ok(c) => c.expand_ranges(),
-            Err(e) => {
-                error!("Unable to build check: {}", e);
-                std::process::exit(1)
-            }
-        };
+        if let Some(shell) = &parsed_args.shell {
+            check_builder = check_builder.shell(shell);
+        }
+
+        if let Some(interval) = parsed_args.interval {
+            check_builder = check_builder.interval(interval);
+        }
 
-        for rc in range_checks {
-            checks.push(rc);
+        if let Some(host) = &parsed_args.ssh_host {
+            let target = SshTarget {
+                host: host.to_string(),
+                user: parsed_args.ssh_user.clone(),
+                port: parsed_args.ssh_port,
+                key: parsed_args.ssh_key.clone(),
+                options: parsed_args.ssh_option.clone(),
+            };
+            check_builder = check_builder.ssh(target);
         }
+
+        checks.push(build_or_exit(check_builder));
     }
 
     if let Some(template_names) = parsed_args.templates.clone() {
         parsed_templates = ParsedTemplates::from_template_names(&template_names);
+        checks.extend(checks_from_templates(&parsed_templates));
+    }
+
+    if let Some(format) = parsed_args.dry_run {
+        let mut plan = CheckPlan::new(&parsed_templates.found, &parsed_templates.missing);
+        for check in checks {
+            plan.add_check(check);
+        }
+        match format {
+            DryRunFormat::Table => print!("{}", plan.as_table()),
+            DryRunFormat::Json => match plan.as_json() {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    error!("Unable to generate JSON: {}", e);
+                    std::process::exit(1)
+                }
+            },
+        }
+        std::process::exit(0);
+    }
+
+    let checks = expand_all_ranges(checks);
+    let has_intervals = checks.iter().any(|c| c.interval().is_some());
+    let has_status_maps = checks.iter().any(|c| c.status_map().is_some());
+    let cache = ResultCache::new(&parsed_args.state_dir);
+
+    let process_options = ProcessOptions {
+        resource_usage: parsed_args.resource_usage,
+        result_age: has_intervals,
+        threshold_state: parsed_args.threshold_state,
+        original_status: has_status_maps,
+        normalized_units: parsed_args.normalize_units,
+    };
 
-        for template in &parsed_templates.strings {
-            let template_yaml: Value =
-                serde_yml::from_str(template).expect("Failed to parse yaml template from string");
-
-            let yaml_checks_vec = template_yaml
-                .as_sequence()
-                .expect(INVALID_YAML_SEQ_ERROR_MSG);
-
-            for check in yaml_checks_vec {
-                let check_map = check
-                    .as_mapping()
-                    .unwrap_or_else(|| panic!("The check is not a valid mapping: {:?}", check));
-
-                let c = CheckBuilder::new()
-                    .name(&yaml_or_panic(check_map, "name"))
-                    .command(&yaml_or_panic(check_map, "command"))
-                    .timeout(
-                        match check_map.get(serde_yml::Value::String("timeout".to_string())) {
-                            Some(t) => t.as_u64().expect("The timeout is not a valid u64"),
-                            None => DEFAULT_TIMEOUT,
-                        },
-                    )
-                    .build();
-
-                let range_checks = match c {
-                    Ok(c) => c.expand_ranges(),
-                    Err(e) => {
-                        error!("Unable to build check: {}", e);
-                        std::process::exit(1)
+    let state_dir = Path::new(&parsed_args.state_dir);
+    let _run_lock = match &run_lock_name {
+        Some(name) => {
+            let lock = acquire_run_lock(
+                &state_dir.join(format!("{}.lock", name)),
+                parsed_args.on_locked,
+            )
+            .await;
+            if lock.is_none() {
+                let previous_output = match parsed_args.on_locked {
+                    OnLocked::Cached => {
+                        fs::read_to_string(state_dir.join(format!("{}.csv", name))).ok()
                     }
+                    _ => None,
                 };
+                let output = previous_output.unwrap_or_else(|| {
+                    render_results(&CheckResults(vec![]), &process_options, &parsed_templates)
+                });
+                print!(
+                    "{}",
+                    with_headlines(&output, &[LOCKED_HEADLINE.to_string()])
+                );
+                std::process::exit(0);
+            }
+            lock
+        }
+        None => None,
+    };
+
+    match max_parallel {
+        Some(1) => debug!("Running checks sequentially"),
+        Some(n) => debug!("Running at most {} checks in parallel", n),
+        None => debug!("Running checks in parallel"),
+    }
+    let check_results = run_all_checks_with_cache(checks, &cache, max_parallel)
+        .await
+        .unwrap();
+
+    if let Some(history_path) = &parsed_args.history {
+        record_history(history_path, &check_results);
+    }
+
+    let output = render_results(&check_results, &process_options, &parsed_templates);
+
+    if let Some(name) = &run_lock_name {
+        let output_path = state_dir.join(format!("{}.csv", name));
+        if let Err(e) = write_atomically(&output_path, &output) {
+            error!("Unable to save output to {}: {}", output_path.display(), e);
+        }
+    }
+
+    print!("{}", output);
+    std::process::exit(0);
+}
+
+/// The maximum number of checks to run at the same time, if limited.
+fn max_parallel(args: &Args) -> Option<usize> {
+    match args.sequential {
+        true => Some(1),
+        false => args.max_parallel,
+    }
+}
+
+fn record_history(history_path: &str, check_results: &CheckResults) {
+    let mut history = ExecutionHistory::load(history_path).unwrap_or_else(|e| {
+        error!(
+            "Unable to read history {}, starting a new one: {}",
+            history_path, e
+        );
+        ExecutionHistory::default()
+    });
+    history.record(check_results);
+    if let Err(e) = history.save(history_path) {
+        error!("Unable to save history {}: {}", history_path, e);
+    }
+}
+
+fn print_budget(budget_args: &BudgetArgs, max_parallel: Option<usize>, history_path: Option<&str>) {
+    let parsed_templates = ParsedTemplates::from_template_names(&budget_args.templates);
+    let checks = expand_all_ranges(checks_from_templates(&parsed_templates));
 
-                for rc in range_checks {
-                    checks.push(rc);
+    let history = history_path.map(|path| {
+        ExecutionHistory::load(path).unwrap_or_else(|e| {
+            error!("Unable to read history {}: {}", path, e);
+            std::process::exit(1)
+        })
+    });
+
+    let budget = RunBudget::new(
+        &checks,
+        max_parallel,
+        Duration::from_secs(budget_args.sample_interval),
+        history.as_ref(),
+    );
+
+    if !parsed_templates.missing.is_empty() {
+        println!(
+            "Templates not found: {}",
+            parsed_templates.missing.join(", ")
+        );
+    }
+    print!("{}", budget.as_text());
+}
+
+/// The name of the lock to hold while running, if any.
+fn run_lock_name(args: &Args) -> Option<String> {
+    if let Some(name) = &args.lock_name {
+        return Some(sanitize_lock_name(name));
+    }
+    if !args.lock {
+        return None;
+    }
+    match (&args.templates, &args.name) {
+        (Some(templates), _) => Some(lock_name_for_templates(templates)),
+        (None, Some(name)) => Some(format!("check-{}", sanitize_lock_name(name))),
+        (None, None) => None,
+    }
+}
+
+/// Take the run lock, or return `None` if a previous run holds it and shouldn't be waited for.
+async fn acquire_run_lock(path: &Path, on_locked: OnLocked) -> Option<RunLock> {
+    let lock = match RunLock::try_acquire(path) {
+        Ok(Some(lock)) => Some(lock),
+        Ok(None) if on_locked == OnLocked::Wait => {
+            debug!("Waiting for the previous run to release {}", path.display());
+            let path = path.to_path_buf();
+            match tokio::task::spawn_blocking(move || RunLock::acquire(path)).await {
+                Ok(Ok(lock)) => Some(lock),
+                Ok(Err(e)) => {
+                    error!("Unable to take the lock: {}", e);
+                    std::process::exit(1)
+                }
+                Err(e) => {
+                    error!("Unable to wait for the lock: {}", e);
+                    std::process::exit(1)
                 }
             }
         }
+        Ok(None) => None,
+        Err(e) => {
+            error!("Unable to take the lock {}: {}", path.display(), e);
+            std::process::exit(1)
+        }
+    };
+
+    if lock.is_none() {
+        debug!("A previous run still holds {}", path.display());
     }
+    lock
+}
 
-    let results = if parsed_args.sequential {
-        debug!("Running checks sequentially");
-        match run_all_checks_sequentially(checks)
-            .unwrap()
-            .process()
-            .as_csv_string()
-        {
-            Ok(s) => s,
-            Err(e) => panic!("Unable to generate CSV string with error: {}", e),
+async fn run_daemon(daemon_args: &DaemonArgs, process_options: ProcessOptions) {
+    let parsed_templates = ParsedTemplates::from_template_names(&daemon_args.templates);
+    let checks = expand_all_ranges(checks_from_templates(&parsed_templates));
+    let process_options = ProcessOptions {
+        original_status: checks.iter().any(|c| c.status_map().is_some()),
+        ..process_options
+    };
+
+    let daemon = Daemon::new(checks, &daemon_args.output, move |check_results| {
+        render_results(check_results, &process_options, &parsed_templates)
+    })
+    .default_interval(daemon_args.default_interval)
+    .jitter(daemon_args.jitter);
+
+    if let Err(e) = daemon.run().await {
+        error!("Daemon failed: {}", e);
+        std::process::exit(1)
+    }
+}
+
+/// Register an executor for every agent given with --agent, so that checks can be dispatched to
+/// them.
+fn register_agents(
+    agents: &[AgentAddress],
+    cert: Option<&str>,
+    key: Option<&str>,
+    ca: Option<&str>,
+) {
+    let (Some(cert), Some(key), Some(ca)) = (cert, key, ca) else {
+        return;
+    };
+    let connector = controller_connector(Path::new(cert), Path::new(key), Path::new(ca))
+        .unwrap_or_else(|e| {
+            error!("Failed to load the agent certificate {}: {}", cert, e);
+            std::process::exit(1)
+        });
+
+    for address in agents {
+        debug!(
+            "Dispatching checks for agent {} to {}",
+            address.name, address
+        );
+        register_executor(
+            &agent_executor_name(&address.name),
+            AgentExecutor::new(address.clone(), connector.clone()),
+        );
+    }
+}
+
+async fn run_agent(args: &AgentArgs) {
+    let listen = args.listen.parse().unwrap_or_else(|e| {
+        error!("Invalid listen address {}: {}", args.listen, e);
+        std::process::exit(1)
+    });
+    let acceptor = agent_acceptor(
+        Path::new(&args.tls_cert),
+        Path::new(&args.tls_key),
+        Path::new(&args.tls_ca),
+    )
+    .unwrap_or_else(|e| {
+        error!(
+            "Failed to load the TLS certificate {}: {}",
+            args.tls_cert, e
+        );
+        std::process::exit(1)
+    });
+
+    if let Err(e) = AgentServer::new(acceptor).run(listen).await {
+        error!("Agent failed: {}", e);
+        std::process::exit(1)
+    }
+}
+
+async fn run_nrpe_server(args: &NrpeServerArgs) {
+    let listen = args.listen.parse().unwrap_or_else(|e| {
+        error!("Invalid listen address {}: {}", args.listen, e);
+        std::process::exit(1)
+    });
+    let allowed_hosts = AllowList::from_str(&args.allowed_hosts).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1)
+    });
+
+    let parsed_templates = ParsedTemplates::from_template_names(&args.templates);
+    let checks = expand_all_ranges(checks_from_templates(&parsed_templates));
+    let mut server = NrpeServer::new(checks).allowed_hosts(allowed_hosts);
+
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        match tls_acceptor(
+            Path::new(cert),
+            Path::new(key),
+            args.tls_ca.as_deref().map(Path::new),
+        ) {
+            Ok(acceptor) => server = server.tls(acceptor),
+            Err(e) => {
+                error!("Failed to load the TLS certificate {}: {}", cert, e);
+                std::process::exit(1)
+            }
         }
-    } else {
-        debug!("Running checks in parallel");
-        match run_all_checks_in_parallel(checks)
-            .await
-            .unwrap()
-            .process()
-            .as_csv_string()
-        {
-            Ok(s) => s,
-            Err(e) => panic!("Unable to generate CSV string with error: {}", e),
+    }
+
+    if let Err(e) = server.run(listen).await {
+        error!("NRPE server failed: {}", e);
+        std::process::exit(1)
+    }
+}
+
+/// Build the checks in the templates. Their ranges are not expanded yet.
+fn checks_from_templates(parsed_templates: &ParsedTemplates) -> Checks {
+    let mut checks = Checks::new();
+
+    for (template_name, template) in parsed_templates.found.iter().zip(&parsed_templates.strings) {
+        let template_name = Path::new(template_name)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| template_name.clone());
+        let template_yaml: Value =
+            serde_yml::from_str(template).expect("Failed to parse yaml template from string");
+
+        let yaml_checks_vec = template_yaml
+            .as_sequence()
+            .expect(INVALID_YAML_SEQ_ERROR_MSG);
+
+        for check in yaml_checks_vec {
+            let check_map = check
+                .as_mapping()
+                .unwrap_or_else(|| panic!("The check is not a valid mapping: {:?}", check));
+
+            let mut check_builder = CheckBuilder::new()
+                .name(&yaml_or_panic(check_map, "name"))
+                .command(&yaml_or_panic(check_map, "command"))
+                .template(&template_name)
+                .timeout(
+                    match check_map.get(serde_yml::Value::String("timeout".to_string())) {
+                        Some(t) => t.as_u64().expect("The timeout is not a valid u64"),
+                        None => DEFAULT_TIMEOUT,
+                    },
+                );
+
+            if let Some(interval) = yaml_to_optional_interval(check_map) {
+                check_builder = check_builder.interval(interval);
+            }
+
+            if let Some(shell) = yaml_to_optional_shell(check_map) {
+                check_builder = check_builder.shell(&shell);
+            }
+
+            if let Some(limits) = yaml_to_optional_limits(check_map) {
+                check_builder = check_builder.limits(limits);
+            }
+
+            if let Some(target) = yaml_to_optional_ssh(check_map) {
+                check_builder = check_builder.ssh(target);
+            }
+
+            if let Some(agent) = yaml_to_optional_string(check_map, "agent") {
+                check_builder = check_builder.executor(&agent_executor_name(&agent));
+            }
+
+            if let Some(thresholds) = yaml_to_optional_thresholds(check_map) {
+                check_builder = check_builder.thresholds(thresholds);
+            }
+
+            if let Some(worst_status) = check_map
+                .get(serde_yml::Value::String("worst_status".to_string()))
+                .map(|v| {
+                    v.as_bool()
+                        .expect("The worst_status is not a valid boolean")
+                })
+            {
+                check_builder = check_builder.worst_status(worst_status);
+            }
+
+            if let Some(status_map) = yaml_to_optional_status_map(check_map) {
+                check_builder = check_builder.status_map(status_map);
+            }
+
+            if let Some(rules) = yaml_to_optional_extract(check_map) {
+                check_builder = check_builder.extract(rules);
+            }
+
+            if let Some(args) = yaml_to_optional_args(check_map) {
+                check_builder = check_builder.args(&args);
+            }
+
+            checks.push(build_or_exit(check_builder));
         }
-    };
+    }
 
-    let results_with_headline =
-        with_templates_in_headline(&results, &parsed_templates.found, &parsed_templates.missing);
+    checks
+}
 
-    print!("{}", results_with_headline);
-    std::process::exit(0);
+fn build_or_exit(check_builder: CheckBuilder) -> Check {
+    match check_builder.build() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Unable to build check: {}", e);
+            std::process::exit(1)
+        }
+    }
 }
 
-fn with_templates_in_headline(
-    results: &str,
-    found_templates: &[String],
-    missing_templates: &[String],
+fn expand_all_ranges(checks: Checks) -> Checks {
+    checks.into_iter().flat_map(Check::expand_ranges).collect()
+}
+
+/// The results as CSV for the Toolkit Plugin, with headlines.
+fn render_results(
+    check_results: &CheckResults,
+    process_options: &ProcessOptions,
+    parsed_templates: &ParsedTemplates,
 ) -> String {
-    let found_msg = format!("<!>templatesFound,{}", found_templates.join(", "));
-    let missing_msg = format!("<!>templatesNotFound,{}", missing_templates.join(", "));
+    let results = match check_results.process_with(process_options).as_csv_string() {
+        Ok(s) => s,
+        Err(e) => panic!("Unable to generate CSV string with error: {}", e),
+    };
+
+    let mut headlines = templates_headlines(&parsed_templates.found, &parsed_templates.missing);
+
+    if process_options.resource_usage {
+        headlines.extend(resource_usage_headlines(
+            &check_results.total_resource_usage(),
+        ));
+    }
+
+    with_headlines(&results, &headlines)
+}
+
+fn templates_headlines(found_templates: &[String], missing_templates: &[String]) -> Vec<String> {
+    vec![
+        format!("<!>templatesFound,{}", found_templates.join(", ")),
+        format!("<!>templatesNotFound,{}", missing_templates.join(", ")),
+    ]
+}
+
+fn resource_usage_headlines(usage: &ResourceUsage) -> Vec<String> {
+    vec![
+        format!("<!>totalUserCpuTime,{}", format_cpu_time(usage.user_cpu)),
+        format!(
+            "<!>totalSystemCpuTime,{}",
+            format_cpu_time(usage.system_cpu)
+        ),
+        format!("<!>largestMaxRss,{}", format_max_rss(usage.max_rss)),
+    ]
+}
 
+// Insert the headlines, in order, right after the CSV header.
+fn with_headlines(results: &str, headlines: &[String]) -> String {
     let mut results_vec: Vec<&str> = results.split('\n').collect();
 
-    results_vec.insert(1, &missing_msg);
-    results_vec.insert(1, &found_msg);
+    for (i, headline) in headlines.iter().enumerate() {
+        results_vec.insert(1 + i, headline);
+    }
 
     results_vec.join("\n")
 }
@@ -381,6 +1190,41 @@ fn find_and_read_template(template: &str) -> std::io::Result<String> {
     }
 }
 
+/// Load the Nagios resource file given with --resource-file, or the default one if it exists.
+fn load_resource_file(path: Option<&str>) {
+    let (path, contents) = match path {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(contents) => (path, contents),
+            Err(e) => {
+                error!("Failed to read resource file {}: {}", path, e);
+                std::process::exit(1)
+            }
+        },
+        None => match fs::read_to_string(RESOURCE_FILE) {
+            Ok(contents) => (RESOURCE_FILE, contents),
+            Err(_) => {
+                debug!("No resource file found, $USERn$ macros are read from the environment.");
+                return;
+            }
+        },
+    };
+
+    match NagiosResources::from_str(&contents) {
+        Ok(resources) => {
+            debug!(
+                "Loaded {} macros from the resource file {}",
+                resources.len(),
+                path
+            );
+            *RESOURCES.write().unwrap() = Some(resources);
+        }
+        Err(e) => {
+            error!("Failed to parse resource file {}: {}", path, e);
+            std::process::exit(1)
+        }
+    }
+}
+
 fn yaml_to_optional_string(map: &serde_yml::Mapping, key: &str) -> Option<String> {
     map.get(serde_yml::Value::String(key.to_string()))
         .and_then(|v| v.as_str())
@@ -388,6 +1232,252 @@ fn yaml_to_optional_string(map: &serde_yml::Mapping, key: &str) -> Option<String
         .map(|s| s.to_string())
 }
 
+// `shell: true` selects the default shell, `shell: <path>` selects a specific interpreter.
+fn yaml_to_optional_shell(map: &serde_yml::Mapping) -> Option<String> {
+    match map.get(serde_yml::Value::String("shell".to_string())) {
+        Some(serde_yml::Value::Bool(true)) => Some(DEFAULT_SHELL.to_string()),
+        Some(serde_yml::Value::Bool(false)) | None => None,
+        Some(v) => Some(
+            v.as_str()
+                .map(|s| s.trim().to_string())
+                .unwrap_or_else(|| panic!("The shell is not a valid boolean or path: {:?}", v)),
+        ),
+    }
+}
+
+fn yaml_to_optional_args(map: &serde_yml::Mapping) -> Option<Vec<String>> {
+    map.get(serde_yml::Value::String("args".to_string()))
+        .map(|v| {
+            v.as_sequence()
+                .unwrap_or_else(|| panic!("The args are not a valid sequence: {:?}", v))
+        })
+        .map(|seq| {
+            seq.iter()
+                .map(|arg| match arg {
+                    serde_yml::Value::String(s) => s.to_string(),
+                    serde_yml::Value::Number(n) => n.to_string(),
+                    serde_yml::Value::Bool(b) => b.to_string(),
+                    _ => panic!("The arg is not a valid scalar: {:?}", arg),
+                })
+                .collect()
+        })
+}
+
+fn yaml_to_optional_ssh(map: &serde_yml::Mapping) -> Option<SshTarget> {
+    match map.get(serde_yml::Value::String("ssh".to_string()))? {
+        serde_yml::Value::String(host) => Some(SshTarget::new(host.trim())),
+        v => Some(
+            serde_yml::from_value(v.clone())
+                .unwrap_or_else(|e| panic!("The ssh target is not valid: {}: {:?}", e, v)),
+        ),
+    }
+}
+
+fn yaml_to_optional_interval(map: &serde_yml::Mapping) -> Option<u64> {
+    map.get(serde_yml::Value::String("interval".to_string()))
+        .map(|i| i.as_u64().expect("The interval is not a valid u64"))
+}
+
+fn yaml_to_optional_limits(map: &serde_yml::Mapping) -> Option<ResourceLimits> {
+    let limits_map = map
+        .get(serde_yml::Value::String("limits".to_string()))?
+        .as_mapping()
+        .unwrap_or_else(|| panic!("The limits are not a valid mapping: {:?}", map));
+
+    let mut limits = ResourceLimits::default();
+
+    for (key, value) in limits_map {
+        let key = key.as_str().unwrap_or_default();
+        let number = || {
+            value
+                .as_u64()
+                .unwrap_or_else(|| panic!("The limit {} is not a valid u64: {:?}", key, value))
+        };
+
+        match key {
+            "address_space" => {
+                limits.address_space = Some(match value.as_str() {
+                    Some(s) => parse_size(s).unwrap_or_else(|e| panic!("{}", e)),
+                    None => number(),
+                })
+            }
+            "cpu_time" => limits.cpu_time = Some(number()),
+            "open_files" => limits.open_files = Some(number()),
+            "processes" => limits.processes = Some(number()),
+            _ => panic!("Unknown limit: {:?}", key),
+        }
+    }
+
+    Some(limits)
+}
+
+fn yaml_to_optional_thresholds(map: &serde_yml::Mapping) -> Option<Thresholds> {
+    let thresholds_map = map
+        .get(serde_yml::Value::String("thresholds".to_string()))?
+        .as_mapping()
+        .unwrap_or_else(|| panic!("The thresholds are not a valid mapping: {:?}", map));
+
+    let mut thresholds = Thresholds::new();
+
+    for (label, ranges) in thresholds_map {
+        let label = match label {
+            serde_yml::Value::String(s) => s.to_string(),
+            serde_yml::Value::Number(n) => n.to_string(),
+            _ => panic!("The threshold label is not valid: {:?}", label),
+        };
+        let ranges = ranges
+            .as_mapping()
+            .unwrap_or_else(|| panic!("The thresholds of {} are not a valid mapping", label));
+
+        let mut metric = MetricThresholds::default();
+        for (key, value) in ranges {
+            let key = key.as_str().unwrap_or_default();
+            let range = match value {
+                serde_yml::Value::String(s) => s.trim().to_string(),
+                serde_yml::Value::Number(n) => n.to_string(),
+                _ => panic!("The {} range of {} is not valid: {:?}", key, label, value),
+            };
+            if let Err(e) = Threshold::from_str(&range) {
+                panic!("The {} range of {} is not valid: {}", key, label, e);
+            }
+
+            match key {
+                "warn" => metric.warn = Some(range),
+                "crit" => metric.crit = Some(range),
+                _ => panic!("Unknown threshold of {}: {:?}", label, key),
+            }
+        }
+        thresholds.insert(label, metric);
+    }
+
+    Some(thresholds)
+}
+
+fn yaml_to_optional_status_map(map: &serde_yml::Mapping) -> Option<StatusMap> {
+    let rules = map
+        .get(serde_yml::Value::String("status_map".to_string()))?
+        .as_sequence()
+        .unwrap_or_else(|| panic!("The status map is not a valid sequence: {:?}", map));
+
+    let status = |value: &serde_yml::Value| {
+        let s = match value {
+            serde_yml::Value::String(s) => s.to_string(),
+            serde_yml::Value::Number(n) => n.to_string(),
+            _ => panic!("The status is not valid: {:?}", value),
+        };
+        parse_status(&s).unwrap_or_else(|e| panic!("{}", e))
+    };
+    // Plugins may exit with any code, such as 126 or 127 when they can't be run, so any
+    // integer is a valid exit status, as well as the status names.
+    let exit = |value: &serde_yml::Value| match value {
+        serde_yml::Value::Number(n) => n
+            .as_i64()
+            .and_then(|n| i32::try_from(n).ok())
+            .unwrap_or_else(|| panic!("The exit status is not a valid integer: {:?}", value)),
+        serde_yml::Value::String(s) => s.trim().parse().unwrap_or_else(|_| status(value)),
+        _ => panic!("The exit status is not valid: {:?}", value),
+    };
+
+    let status_map = rules
+        .iter()
+        .map(|rule| {
+            let rule_map = rule
+                .as_mapping()
+                .unwrap_or_else(|| panic!("The status rule is not a valid mapping: {:?}", rule));
+            let mut status_rule = StatusRule {
+                exit: None,
+                output: None,
+                status: 0,
+            };
+            let mut has_status = false;
+
+            for (key, value) in rule_map {
+                match key.as_str().unwrap_or_default() {
+                    "exit" => status_rule.exit = Some(exit(value)),
+                    "output" => {
+                        let pattern = value.as_str().unwrap_or_else(|| {
+                            panic!("The output pattern is not a valid string: {:?}", value)
+                        });
+                        status_rule.output =
+                            Some(OutputPattern::new(pattern).unwrap_or_else(|e| {
+                                panic!("The output pattern is not valid: {}", e)
+                            }));
+                    }
+                    "status" => {
+                        status_rule.status = status(value);
+                        has_status = true;
+                    }
+                    key => panic!("Unknown key in status rule: {:?}", key),
+                }
+            }
+
+            if !has_status {
+                panic!("The status rule has no status: {:?}", rule);
+            }
+            if status_rule.exit.is_none() && status_rule.output.is_none() {
+                panic!("The status rule has neither exit nor output: {:?}", rule);
+            }
+            status_rule
+        })
+        .collect();
+
+    Some(status_map)
+}
+
+fn yaml_to_optional_extract(map: &serde_yml::Mapping) -> Option<ExtractRules> {
+    let rules = map
+        .get(serde_yml::Value::String("extract".to_string()))?
+        .as_sequence()
+        .unwrap_or_else(|| panic!("The extract rules are not a valid sequence: {:?}", map));
+
+    let rules = rules
+        .iter()
+        .map(|rule| {
+            let rule_map = rule
+                .as_mapping()
+                .unwrap_or_else(|| panic!("The extract rule is not a valid mapping: {:?}", rule));
+            let pattern = yaml_to_optional_string(rule_map, "regex")
+                .unwrap_or_else(|| panic!("The extract rule has no regex: {:?}", rule));
+            let mut extract_rule = ExtractRule::new(
+                OutputPattern::new(&pattern)
+                    .unwrap_or_else(|e| panic!("The extract regex is not valid: {}", e)),
+            );
+
+            for (key, value) in rule_map {
+                let key = key.as_str().unwrap_or_default();
+                let text = || match value {
+                    serde_yml::Value::String(s) => s.trim().to_string(),
+                    serde_yml::Value::Number(n) => n.to_string(),
+                    _ => panic!("The {} of the extract rule is not valid: {:?}", key, value),
+                };
+                let range = || {
+                    let range = text();
+                    if let Err(e) = Threshold::from_str(&range) {
+                        panic!("The {} range of the extract rule is not valid: {}", key, e);
+                    }
+                    range
+                };
+
+                extract_rule = match key {
+                    "regex" => extract_rule,
+                    "label" => extract_rule.label(&text()),
+                    "uom" => extract_rule.uom(&text()),
+                    "warn" => extract_rule.warn(&range()),
+                    "crit" => extract_rule.crit(&range()),
+                    _ => panic!("Unknown key in extract rule: {:?}", key),
+                };
+            }
+
+            if let Err(e) = extract_rule.validate() {
+                panic!("{}", e);
+            }
+            extract_rule
+        })
+        .collect();
+
+    Some(rules)
+}
+
 fn yaml_or_panic(map: &serde_yml::Mapping, key: &str) -> String {
     yaml_to_optional_string(map, key)
         .unwrap_or_else(|| panic!("Unable to parse {} in check: {:?}", key, map))
@@ -444,6 +1534,242 @@ mod tests {
         assert_eq!(yaml_or_panic(map, "baz"), "qux".to_string());
     }
 
+    #[test]
+    fn test_yaml_to_optional_shell() {
+        let yaml = serde_yml::from_str::<serde_yml::Value>(
+            r#"
+        - shell: true
+        - shell: false
+        - shell: /bin/bash
+        - name: no shell
+    "#,
+        )
+        .unwrap();
+
+        let maps = yaml
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_mapping().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(yaml_to_optional_shell(maps[0]), Some("/bin/sh".to_string()));
+        assert_eq!(yaml_to_optional_shell(maps[1]), None);
+        assert_eq!(
+            yaml_to_optional_shell(maps[2]),
+            Some("/bin/bash".to_string())
+        );
+        assert_eq!(yaml_to_optional_shell(maps[3]), None);
+    }
+
+    #[test]
+    fn test_yaml_to_optional_args() {
+        let yaml = serde_yml::from_str::<serde_yml::Value>(
+            r#"
+        - args: ["-H", "my host", 5, true]
+        - name: no args
+    "#,
+        )
+        .unwrap();
+
+        let seq = yaml.as_sequence().unwrap();
+
+        assert_eq!(
+            yaml_to_optional_args(seq[0].as_mapping().unwrap()),
+            Some(vec![
+                "-H".to_string(),
+                "my host".to_string(),
+                "5".to_string(),
+                "true".to_string()
+            ])
+        );
+        assert_eq!(yaml_to_optional_args(seq[1].as_mapping().unwrap()), None);
+    }
+
+    #[test]
+    fn test_yaml_to_optional_limits() {
+        let yaml = serde_yml::from_str::<serde_yml::Value>(
+            r#"
+        - limits:
+            address_space: 64M
+            cpu_time: 10
+        - limits:
+            address_space: 1024
+            open_files: 32
+            processes: 4
+        - name: no limits
+    "#,
+        )
+        .unwrap();
+
+        let seq = yaml.as_sequence().unwrap();
+
+        assert_eq!(
+            yaml_to_optional_limits(seq[0].as_mapping().unwrap()),
+            Some(ResourceLimits {
+                address_space: Some(64 * 1024 * 1024),
+                cpu_time: Some(10),
+                ..ResourceLimits::default()
+            })
+        );
+        assert_eq!(
+            yaml_to_optional_limits(seq[1].as_mapping().unwrap()),
+            Some(ResourceLimits {
+                address_space: Some(1024),
+                cpu_time: None,
+                open_files: Some(32),
+                processes: Some(4),
+            })
+        );
+        assert_eq!(yaml_to_optional_limits(seq[2].as_mapping().unwrap()), None);
+    }
+
+    #[test]
+    fn test_yaml_to_optional_thresholds() {
+        let yaml = serde_yml::from_str::<serde_yml::Value>(
+            r#"
+        - thresholds:
+            load1: { warn: "5", crit: 10 }
+            'Disk /': { crit: "@~:1.5" }
+        - name: no thresholds
+    "#,
+        )
+        .unwrap();
+
+        let seq = yaml.as_sequence().unwrap();
+
+        assert_eq!(
+            yaml_to_optional_thresholds(seq[0].as_mapping().unwrap()),
+            Some(Thresholds::from([
+                (
+                    "load1".to_string(),
+                    MetricThresholds {
+                        warn: Some("5".to_string()),
+                        crit: Some("10".to_string()),
+                    }
+                ),
+                (
+                    "Disk /".to_string(),
+                    MetricThresholds {
+                        warn: None,
+                        crit: Some("@~:1.5".to_string()),
+                    }
+                ),
+            ]))
+        );
+        assert_eq!(
+            yaml_to_optional_thresholds(seq[1].as_mapping().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_yaml_to_optional_thresholds_invalid_range() {
+        let yaml =
+            serde_yml::from_str::<serde_yml::Value>("thresholds: { load1: { warn: '10:5' } }")
+                .unwrap();
+
+        yaml_to_optional_thresholds(yaml.as_mapping().unwrap());
+    }
+
+    #[test]
+    fn test_yaml_to_optional_status_map() {
+        let yaml = serde_yml::from_str::<serde_yml::Value>(
+            r#"
+        - status_map:
+            - exit: 1
+              status: CRITICAL
+            - exit: UNKNOWN
+              output: ^Timed out
+              status: warning
+            - output: maintenance
+              status: 0
+        - name: no status map
+    "#,
+        )
+        .unwrap();
+
+        let seq = yaml.as_sequence().unwrap();
+        let status_map = yaml_to_optional_status_map(seq[0].as_mapping().unwrap()).unwrap();
+
+        assert_eq!(
+            status_map
+                .iter()
+                .map(|r| (r.exit, r.output.as_ref().map(|o| o.as_str()), r.status))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(1), None, 2),
+                (Some(3), Some("^Timed out"), 1),
+                (None, Some("maintenance"), 0),
+            ]
+        );
+        assert_eq!(
+            yaml_to_optional_status_map(seq[1].as_mapping().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_yaml_to_optional_status_map_without_condition() {
+        let yaml =
+            serde_yml::from_str::<serde_yml::Value>("status_map: [ { status: OK } ]").unwrap();
+
+        yaml_to_optional_status_map(yaml.as_mapping().unwrap());
+    }
+
+    #[test]
+    fn test_yaml_to_optional_extract() {
+        let yaml = serde_yml::from_str::<serde_yml::Value>(
+            r#"
+        - extract:
+            - regex: Queue depth is (?P<value>\d+)
+              label: queue_depth
+              warn: 100
+              crit: "500"
+            - regex: (?P<label>\w+) took (?P<value>\S+)
+              uom: s
+        - name: no extract
+    "#,
+        )
+        .unwrap();
+
+        let seq = yaml.as_sequence().unwrap();
+        let pattern = |p: &str| OutputPattern::new(p).unwrap();
+
+        assert_eq!(
+            yaml_to_optional_extract(seq[0].as_mapping().unwrap()),
+            Some(vec![
+                ExtractRule::new(pattern(r"Queue depth is (?P<value>\d+)"))
+                    .label("queue_depth")
+                    .warn("100")
+                    .crit("500"),
+                ExtractRule::new(pattern(r"(?P<label>\w+) took (?P<value>\S+)")).uom("s"),
+            ])
+        );
+        assert_eq!(yaml_to_optional_extract(seq[1].as_mapping().unwrap()), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_yaml_to_optional_extract_without_value_group() {
+        let yaml = serde_yml::from_str::<serde_yml::Value>(
+            r"extract: [ { regex: 'depth (\d+)', label: depth } ]",
+        )
+        .unwrap();
+
+        yaml_to_optional_extract(yaml.as_mapping().unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_yaml_to_optional_limits_unknown_limit() {
+        let yaml = serde_yml::from_str::<serde_yml::Value>("limits: { stack: 10 }").unwrap();
+
+        yaml_to_optional_limits(yaml.as_mapping().unwrap());
+    }
+
     #[test]
     #[should_panic]
     fn test_yaml_or_panic_missing_key() {
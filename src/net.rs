@@ -0,0 +1,43 @@
+//! Asynchronous TCP and TLS connections for the clients and servers of xtender. Nothing blocks a
+//! thread, so a timeout around any of them cancels the connection and closes the socket.
+
+use openssl::ssl::{ConnectConfiguration, Ssl, SslAcceptor};
+use std::io;
+use std::pin::Pin;
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+/// Connect to the first address of the host that accepts the connection.
+pub async fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    TcpStream::connect((host, port)).await
+}
+
+/// Perform the TLS handshake with a server, with SNI for the host. The certificate chain and the
+/// host name are verified as set up in the configuration.
+pub async fn tls_connect(
+    config: ConnectConfiguration,
+    host: &str,
+    stream: TcpStream,
+) -> io::Result<SslStream<TcpStream>> {
+    let ssl = config.into_ssl(host).map_err(io::Error::other)?;
+    let mut stream = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+    Pin::new(&mut stream)
+        .connect()
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(stream)
+}
+
+/// Perform the TLS handshake with a client that connected to a listener.
+pub async fn tls_accept(
+    acceptor: &SslAcceptor,
+    stream: TcpStream,
+) -> io::Result<SslStream<TcpStream>> {
+    let ssl = Ssl::new(acceptor.context()).map_err(io::Error::other)?;
+    let mut stream = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+    Pin::new(&mut stream)
+        .accept()
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(stream)
+}
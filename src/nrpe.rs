@@ -0,0 +1,357 @@
+//! The NRPE protocol, versions 2 and 3, as spoken by `check_nrpe` and the `nrpe` daemon. A query
+//! names a remote command and its arguments, separated by `!`, and the response carries the
+//! status and output of the command.
+
+pub mod server;
+
+use crate::net::tls_connect;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const DEFAULT_PORT: u16 = 5666;
+/// The ciphers `check_nrpe` uses by default. The `nrpe` daemon uses anonymous Diffie-Hellman
+/// unless it's configured with a certificate, which needs security level 0.
+pub const DEFAULT_CIPHERS: &str = "ALL:!MD5:@STRENGTH:@SECLEVEL=0";
+
+pub const QUERY_PACKET: u16 = 1;
+pub const RESPONSE_PACKET: u16 = 2;
+/// A response packet followed by another packet with the rest of the output.
+pub const RESPONSE_PACKET_WITH_MORE: u16 = 3;
+
+/// The size of the buffer in a version 2 packet, including the terminating NUL.
+const V2_BUFFER_SIZE: usize = 1024;
+/// The size of a version 2 packet, including 2 bytes of struct padding.
+const V2_PACKET_SIZE: usize = 10 + V2_BUFFER_SIZE + 2;
+/// The size of the header of a version 3 packet, up to the buffer.
+const V3_HEADER_SIZE: usize = 16;
+/// Version 3 packets end with the trailing padding of the C struct.
+const V3_TRAILING_BYTES: usize = 3;
+/// The largest buffer accepted in a version 3 packet.
+const MAX_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum NrpeError {
+    Io(io::Error),
+    Tls(String),
+    UnsupportedVersion(u16),
+    InvalidCrc,
+    BufferTooLarge(usize),
+    UnexpectedPacketType(u16),
+}
+
+impl std::error::Error for NrpeError {}
+
+impl fmt::Display for NrpeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NrpeError::Io(e) => write!(f, "{}", e),
+            NrpeError::Tls(e) => write!(f, "TLS error: {}", e),
+            NrpeError::UnsupportedVersion(v) => write!(f, "Unsupported packet version {}", v),
+            NrpeError::InvalidCrc => write!(f, "Packet had invalid CRC32"),
+            NrpeError::BufferTooLarge(size) => write!(f, "Packet buffer too large: {}", size),
+            NrpeError::UnexpectedPacketType(t) => write!(f, "Unexpected packet type {}", t),
+        }
+    }
+}
+
+impl From<io::Error> for NrpeError {
+    fn from(e: io::Error) -> Self {
+        NrpeError::Io(e)
+    }
+}
+
+/// A single NRPE packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub version: u16,
+    pub packet_type: u16,
+    pub result_code: i16,
+    /// The buffer up to the terminating NUL.
+    pub buffer: Vec<u8>,
+}
+
+impl Packet {
+    pub fn query(version: u16, query: &str) -> Self {
+        Self {
+            version,
+            packet_type: QUERY_PACKET,
+            result_code: 0,
+            buffer: query.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn response(version: u16, result_code: i16, output: &str) -> Self {
+        Self {
+            version,
+            packet_type: RESPONSE_PACKET,
+            result_code,
+            buffer: output.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn buffer_string(&self) -> String {
+        String::from_utf8_lossy(&self.buffer).to_string()
+    }
+
+    /// The packet as sent on the wire. A version 2 buffer is truncated to 1023 bytes. A version
+    /// 3 packet is at least as large as a version 2 packet, so that a daemon that only speaks
+    /// version 2 reads all of it and rejects it, instead of waiting for more.
+    pub fn encode(&self) -> Result<Vec<u8>, NrpeError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.packet_type.to_be_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&self.result_code.to_be_bytes());
+
+        match self.version {
+            2 => {
+                let length = self.buffer.len().min(V2_BUFFER_SIZE - 1);
+                bytes.extend_from_slice(&self.buffer[..length]);
+                bytes.resize(V2_PACKET_SIZE, 0);
+            }
+            3 | 4 => {
+                let trailing = trailing_bytes(self.version);
+                let size = (V3_HEADER_SIZE + self.buffer.len() + 1 + trailing).max(V2_PACKET_SIZE);
+                let buffer_length = size - V3_HEADER_SIZE - trailing;
+                if buffer_length > MAX_BUFFER_SIZE {
+                    return Err(NrpeError::BufferTooLarge(buffer_length));
+                }
+                bytes.extend_from_slice(&[0; 2]);
+                bytes.extend_from_slice(&(buffer_length as u32).to_be_bytes());
+                bytes.extend_from_slice(&self.buffer);
+                bytes.resize(size, 0);
+            }
+            v => return Err(NrpeError::UnsupportedVersion(v)),
+        }
+
+        let crc = crc32(&bytes);
+        bytes[4..8].copy_from_slice(&crc.to_be_bytes());
+        Ok(bytes)
+    }
+
+    /// Read a single packet of any supported version, and check its CRC.
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, NrpeError> {
+        let mut bytes = vec![0; 10];
+        reader.read_exact(&mut bytes).await?;
+        let version = u16::from_be_bytes([bytes[0], bytes[1]]);
+
+        let buffer_range = match version {
+            2 => {
+                bytes.resize(V2_PACKET_SIZE, 0);
+                reader.read_exact(&mut bytes[10..]).await?;
+                10..10 + V2_BUFFER_SIZE
+            }
+            3 | 4 => {
+                bytes.resize(V3_HEADER_SIZE, 0);
+                reader.read_exact(&mut bytes[10..]).await?;
+                let buffer_length =
+                    u32::from_be_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]) as usize;
+                if buffer_length > MAX_BUFFER_SIZE {
+                    return Err(NrpeError::BufferTooLarge(buffer_length));
+                }
+                let size = V3_HEADER_SIZE + buffer_length + trailing_bytes(version);
+                bytes.resize(size, 0);
+                reader.read_exact(&mut bytes[V3_HEADER_SIZE..]).await?;
+                V3_HEADER_SIZE..V3_HEADER_SIZE + buffer_length
+            }
+            v => return Err(NrpeError::UnsupportedVersion(v)),
+        };
+
+        let crc = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        bytes[4..8].copy_from_slice(&[0; 4]);
+        if crc32(&bytes) != crc {
+            return Err(NrpeError::InvalidCrc);
+        }
+
+        let buffer = &bytes[buffer_range];
+        let end = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+
+        Ok(Self {
+            version,
+            packet_type: u16::from_be_bytes([bytes[2], bytes[3]]),
+            result_code: i16::from_be_bytes([bytes[8], bytes[9]]),
+            buffer: buffer[..end].to_vec(),
+        })
+    }
+}
+
+fn trailing_bytes(version: u16) -> usize {
+    match version {
+        3 => V3_TRAILING_BYTES,
+        _ => 0,
+    }
+}
+
+/// The CRC-32 (IEEE) used by NRPE.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// The query for a remote command and its arguments, in the form `command!arg1!arg2`.
+pub fn query_string(command: &str, args: &[String]) -> String {
+    std::iter::once(command.to_string())
+        .chain(args.iter().cloned())
+        .collect::<Vec<_>>()
+        .join("!")
+}
+
+/// How to secure the connection to an NRPE daemon. Without a CA certificate, the daemon's
+/// certificate isn't verified, like `check_nrpe`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    pub ciphers: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    pub fn connector(&self) -> Result<SslConnector, NrpeError> {
+        let tls_error = |e: openssl::error::ErrorStack| NrpeError::Tls(e.to_string());
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(tls_error)?;
+
+        builder
+            .set_cipher_list(self.ciphers.as_deref().unwrap_or(DEFAULT_CIPHERS))
+            .map_err(tls_error)?;
+        match &self.ca_cert {
+            Some(ca_cert) => builder.set_ca_file(ca_cert).map_err(tls_error)?,
+            None => builder.set_verify(SslVerifyMode::NONE),
+        }
+        if let Some(client_cert) = &self.client_cert {
+            builder
+                .set_certificate_chain_file(client_cert)
+                .map_err(tls_error)?;
+        }
+        if let Some(client_key) = &self.client_key {
+            builder
+                .set_private_key_file(client_key, SslFiletype::PEM)
+                .map_err(tls_error)?;
+        }
+
+        Ok(builder.build())
+    }
+}
+
+/// Send a query over a connected stream, and read the response. Output split over several
+/// packets is joined.
+pub async fn query(
+    mut stream: TcpStream,
+    host: &str,
+    tls: Option<&TlsOptions>,
+    version: u16,
+    query: &str,
+) -> Result<Packet, NrpeError> {
+    match tls {
+        Some(tls) => {
+            let mut config = tls
+                .connector()?
+                .configure()
+                .map_err(|e| NrpeError::Tls(e.to_string()))?;
+            config.set_verify_hostname(tls.ca_cert.is_some());
+            let mut stream = tls_connect(config, host, stream)
+                .await
+                .map_err(|e| NrpeError::Tls(e.to_string()))?;
+            exchange(&mut stream, version, query).await
+        }
+        None => exchange(&mut stream, version, query).await,
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    version: u16,
+    query: &str,
+) -> Result<Packet, NrpeError> {
+    stream
+        .write_all(&Packet::query(version, query).encode()?)
+        .await?;
+    stream.flush().await?;
+
+    let mut response = Packet::read(stream).await?;
+    let mut packet_type = response.packet_type;
+    while packet_type == RESPONSE_PACKET_WITH_MORE {
+        let more = Packet::read(stream).await?;
+        response.buffer.extend_from_slice(&more.buffer);
+        response.result_code = more.result_code;
+        packet_type = more.packet_type;
+    }
+    if packet_type != RESPONSE_PACKET {
+        return Err(NrpeError::UnexpectedPacketType(packet_type));
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod nrpe_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[tokio::test]
+    async fn test_v2_packet_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let packet = Packet::query(2, "check_load!5!10");
+        let bytes = packet.encode()?;
+
+        assert_eq!(bytes.len(), 1036);
+        assert_eq!(&bytes[..4], &[0, 2, 0, 1]);
+        assert_eq!(Packet::read(&mut Cursor::new(bytes)).await?, packet);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_v3_packet_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let short = Packet::response(3, 1, "WARNING - load average: 5.1");
+        let bytes = short.encode()?;
+        assert_eq!(bytes.len(), 1036);
+        assert_eq!(Packet::read(&mut Cursor::new(bytes)).await?, short);
+
+        let long = Packet::response(3, 0, &"x".repeat(5000));
+        let bytes = long.encode()?;
+        assert_eq!(bytes.len(), 16 + 5001 + 3);
+        assert_eq!(Packet::read(&mut Cursor::new(bytes)).await?, long);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_packet_with_invalid_crc() -> Result<(), Box<dyn std::error::Error>> {
+        let mut bytes = Packet::query(2, "check_load").encode()?;
+        bytes[20] = b'!';
+
+        assert!(matches!(
+            Packet::read(&mut Cursor::new(bytes)).await,
+            Err(NrpeError::InvalidCrc)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_string() {
+        assert_eq!(
+            query_string("check_disk", &["20%".to_string(), "10%".to_string()]),
+            "check_disk!20%!10%"
+        );
+        assert_eq!(query_string("check_users", &[]), "check_users");
+    }
+}
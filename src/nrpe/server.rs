@@ -0,0 +1,443 @@
+use super::{NrpeError, Packet, QUERY_PACKET};
+use crate::check::{Check, Checks};
+use crate::net::tls_accept;
+use log::{debug, error, info};
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{error::Elapsed, timeout};
+
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:5666";
+/// The same default as the `allowed_hosts` of the `nrpe` daemon.
+pub const DEFAULT_ALLOWED_HOSTS: &str = "127.0.0.1,::1";
+/// The command `check_nrpe` sends without `-c`, to ask for the version of the daemon.
+pub const VERSION_COMMAND: &str = "_NRPE_CHECK";
+/// How long a poller has to send its query, and to read the response.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The addresses and networks that may connect, such as `10.0.0.0/8,::1`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllowList(Vec<(IpAddr, u8)>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AllowListParseError(String);
+
+impl std::error::Error for AllowListParseError {}
+
+impl fmt::Display for AllowListParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid allowed host: {}, expected an IP address or network",
+            self.0
+        )
+    }
+}
+
+impl FromStr for AllowList {
+    type Err = AllowListParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let invalid = || AllowListParseError(entry.to_string());
+                let (address, prefix) = match entry.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (entry, None),
+                };
+                let address = address.parse::<IpAddr>().map_err(|_| invalid())?;
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(p) => p.parse::<u8>().map_err(|_| invalid())?,
+                    None => max_prefix,
+                };
+                if prefix > max_prefix {
+                    return Err(invalid());
+                }
+                Ok((address, prefix))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+}
+
+impl AllowList {
+    pub fn allows(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        self.0
+            .iter()
+            .any(|(network, prefix)| match (network, address) {
+                (IpAddr::V4(n), IpAddr::V4(a)) => {
+                    same_network(u32::from(*n) as u128, u32::from(a) as u128, *prefix, 32)
+                }
+                (IpAddr::V6(n), IpAddr::V6(a)) => {
+                    same_network(u128::from(*n), u128::from(a), *prefix, 128)
+                }
+                _ => false,
+            })
+    }
+}
+
+fn same_network(network: u128, address: u128, prefix: u8, bits: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= bits || network >> shift == address >> shift
+}
+
+/// The NRPE command name of a check: its name in lower case, with every character other than
+/// letters and digits replaced by `_`. A check can also be queried by its exact name.
+pub fn command_name(check_name: &str) -> String {
+    check_name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+/// A TLS acceptor with the certificate and key of the server. With a CA certificate, pollers
+/// must present a client certificate signed by it.
+pub fn tls_acceptor(
+    cert: &Path,
+    key: &Path,
+    ca: Option<&Path>,
+) -> Result<SslAcceptor, openssl::error::ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_certificate_chain_file(cert)?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    if let Some(ca) = ca {
+        builder.set_ca_file(ca)?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    Ok(builder.build())
+}
+
+/// Answers NRPE queries from Nagios-family pollers by running the check the command names.
+pub struct NrpeServer {
+    checks: Arc<HashMap<String, Check>>,
+    allowed_hosts: AllowList,
+    tls: Option<Arc<SslAcceptor>>,
+}
+
+impl NrpeServer {
+    /// The checks must have their ranges expanded. If two checks have the same command name,
+    /// the first one is used.
+    pub fn new(checks: Checks) -> Self {
+        let mut by_command = HashMap::new();
+        for check in checks {
+            by_command
+                .entry(command_name(check.name()))
+                .or_insert_with(|| check.clone());
+            by_command.entry(check.name().to_string()).or_insert(check);
+        }
+
+        Self {
+            checks: Arc::new(by_command),
+            allowed_hosts: DEFAULT_ALLOWED_HOSTS.parse().unwrap_or_default(),
+            tls: None,
+        }
+    }
+
+    pub fn allowed_hosts(mut self, allowed_hosts: AllowList) -> Self {
+        self.allowed_hosts = allowed_hosts;
+        self
+    }
+
+    pub fn tls(mut self, acceptor: SslAcceptor) -> Self {
+        self.tls = Some(Arc::new(acceptor));
+        self
+    }
+
+    /// Listen on the address and answer queries until SIGINT or SIGTERM is received.
+    pub async fn run(self, listen: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(listen).await?;
+        info!("Listening for NRPE queries on {}", listener.local_addr()?);
+        self.serve(listener).await
+    }
+
+    /// Answer queries on a bound listener until SIGINT or SIGTERM is received.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(a) => a,
+                        Err(e) => {
+                            error!("Failed to accept a connection: {}", e);
+                            continue;
+                        }
+                    };
+                    if !self.allowed_hosts.allows(peer.ip()) {
+                        error!("Rejected a connection from {}, which isn't an allowed host", peer);
+                        continue;
+                    }
+                    let checks = Arc::clone(&self.checks);
+                    let tls = self.tls.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, checks, tls).await {
+                            error!("Failed to answer the NRPE query from {}: {}", peer, e);
+                        }
+                    });
+                }
+                _ = sigint.recv() => {
+                    info!("Received SIGINT, stopping");
+                    return Ok(());
+                }
+                _ = sigterm.recv() => {
+                    info!("Received SIGTERM, stopping");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    checks: Arc<HashMap<String, Check>>,
+    tls: Option<Arc<SslAcceptor>>,
+) -> Result<(), NrpeError> {
+    match tls {
+        Some(acceptor) => {
+            let stream = timeout(CONNECTION_TIMEOUT, tls_accept(&acceptor, stream))
+                .await
+                .map_err(timed_out)?
+                .map_err(|e| NrpeError::Tls(e.to_string()))?;
+            respond(stream, &checks).await
+        }
+        None => respond(stream, &checks).await,
+    }
+}
+
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    checks: &HashMap<String, Check>,
+) -> Result<(), NrpeError> {
+    let query = timeout(CONNECTION_TIMEOUT, Packet::read(&mut stream))
+        .await
+        .map_err(timed_out)??;
+
+    let (result_code, output) = answer(checks, &query).await;
+    let response = Packet::response(query.version, result_code, &output).encode()?;
+
+    timeout(CONNECTION_TIMEOUT, async {
+        stream.write_all(&response).await?;
+        stream.flush().await
+    })
+    .await
+    .map_err(timed_out)??;
+
+    Ok(())
+}
+
+fn timed_out(_: Elapsed) -> NrpeError {
+    NrpeError::Io(io::Error::from(io::ErrorKind::TimedOut))
+}
+
+async fn answer(checks: &HashMap<String, Check>, query: &Packet) -> (i16, String) {
+    if query.packet_type != QUERY_PACKET {
+        return (3, "NRPE: Invalid packet type".to_string());
+    }
+
+    let query = query.buffer_string();
+    let (command, args) = match query.split_once('!') {
+        Some((command, args)) => (command, Some(args)),
+        None => (query.as_str(), None),
+    };
+    debug!("Received NRPE query for command {}", command);
+
+    if command == VERSION_COMMAND {
+        return (0, format!("xtender {}", env!("CARGO_PKG_VERSION")));
+    }
+    if args.is_some() {
+        return (3, "NRPE: Command arguments are not allowed".to_string());
+    }
+
+    match checks.get(command) {
+        Some(check) => {
+            // The same status as in the status column, including the thresholds of the check.
+            let result = check.run_async().await;
+            (
+                result.overall_status().unwrap_or(3).clamp(0, 3) as i16,
+                result.plugin_output(),
+            )
+        }
+        None => (3, format!("NRPE: Command '{}' not defined", command)),
+    }
+}
+
+#[cfg(test)]
+mod server_test {
+    use super::*;
+    use crate::check::CheckBuilder;
+    use crate::nrpe::{query, TlsOptions};
+    use crate::threshold::{MetricThresholds, Thresholds};
+    use pretty_assertions::assert_eq;
+
+    fn checks() -> Checks {
+        vec![
+            CheckBuilder::new()
+                .name("Remote Load")
+                .command("sh -c 'echo \"LOAD WARNING|load1=5\"; exit 1'")
+                .build_raw(),
+            CheckBuilder::new()
+                .name("fail")
+                .command("sh -c 'echo broken; exit 2'")
+                .build_raw(),
+            CheckBuilder::new()
+                .name("disk")
+                .command("printf 'DISK OK - /var, /tmp|used=5\\nline 2\\nline 3'")
+                .build_raw(),
+            CheckBuilder::new()
+                .name("load")
+                .command("echo 'LOAD OK|load1=7'")
+                .thresholds(Thresholds::from([(
+                    "load1".to_string(),
+                    MetricThresholds {
+                        warn: Some("5".to_string()),
+                        crit: Some("10".to_string()),
+                    },
+                )]))
+                .worst_status(true)
+                .build_raw(),
+        ]
+    }
+
+    async fn start(server: NrpeServer) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(server.serve(listener));
+        port
+    }
+
+    async fn ask(
+        port: u16,
+        tls: Option<TlsOptions>,
+        version: u16,
+        q: &str,
+    ) -> Result<Packet, NrpeError> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        timeout(
+            Duration::from_secs(5),
+            query(stream, "localhost", tls.as_ref(), version, q),
+        )
+        .await
+        .map_err(timed_out)?
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let allowed = AllowList::from_str("10.1.0.0/16, 192.168.1.5,::1").unwrap();
+
+        assert!(allowed.allows("10.1.200.3".parse().unwrap()));
+        assert!(!allowed.allows("10.2.0.1".parse().unwrap()));
+        assert!(allowed.allows("192.168.1.5".parse().unwrap()));
+        assert!(allowed.allows("::ffff:192.168.1.5".parse().unwrap()));
+        assert!(allowed.allows("::1".parse().unwrap()));
+        assert!(!allowed.allows("127.0.0.1".parse().unwrap()));
+        assert!(AllowList::from_str("0.0.0.0/0")
+            .unwrap()
+            .allows("8.8.8.8".parse().unwrap()));
+        assert_eq!(
+            AllowList::from_str("10.0.0.0/33"),
+            Err(AllowListParseError("10.0.0.0/33".to_string()))
+        );
+        assert!(AllowList::from_str("example.com").is_err());
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(command_name("Remote Load"), "remote_load");
+        assert_eq!(command_name("disk /var"), "disk__var");
+    }
+
+    #[tokio::test]
+    async fn test_answers_queries() -> Result<(), Box<dyn std::error::Error>> {
+        let port = start(NrpeServer::new(checks())).await;
+
+        let response = ask(port, None, 3, "remote_load").await?;
+        assert_eq!(response.result_code, 1);
+        assert_eq!(response.buffer_string(), "LOAD WARNING|load1=5");
+
+        let response = ask(port, None, 2, "fail").await?;
+        assert_eq!((response.version, response.result_code), (2, 2));
+        assert_eq!(response.buffer_string(), "broken");
+
+        let response = ask(port, None, 3, "missing").await?;
+        assert_eq!(response.result_code, 3);
+        assert_eq!(
+            response.buffer_string(),
+            "NRPE: Command 'missing' not defined"
+        );
+
+        let response = ask(port, None, 3, "fail!1!2").await?;
+        assert_eq!(response.result_code, 3);
+
+        let response = ask(port, None, 3, VERSION_COMMAND).await?;
+        assert!(response.buffer_string().starts_with("xtender "));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_answers_with_the_plugin_output() -> Result<(), Box<dyn std::error::Error>> {
+        let port = start(NrpeServer::new(checks())).await;
+
+        let response = ask(port, None, 3, "disk").await?;
+        assert_eq!(response.result_code, 0);
+        assert_eq!(
+            response.buffer_string(),
+            "DISK OK - /var, /tmp|used=5\nline 2\nline 3"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_answers_with_the_status_of_the_thresholds(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let port = start(NrpeServer::new(checks())).await;
+
+        let response = ask(port, None, 3, "load").await?;
+        assert_eq!(response.result_code, 1);
+        assert_eq!(response.buffer_string(), "LOAD OK|load1=7");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_answers_queries_over_tls() -> Result<(), Box<dyn std::error::Error>> {
+        let acceptor = crate::builtin::self_signed_acceptor(30);
+        let port = start(NrpeServer::new(checks()).tls(acceptor)).await;
+
+        let response = ask(port, Some(TlsOptions::default()), 3, "Remote Load").await?;
+        assert_eq!(response.buffer_string(), "LOAD WARNING|load1=5");
+
+        assert!(ask(port, None, 3, "Remote Load").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejects_hosts_not_allowed() {
+        let server = NrpeServer::new(checks()).allowed_hosts("10.0.0.1".parse().unwrap());
+        let port = start(server).await;
+
+        assert!(ask(port, None, 3, "fail").await.is_err());
+    }
+}
@@ -0,0 +1,458 @@
+//! Performance data in the format of the Nagios plugin API. Each entry is
+//! `'label'=value[UOM];[warn];[crit];[min];[max]`, and entries are separated by spaces. Labels
+//! with spaces or `=` are quoted with single quotes, and a single quote inside a quoted label is
+//! written as two. The value `U` means the plugin couldn't determine the value. Decimal commas,
+//! as printed by plugins running in some locales, are read as decimal points.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A single metric of the performance data of a check.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PerfDatum {
+    pub label: String,
+    /// `None` if the value is `U`.
+    pub value: Option<f64>,
+    pub uom: Option<String>,
+    /// The warning threshold range, with decimal commas replaced by points.
+    pub warn: Option<String>,
+    /// The critical threshold range, with decimal commas replaced by points.
+    pub crit: Option<String>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PerfDataError {
+    MissingEquals(String),
+    UnterminatedQuote(String),
+    EmptyLabel(String),
+    InvalidValue(String),
+    InvalidThreshold(String),
+    InvalidMinMax(String),
+}
+
+impl std::error::Error for PerfDataError {}
+
+impl fmt::Display for PerfDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PerfDataError::MissingEquals(entry) => {
+                write!(f, "Invalid perfdata {}: missing '=' after the label", entry)
+            }
+            PerfDataError::UnterminatedQuote(entry) => {
+                write!(
+                    f,
+                    "Invalid perfdata {}: the label has no closing quote",
+                    entry
+                )
+            }
+            PerfDataError::EmptyLabel(entry) => {
+                write!(f, "Invalid perfdata {}: the label is empty", entry)
+            }
+            PerfDataError::InvalidValue(entry) => {
+                write!(f, "Invalid perfdata {}: the value is not a number", entry)
+            }
+            PerfDataError::InvalidThreshold(entry) => {
+                write!(f, "Invalid perfdata {}: invalid warn or crit range", entry)
+            }
+            PerfDataError::InvalidMinMax(entry) => {
+                write!(f, "Invalid perfdata {}: min or max is not a number", entry)
+            }
+        }
+    }
+}
+
+impl PerfDataError {
+    /// The entry that couldn't be parsed.
+    pub fn entry(&self) -> &str {
+        match self {
+            PerfDataError::MissingEquals(e)
+            | PerfDataError::UnterminatedQuote(e)
+            | PerfDataError::EmptyLabel(e)
+            | PerfDataError::InvalidValue(e)
+            | PerfDataError::InvalidThreshold(e)
+            | PerfDataError::InvalidMinMax(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for PerfDatum {
+    /// The entry in the perfdata format, with the label quoted if needed.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self
+            .label
+            .contains(|c: char| c.is_whitespace() || "=';".contains(c))
+        {
+            write!(f, "'{}'=", self.label.replace('\'', "''"))?;
+        } else {
+            write!(f, "{}=", self.label)?;
+        }
+        match self.value {
+            Some(value) => write!(f, "{}", value)?,
+            None => write!(f, "U")?,
+        }
+        write!(f, "{}", self.uom.as_deref().unwrap_or_default())?;
+
+        let fields = [
+            self.warn.clone(),
+            self.crit.clone(),
+            self.min.map(|m| m.to_string()),
+            self.max.map(|m| m.to_string()),
+        ];
+        let used = fields
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |i| i + 1);
+        for field in &fields[..used] {
+            write!(f, ";{}", field.as_deref().unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PerfDatum {
+    type Err = PerfDataError;
+
+    /// Parse a single entry. Anything after the first entry is an error.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = parse_entries(s);
+        match (entries.next(), entries.next()) {
+            (Some(entry), None) => entry,
+            (Some(_), Some(_)) => Err(PerfDataError::InvalidValue(s.trim().to_string())),
+            (None, _) => Err(PerfDataError::EmptyLabel(s.trim().to_string())),
+        }
+    }
+}
+
+/// Parse performance data into its entries, in order. Every entry that can't be parsed is an
+/// error, without affecting the entries around it.
+pub fn parse_perfdata(s: &str) -> Vec<Result<PerfDatum, PerfDataError>> {
+    parse_entries(s).collect()
+}
+
+fn parse_entries(s: &str) -> impl Iterator<Item = Result<PerfDatum, PerfDataError>> + '_ {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let (entry, remaining) = next_entry(rest);
+        rest = remaining;
+        Some(entry)
+    })
+}
+
+/// Parse the entry at the start of the string, and return the rest of the string after it.
+fn next_entry(s: &str) -> (Result<PerfDatum, PerfDataError>, &str) {
+    let (label, after_label) = match s.strip_prefix('\'') {
+        Some(quoted) => match quoted_label(quoted) {
+            Some(label) => label,
+            None => return (Err(PerfDataError::UnterminatedQuote(s.to_string())), ""),
+        },
+        None => {
+            let end = s
+                .find(|c: char| c == '=' || c.is_whitespace())
+                .unwrap_or(s.len());
+            (s[..end].to_string(), &s[end..])
+        }
+    };
+
+    let data_end = after_label
+        .find(char::is_whitespace)
+        .unwrap_or(after_label.len());
+    let entry = &s[..s.len() - after_label.len() + data_end];
+    let rest = &after_label[data_end..];
+
+    let Some(data) = after_label[..data_end].strip_prefix('=') else {
+        return (Err(PerfDataError::MissingEquals(entry.to_string())), rest);
+    };
+    if label.is_empty() {
+        return (Err(PerfDataError::EmptyLabel(entry.to_string())), rest);
+    }
+
+    (parse_data(label, data, entry), rest)
+}
+
+/// The label in quotes at the start of the string, which starts after the opening quote, and
+/// the rest of the string after the closing quote.
+fn quoted_label(s: &str) -> Option<(String, &str)> {
+    let mut label = String::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != '\'' {
+            label.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some((_, '\'')) => {
+                label.push('\'');
+                chars.next();
+            }
+            _ => return Some((label, &s[i + 1..])),
+        }
+    }
+    None
+}
+
+fn parse_data(label: String, data: &str, entry: &str) -> Result<PerfDatum, PerfDataError> {
+    let mut fields = data.split(';');
+    let value_field = fields.next().unwrap_or_default();
+
+    let (value, uom) = match value_field {
+        "U" => (None, ""),
+        _ => match number_prefix(value_field) {
+            Some((value, uom)) if is_uom(uom) => (Some(value), uom),
+            _ => return Err(PerfDataError::InvalidValue(entry.to_string())),
+        },
+    };
+
+    let mut range = || -> Result<Option<String>, PerfDataError> {
+        match fields.next().map(|f| f.trim()).filter(|f| !f.is_empty()) {
+            Some(range) if is_range(range) => Ok(Some(range.replace(',', "."))),
+            Some(_) => Err(PerfDataError::InvalidThreshold(entry.to_string())),
+            None => Ok(None),
+        }
+    };
+    let warn = range()?;
+    let crit = range()?;
+
+    // Some plugins repeat the unit of measurement after min and max.
+    let mut limit = || -> Result<Option<f64>, PerfDataError> {
+        match fields.next().map(|f| f.trim()).filter(|f| !f.is_empty()) {
+            Some(field) => match number_prefix(field) {
+                Some((limit, rest)) if rest.is_empty() || rest == uom => Ok(Some(limit)),
+                _ => Err(PerfDataError::InvalidMinMax(entry.to_string())),
+            },
+            None => Ok(None),
+        }
+    };
+    let min = limit()?;
+    let max = limit()?;
+
+    Ok(PerfDatum {
+        label,
+        value,
+        uom: (!uom.is_empty()).then(|| uom.to_string()),
+        warn,
+        crit,
+        min,
+        max,
+    })
+}
+
+/// A number as printed by plugins, which may have a decimal comma or an exponent.
+pub fn parse_number(s: &str) -> Option<f64> {
+    match number_prefix(s.trim()) {
+        Some((number, "")) => Some(number),
+        _ => None,
+    }
+}
+
+/// The number at the start of the string, and the rest of the string after it. Accepts a sign,
+/// a decimal point or comma, and an exponent.
+fn number_prefix(s: &str) -> Option<(f64, &str)> {
+    let bytes = s.as_bytes();
+    let digits_from = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+
+    let mut end = 0;
+    if matches!(bytes.first(), Some(b'-' | b'+')) {
+        end = 1;
+    }
+    let integer_end = digits_from(end);
+    let mut mantissa_digits = integer_end - end;
+    end = integer_end;
+    if matches!(bytes.get(end), Some(b'.' | b',')) {
+        let fraction_end = digits_from(end + 1);
+        mantissa_digits += fraction_end - end - 1;
+        end = fraction_end;
+    }
+    if mantissa_digits == 0 {
+        return None;
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(bytes.get(end + 1), Some(b'-' | b'+')));
+        let exponent_end = digits_from(end + 1 + sign);
+        if exponent_end > end + 1 + sign {
+            end = exponent_end;
+        }
+    }
+
+    let number = s[..end].replace(',', ".").parse::<f64>().ok()?;
+    Some((number, &s[end..]))
+}
+
+/// Whether the text after a value is a unit of measurement, such as `%`, `ms`, `KB`, `B/s` or
+/// `°C`.
+fn is_uom(s: &str) -> bool {
+    s.chars().all(|c| c.is_alphabetic() || "%/°".contains(c))
+}
+
+/// Whether the string only has the characters of a Nagios threshold range.
+fn is_range(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_digit() || ".,-+:~@eE".contains(c))
+}
+
+#[cfg(test)]
+mod perfdata_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn datum(label: &str, value: Option<f64>, uom: Option<&str>) -> PerfDatum {
+        PerfDatum {
+            label: label.to_string(),
+            value,
+            uom: uom.map(|u| u.to_string()),
+            ..PerfDatum::default()
+        }
+    }
+
+    #[test]
+    fn test_full_entry() {
+        assert_eq!(
+            PerfDatum::from_str("time=0.245s;1;2.5;0;10"),
+            Ok(PerfDatum {
+                label: "time".to_string(),
+                value: Some(0.245),
+                uom: Some("s".to_string()),
+                warn: Some("1".to_string()),
+                crit: Some("2.5".to_string()),
+                min: Some(0.0),
+                max: Some(10.0),
+            })
+        );
+        assert_eq!(
+            PerfDatum::from_str("/=2643MB;5948;5958;0;5968MB").map(|d| d.max),
+            Ok(Some(5968.0))
+        );
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(
+            PerfDatum::from_str("offset=-0.5s"),
+            Ok(datum("offset", Some(-0.5), Some("s")))
+        );
+        assert_eq!(
+            PerfDatum::from_str("rate=1.5e3"),
+            Ok(datum("rate", Some(1500.0), None))
+        );
+        assert_eq!(
+            PerfDatum::from_str("tiny=2E-3"),
+            Ok(datum("tiny", Some(0.002), None))
+        );
+        assert_eq!(
+            PerfDatum::from_str("users=U"),
+            Ok(datum("users", None, None))
+        );
+        assert_eq!(
+            PerfDatum::from_str("load=0,75;1,5;2"),
+            Ok(PerfDatum {
+                warn: Some("1.5".to_string()),
+                crit: Some("2".to_string()),
+                ..datum("load", Some(0.75), None)
+            })
+        );
+        assert_eq!(
+            PerfDatum::from_str("traffic=.5KB/s"),
+            Ok(datum("traffic", Some(0.5), Some("KB/s")))
+        );
+        assert_eq!(
+            PerfDatum::from_str("c=12c"),
+            Ok(datum("c", Some(12.0), Some("c")))
+        );
+    }
+
+    #[test]
+    fn test_quoted_labels() {
+        assert_eq!(
+            PerfDatum::from_str("'split label'=foo").unwrap_err(),
+            PerfDataError::InvalidValue("'split label'=foo".to_string())
+        );
+        assert_eq!(
+            PerfDatum::from_str("'Load Average'=1").map(|d| d.label),
+            Ok("Load Average".to_string())
+        );
+        assert_eq!(
+            PerfDatum::from_str("'a=b'=1").map(|d| d.label),
+            Ok("a=b".to_string())
+        );
+        assert_eq!(
+            PerfDatum::from_str("'it''s'=1").map(|d| d.label),
+            Ok("it's".to_string())
+        );
+    }
+
+    #[test]
+    fn test_multiple_entries() {
+        assert_eq!(
+            parse_perfdata("  'C:\\ used'=10%;80;90  'Mem ''free'''=2GB  x=1 "),
+            vec![
+                Ok(PerfDatum {
+                    warn: Some("80".to_string()),
+                    crit: Some("90".to_string()),
+                    ..datum("C:\\ used", Some(10.0), Some("%"))
+                }),
+                Ok(datum("Mem 'free'", Some(2.0), Some("GB"))),
+                Ok(datum("x", Some(1.0), None)),
+            ]
+        );
+        assert_eq!(parse_perfdata(""), vec![]);
+    }
+
+    #[test]
+    fn test_display() {
+        for entry in [
+            "time=0.245s;1;2.5;0;10",
+            "'Load Average'=1",
+            "'it''s'=U",
+            "'a=b'=-1.5;;@5:10",
+            "x=1;;;;100",
+        ] {
+            let datum = PerfDatum::from_str(entry).unwrap();
+            assert_eq!(datum.to_string(), entry);
+        }
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("421"), Some(421.0));
+        assert_eq!(parse_number(" 0,5 "), Some(0.5));
+        assert_eq!(parse_number("-1e3"), Some(-1000.0));
+        assert_eq!(parse_number("12ms"), None);
+        assert_eq!(parse_number(""), None);
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        assert_eq!(
+            parse_perfdata("a=1 b c=x d=1;5s;; e=2"),
+            vec![
+                Ok(datum("a", Some(1.0), None)),
+                Err(PerfDataError::MissingEquals("b".to_string())),
+                Err(PerfDataError::InvalidValue("c=x".to_string())),
+                Err(PerfDataError::InvalidThreshold("d=1;5s;;".to_string())),
+                Ok(datum("e", Some(2.0), None)),
+            ]
+        );
+        assert_eq!(
+            parse_perfdata("=1 f=1;;;zero 'open=1"),
+            vec![
+                Err(PerfDataError::EmptyLabel("=1".to_string())),
+                Err(PerfDataError::InvalidMinMax("f=1;;;zero".to_string())),
+                Err(PerfDataError::UnterminatedQuote("'open=1".to_string())),
+            ]
+        );
+        assert_eq!(
+            PerfDataError::InvalidValue("c=x".to_string()).to_string(),
+            "Invalid perfdata c=x: the value is not a number"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use crate::check::Check;
+use crate::variable::{VariableKind, Variables};
+use serde::Serialize;
+
+/// A check as it would be run, without running it. Secrets are always obfuscated.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedCheck {
+    pub name: String,
+    pub command: String,
+    pub timeout: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    /// The remote host the check would run on through SSH.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_host: Option<String>,
+    pub variables_found: Vec<String>,
+    pub variables_not_found: Vec<String>,
+}
+
+/// Every check that a run would execute, in the order they would be executed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckPlan {
+    pub templates_found: Vec<String>,
+    pub templates_not_found: Vec<String>,
+    pub checks: Vec<PlannedCheck>,
+}
+
+const TABLE_HEADER: [&str; 5] = [
+    "NAME",
+    "COMMAND",
+    "TIMEOUT",
+    "VARIABLES FOUND",
+    "VARIABLES NOT FOUND",
+];
+
+impl PlannedCheck {
+    /// Plan every check that the built check expands to. The variables are those resolved when
+    /// the check was built, before its ranges were expanded.
+    pub fn from_check(check: Check) -> Vec<Self> {
+        let variables_found = variable_strings(check.variables_found());
+        let variables_not_found = variable_strings(check.variables_not_found());
+
+        check
+            .expand_ranges()
+            .into_iter()
+            .map(|c| Self {
+                name: c.name().to_string(),
+                command: c
+                    .runtime_macros()
+                    .apply(&c.display_command())
+                    .trim()
+                    .to_string(),
+                timeout: c.timeout(),
+                interval: c.interval(),
+                shell: c.shell().map(|s| s.to_string()),
+                ssh_host: c.ssh().map(|t| t.host.clone()),
+                variables_found: variables_found.clone(),
+                variables_not_found: variables_not_found.clone(),
+            })
+            .collect()
+    }
+}
+
+impl CheckPlan {
+    pub fn new(templates_found: &[String], templates_not_found: &[String]) -> Self {
+        Self {
+            templates_found: templates_found.to_vec(),
+            templates_not_found: templates_not_found.to_vec(),
+            checks: Vec::new(),
+        }
+    }
+
+    /// Add a built check, and every check its ranges expand to, to the plan.
+    pub fn add_check(&mut self, check: Check) {
+        self.checks.extend(PlannedCheck::from_check(check));
+    }
+
+    pub fn as_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// The plan as a plain text table with one row per check.
+    pub fn as_table(&self) -> String {
+        let rows = self
+            .checks
+            .iter()
+            .map(|c| {
+                [
+                    c.name.clone(),
+                    match &c.ssh_host {
+                        Some(host) => format!("{} (ssh {})", single_line(&c.command), host),
+                        None => single_line(&c.command),
+                    },
+                    format!("{} s", c.timeout),
+                    c.variables_found.join(", "),
+                    c.variables_not_found.join(", "),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        let mut widths = TABLE_HEADER.map(|h| h.chars().count());
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let format_row = |cells: &[String]| {
+            cells
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut lines = vec![
+            format!("Templates found: {}", self.templates_found.join(", ")),
+            format!(
+                "Templates not found: {}",
+                self.templates_not_found.join(", ")
+            ),
+            format!("Checks: {}", self.checks.len()),
+            String::new(),
+            format_row(&TABLE_HEADER.map(|h| h.to_string())),
+        ];
+        lines.extend(rows.iter().map(|row| format_row(row)));
+
+        lines.join("\n") + "\n"
+    }
+}
+
+fn variable_strings(variables: Option<&Variables>) -> Vec<String> {
+    let mut strings = variables
+        .into_iter()
+        .flatten()
+        .map(|v| match v {
+            VariableKind::Public(v) => v.to_public_string(),
+            VariableKind::Secret(v) => v.to_hidden_string(),
+        })
+        .collect::<Vec<_>>();
+    strings.sort();
+    strings
+}
+
+fn single_line(s: &str) -> String {
+    s.lines().map(str::trim).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod plan_test {
+    use super::*;
+    use crate::check::CheckBuilder;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_plan_expands_ranges() -> Result<(), Box<dyn std::error::Error>> {
+        std::env::set_var("PLAN_TEST_HOST", "example.com");
+
+        let check = CheckBuilder::new()
+            .name("ping !!A:1..2!!")
+            .command("ping -c !!A:1..2!! $PLAN_TEST_HOST$ $PLAN_TEST_MISSING$")
+            .timeout(10)
+            .build()?;
+
+        let mut plan = CheckPlan::new(&["network".to_string()], &[]);
+        plan.add_check(check);
+
+        assert_eq!(
+            plan.checks
+                .iter()
+                .map(|c| c.command.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "ping -c 1 example.com $PLAN_TEST_MISSING$",
+                "ping -c 2 example.com $PLAN_TEST_MISSING$",
+            ]
+        );
+        assert_eq!(plan.checks[1].name, "ping 2");
+        assert_eq!(
+            plan.checks[1].variables_found,
+            vec!["PLAN_TEST_HOST=\"example.com\""]
+        );
+        assert_eq!(
+            plan.checks[1].variables_not_found,
+            vec!["PLAN_TEST_MISSING"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_as_table() {
+        let plan = CheckPlan {
+            templates_found: vec!["base".to_string()],
+            templates_not_found: vec!["missing".to_string()],
+            checks: vec![PlannedCheck {
+                name: "check".to_string(),
+                command: "check_foo\n  --bar ***".to_string(),
+                timeout: 5,
+                interval: None,
+                shell: None,
+                ssh_host: None,
+                variables_found: vec!["SECRET=***".to_string()],
+                variables_not_found: vec![],
+            }],
+        };
+
+        assert_eq!(
+            plan.as_table(),
+            [
+                "Templates found: base",
+                "Templates not found: missing",
+                "Checks: 1",
+                "",
+                "NAME   COMMAND              TIMEOUT  VARIABLES FOUND  VARIABLES NOT FOUND",
+                "check  check_foo --bar ***  5 s      SECRET=***",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
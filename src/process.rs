@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus};
+use std::time::Duration;
+use tokio::process::{ChildStderr, ChildStdin, ChildStdout};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Resources used by a plugin process, as reported by the kernel when it was reaped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceUsage {
+    pub user_cpu: Duration,
+    pub system_cpu: Duration,
+    /// Maximum resident set size in KiB.
+    pub max_rss: u64,
+}
+
+impl ResourceUsage {
+    /// Sum the CPU times and keep the largest maximum resident set size.
+    pub fn total<'a>(usages: impl IntoIterator<Item = &'a ResourceUsage>) -> Self {
+        usages.into_iter().fold(Self::default(), |acc, u| Self {
+            user_cpu: acc.user_cpu + u.user_cpu,
+            system_cpu: acc.system_cpu + u.system_cpu,
+            max_rss: acc.max_rss.max(u.max_rss),
+        })
+    }
+
+    fn from_rusage(r: &libc::rusage) -> Self {
+        let to_duration =
+            |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+
+        // Linux reports the maximum resident set size in KiB, macOS in bytes.
+        #[cfg(target_os = "macos")]
+        let max_rss = r.ru_maxrss as u64 / 1024;
+        #[cfg(not(target_os = "macos"))]
+        let max_rss = r.ru_maxrss as u64;
+
+        Self {
+            user_cpu: to_duration(r.ru_utime),
+            system_cpu: to_duration(r.ru_stime),
+            max_rss,
+        }
+    }
+}
+
+/// A spawned plugin process that is reaped with `wait4`, so that its resource usage can be
+/// collected. If it's dropped before it has been reaped, the process is killed and reaped.
+pub(crate) struct ChildProcess {
+    pid: libc::pid_t,
+    reaped: bool,
+    sigchld: Signal,
+    pub stdin: Option<ChildStdin>,
+    pub stdout: Option<ChildStdout>,
+    pub stderr: Option<ChildStderr>,
+}
+
+impl ChildProcess {
+    /// Spawn the command. Must be called from within a Tokio runtime.
+    pub fn spawn(command: &mut Command) -> io::Result<Self> {
+        // Listen for SIGCHLD before spawning, so that the exit can't be missed.
+        let sigchld = signal(SignalKind::child())?;
+        let mut child = command.spawn()?;
+
+        Ok(Self {
+            pid: child.id() as libc::pid_t,
+            reaped: false,
+            sigchld,
+            stdin: child.stdin.take().map(ChildStdin::from_std).transpose()?,
+            stdout: child.stdout.take().map(ChildStdout::from_std).transpose()?,
+            stderr: child.stderr.take().map(ChildStderr::from_std).transpose()?,
+        })
+    }
+
+    /// Wait for the process to exit and reap it.
+    pub async fn wait(&mut self) -> io::Result<(ExitStatus, ResourceUsage)> {
+        loop {
+            if let Some(exited) = self.try_wait(libc::WNOHANG)? {
+                return Ok(exited);
+            }
+            self.sigchld.recv().await;
+        }
+    }
+
+    pub fn kill(&mut self) -> io::Result<()> {
+        if self.reaped {
+            return Ok(());
+        }
+        // SAFETY: The process hasn't been reaped, so the pid can't have been reused.
+        if unsafe { libc::kill(self.pid, libc::SIGKILL) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn try_wait(
+        &mut self,
+        options: libc::c_int,
+    ) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+        let exited = wait4(self.pid, options)?;
+        if exited.is_some() {
+            self.reaped = true;
+        }
+        Ok(exited)
+    }
+}
+
+/// Reap the process with `wait4`, blocking until it exits unless `options` has `WNOHANG`.
+fn wait4(
+    pid: libc::pid_t,
+    options: libc::c_int,
+) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    let mut status = 0;
+    // SAFETY: An all-zero rusage is a valid value, and it's only read after wait4 filled it.
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        // SAFETY: The pointers are valid for the duration of the call.
+        match unsafe { libc::wait4(pid, &mut status, options, &mut rusage) } {
+            0 => return Ok(None),
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            _ => {
+                return Ok(Some((
+                    ExitStatus::from_raw(status),
+                    ResourceUsage::from_rusage(&rusage),
+                )))
+            }
+        }
+    }
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        if self.reaped || self.kill().is_err() {
+            return;
+        }
+        // Waiting for the killed process blocks, so it's done off the async worker threads
+        // when running inside a runtime.
+        let pid = self.pid;
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || wait4(pid, 0));
+            }
+            Err(_) => {
+                let _ = wait4(pid, 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod process_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::process::Stdio;
+
+    #[test]
+    fn test_total_resource_usage() {
+        let usages = [
+            ResourceUsage {
+                user_cpu: Duration::from_millis(10),
+                system_cpu: Duration::from_millis(1),
+                max_rss: 2048,
+            },
+            ResourceUsage {
+                user_cpu: Duration::from_millis(20),
+                system_cpu: Duration::from_millis(2),
+                max_rss: 1024,
+            },
+        ];
+
+        assert_eq!(
+            ResourceUsage::total(&usages),
+            ResourceUsage {
+                user_cpu: Duration::from_millis(30),
+                system_cpu: Duration::from_millis(3),
+                max_rss: 2048,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dropped_process_is_reaped() -> io::Result<()> {
+        let child = ChildProcess::spawn(Command::new("sleep").arg("10").stdout(Stdio::null()))?;
+        let pid = child.pid;
+        drop(child);
+
+        // The process is killed right away and reaped in the background.
+        for _ in 0..100 {
+            // SAFETY: Signal 0 only checks whether the process exists.
+            if unsafe { libc::kill(pid, 0) } == -1 {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("The dropped process {} was not reaped", pid);
+    }
+
+    #[tokio::test]
+    async fn test_wait_collects_resource_usage() -> io::Result<()> {
+        let mut child = ChildProcess::spawn(Command::new("sh").args(["-c", "exit 3"]))?;
+        let (status, usage) = child.wait().await?;
+
+        assert_eq!(status.code(), Some(3));
+        assert!(usage.max_rss > 0);
+
+        Ok(())
+    }
+}
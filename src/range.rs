@@ -1,6 +1,6 @@
 const RANGE_RE: &str = r"!!(A|B):([0-9]+)\.\.([0-9]+)!!";
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Range {
     pub name: String,
     pub start: i32,
@@ -20,6 +20,11 @@ impl Range {
             end,
         }
     }
+
+    /// The range as it is written in a template, e.g. `!!A:1..4!!`.
+    pub fn placeholder(&self) -> String {
+        format!("!!{}:{}..{}!!", self.name, self.start, self.end)
+    }
 }
 
 // fn contains_named_range(s: &str) -> bool {
@@ -83,4 +88,9 @@ mod range_test {
             vec![Range::new("A", 1, 2), Range::new("B", 3, 4)]
         );
     }
+
+    #[test]
+    fn test_range_placeholder() {
+        assert_eq!(Range::new("A", 1, 4).placeholder(), "!!A:1..4!!");
+    }
 }
@@ -0,0 +1,155 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+lazy_static! {
+    pub static ref RESOURCES: RwLock<Option<NagiosResources>> = RwLock::new(None);
+}
+
+/// The highest `$USERn$` macro supported by Nagios.
+const MAX_USER_MACRO: u32 = 256;
+
+/// The `$USERn$` macros of a Nagios resource file (`resource.cfg`), by name without the dollar
+/// signs. Values may be encrypted with a `+encs+` prefix, the same as environment variables.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NagiosResources {
+    macros: HashMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResourceParseError {
+    InvalidLine(usize, String),
+    InvalidMacro(usize, String),
+}
+
+impl std::error::Error for ResourceParseError {}
+
+impl fmt::Display for ResourceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceParseError::InvalidLine(number, line) => {
+                write!(f, "Invalid line {}: {}", number, line)
+            }
+            ResourceParseError::InvalidMacro(number, name) => write!(
+                f,
+                "Invalid macro on line {}: {}, expected $USER1$ to $USER{}$",
+                number, name, MAX_USER_MACRO
+            ),
+        }
+    }
+}
+
+impl FromStr for NagiosResources {
+    type Err = ResourceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut macros = HashMap::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| ResourceParseError::InvalidLine(number, line.to_string()))?;
+            let name = name.trim();
+            let macro_name = name
+                .strip_prefix('$')
+                .and_then(|n| n.strip_suffix('$'))
+                .filter(|n| is_user_macro(n))
+                .ok_or_else(|| ResourceParseError::InvalidMacro(number, name.to_string()))?;
+
+            macros.insert(macro_name.to_string(), value.trim().to_string());
+        }
+
+        Ok(Self { macros })
+    }
+}
+
+impl NagiosResources {
+    /// The value of a macro, by name without the dollar signs, such as `USER1`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.macros.get(name).map(|v| v.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.macros.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.macros.is_empty()
+    }
+}
+
+/// Whether a variable name is a Nagios `$USERn$` macro.
+pub fn is_user_macro(name: &str) -> bool {
+    name.strip_prefix("USER")
+        .filter(|n| !n.starts_with('0'))
+        .and_then(|n| n.parse::<u32>().ok())
+        .is_some_and(|n| (1..=MAX_USER_MACRO).contains(&n))
+}
+
+/// The value of a `$USERn$` macro from the loaded resource file, if any.
+pub fn resource_value(name: &str) -> Option<String> {
+    if !is_user_macro(name) {
+        return None;
+    }
+    RESOURCES
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|r| r.get(name))
+        .map(|v| v.to_string())
+}
+
+#[cfg(test)]
+mod resource_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_resources_from_str() {
+        let resources = NagiosResources::from_str(
+            "# Sets $USER1$ to be the path to the plugins\n\
+             $USER1$=/usr/local/nagios/libexec\n\
+             \n\
+             $USER3$ = secret password\n",
+        )
+        .unwrap();
+
+        assert_eq!(resources.len(), 2);
+        assert_eq!(resources.get("USER1"), Some("/usr/local/nagios/libexec"));
+        assert_eq!(resources.get("USER3"), Some("secret password"));
+        assert_eq!(resources.get("USER2"), None);
+    }
+
+    #[test]
+    fn test_invalid_resources() {
+        assert_eq!(
+            NagiosResources::from_str("$USER1$=a\nUSER2"),
+            Err(ResourceParseError::InvalidLine(2, "USER2".to_string()))
+        );
+        assert_eq!(
+            NagiosResources::from_str("$HOSTADDRESS$=127.0.0.1"),
+            Err(ResourceParseError::InvalidMacro(
+                1,
+                "$HOSTADDRESS$".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_is_user_macro() {
+        assert!(is_user_macro("USER1"));
+        assert!(is_user_macro("USER256"));
+        assert!(!is_user_macro("USER0"));
+        assert!(!is_user_macro("USER01"));
+        assert!(!is_user_macro("USER257"));
+        assert!(!is_user_macro("USERNAME"));
+    }
+}
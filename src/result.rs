@@ -1,21 +1,41 @@
-use crate::variable::{Variables, VariablesExt};
-use regex::Regex;
-use serde::Serialize;
+use crate::extract::{extract_perfdata, ExtractRules};
+use crate::perfdata::{parse_perfdata, PerfDataError, PerfDatum};
+use crate::process::ResourceUsage;
+use crate::status_map::{map_status, StatusMap};
+use crate::threshold::{Threshold, Thresholds};
+use crate::uom::Unit;
+use crate::variable::{VariableKind, Variables, VariablesExt};
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Debug, Default, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CheckResult {
     name: String,
     command: String,
     secret_command: String,
     status: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_status: Option<i32>,
     short_output: String,
     long_output: String,
     performance_data: String,
+    /// The output in the Nagios plugin format, without the escaping of the CSV columns.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    plugin_output: String,
     execution_time: String,
+    resource_usage: Option<ResourceUsage>,
+    #[serde(skip)]
+    cache_age: Option<Duration>,
+    #[serde(skip)]
+    cache_ttl: Option<Duration>,
     variables_found: Option<Variables>,
     variables_not_found: Option<Variables>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thresholds: Option<Thresholds>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    worst_status: bool,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -28,8 +48,13 @@ pub struct CheckResultBuilder {
     long_output: Option<String>,
     performance_data: Option<String>,
     execution_time: Option<String>,
+    resource_usage: Option<ResourceUsage>,
     variables_found: Option<Variables>,
     variables_not_found: Option<Variables>,
+    thresholds: Option<Thresholds>,
+    worst_status: bool,
+    status_map: Option<StatusMap>,
+    extract: Option<ExtractRules>,
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -37,6 +62,8 @@ pub struct CheckResultBuilder {
 pub struct ProcessedCheckResult {
     name: String,
     status: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_status: Option<String>,
     short_output: String,
     label: Option<String>,
     value: Option<f64>,
@@ -45,20 +72,63 @@ pub struct ProcessedCheckResult {
     crit: Option<String>,
     min: Option<String>,
     max: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalized_value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalized_uom: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalized_warn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalized_crit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalized_min: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalized_max: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threshold_state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threshold_reason: Option<String>,
     command: String,
     performance_data_string: String,
     long_output: String,
     execution_time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_cpu_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_cpu_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_rss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result_age: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stale: Option<String>,
     variables_found: Option<String>,
     variables_not_found: Option<String>,
 }
 
+/// Options for turning check results into rows. Optional columns are only included when asked for.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessOptions {
+    /// Add the userCpuTime, systemCpuTime and maxRss columns.
+    pub resource_usage: bool,
+    /// Add the resultAge and stale columns.
+    pub result_age: bool,
+    /// Add the thresholdState and thresholdReason columns.
+    pub threshold_state: bool,
+    /// Add the originalStatus column.
+    pub original_status: bool,
+    /// Add the normalizedValue, normalizedUom, normalizedWarn, normalizedCrit, normalizedMin
+    /// and normalizedMax columns.
+    pub normalized_units: bool,
+}
+
 pub struct CheckResults(pub Vec<CheckResult>);
 
 pub type ProcessedCheckResults = Vec<ProcessedCheckResult>;
 
 pub trait ProcessedCheckResultsExt {
     fn from_check_result(check_result: &CheckResult) -> Self;
+    fn from_check_result_with(check_result: &CheckResult, options: &ProcessOptions) -> Self;
     fn as_csv_string(&mut self) -> Result<String, Box<dyn std::error::Error>>;
 }
 
@@ -87,10 +157,98 @@ impl CheckResult {
         self.performance_data.to_string()
     }
 
+    /// The short output, performance data and long output in the Nagios plugin format, as
+    /// they were before being escaped for the CSV columns.
+    pub fn plugin_output(&self) -> String {
+        self.plugin_output.to_string()
+    }
+
+    /// The status of the check as shown in its status column. With `worst_status`, it's the
+    /// worst of the status and the states of the metrics with thresholds in the check.
+    pub fn overall_status(&self) -> Option<i32> {
+        self.status_with(&self.perf_entries())
+    }
+
+    /// The entries of the performance data, where the ranges of metrics with thresholds in the
+    /// check are replaced by those thresholds.
+    fn perf_entries(&self) -> Vec<Result<PerfDatum, PerfDataError>> {
+        let mut perf_entries = parse_perfdata(&self.performance_data);
+        if let Some(thresholds) = &self.thresholds {
+            for perf in perf_entries.iter_mut().flatten() {
+                if let Some(t) = thresholds.get(&perf.label) {
+                    perf.warn = t.warn.clone();
+                    perf.crit = t.crit.clone();
+                }
+            }
+        }
+        perf_entries
+    }
+
+    fn status_with(&self, perf_entries: &[Result<PerfDatum, PerfDataError>]) -> Option<i32> {
+        match &self.thresholds {
+            Some(thresholds) if self.worst_status => {
+                worst_status(self.status, perf_entries, thresholds)
+            }
+            _ => self.status,
+        }
+    }
+
     pub fn execution_time(&self) -> String {
         self.execution_time.to_string()
     }
 
+    pub fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.resource_usage
+    }
+
+    /// The status before it was changed by the status map of the check, if it has one.
+    pub fn original_status(&self) -> Option<i32> {
+        self.original_status
+    }
+
+    /// How old the result was when it was taken from the result cache, if it was.
+    pub fn cache_age(&self) -> Option<Duration> {
+        self.cache_age
+    }
+
+    /// Mark the result as taken from the result cache, where it's `cache_age` old and may be
+    /// served for `cache_ttl`, the interval of the check.
+    pub fn with_cache_age(mut self, cache_age: Duration, cache_ttl: Duration) -> Self {
+        self.cache_age = Some(cache_age);
+        self.cache_ttl = Some(cache_ttl);
+        self
+    }
+
+    /// Whether the result was taken from the result cache when it was older than it may be
+    /// served for.
+    pub fn is_stale(&self) -> bool {
+        match (self.cache_age, self.cache_ttl) {
+            (Some(age), Some(ttl)) => age > ttl,
+            _ => false,
+        }
+    }
+
+    /// A copy of the result that is safe to write to disk, without any decrypted values.
+    pub fn without_secrets(&self) -> Self {
+        let strip = |vars: &Option<Variables>| {
+            vars.as_ref().map(|vars| {
+                vars.iter()
+                    .map(|v| match v {
+                        VariableKind::Public(v) => VariableKind::Public(v.without_secret_value()),
+                        VariableKind::Secret(v) => VariableKind::Secret(v.without_secret_value()),
+                    })
+                    .collect::<Variables>()
+            })
+        };
+
+        Self {
+            secret_command: self.command.clone(),
+            variables_found: strip(&self.variables_found),
+            variables_not_found: strip(&self.variables_not_found),
+            ..self.clone()
+        }
+    }
+
     pub fn variables_found(&self) -> Option<String> {
         match self.variables_found {
             Some(ref vars) if !vars.is_empty() => Some(escape_chars(&vars.to_string())),
@@ -156,6 +314,32 @@ impl CheckResultBuilder {
         self
     }
 
+    /// Thresholds by label that replace the warn and crit ranges printed by the plugin.
+    pub fn thresholds(mut self, thresholds: &Thresholds) -> Self {
+        self.thresholds = Some(thresholds.clone());
+        self
+    }
+
+    /// Make the status the worst of the exit code and the states of the metrics with
+    /// thresholds.
+    pub fn worst_status(mut self, worst_status: bool) -> Self {
+        self.worst_status = worst_status;
+        self
+    }
+
+    /// Rules that change the status when the result is built.
+    pub fn status_map(mut self, status_map: &StatusMap) -> Self {
+        self.status_map = Some(status_map.clone());
+        self
+    }
+
+    /// Rules that add metrics for the text they match in the short and long output when the
+    /// result is built.
+    pub fn extract(mut self, rules: &ExtractRules) -> Self {
+        self.extract = Some(rules.clone());
+        self
+    }
+
     pub fn parse_output(mut self, output: &str) -> Self {
         self.short_output = Some(extract_short_output(output));
         self.long_output = Some(extract_long_output(output));
@@ -169,7 +353,57 @@ impl CheckResultBuilder {
         self
     }
 
-    pub fn build(self) -> CheckResult {
+    pub fn with_resource_usage(mut self, resource_usage: ResourceUsage) -> Self {
+        self.resource_usage = Some(resource_usage);
+        self
+    }
+
+    pub fn build(mut self) -> CheckResult {
+        if let Some(rules) = &self.extract {
+            let text = format!(
+                "{}\n{}",
+                self.short_output.as_deref().unwrap_or_default(),
+                self.long_output.as_deref().unwrap_or_default()
+            );
+            let extracted = extract_perfdata(rules, &text)
+                .iter()
+                .map(|perf| perf.to_string())
+                .collect::<Vec<_>>();
+            if !extracted.is_empty() {
+                let perf = self.performance_data.take().unwrap_or_default();
+                self.performance_data = Some(
+                    std::iter::once(perf)
+                        .filter(|p| !p.is_empty())
+                        .chain(extracted)
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                );
+            }
+        }
+
+        let (status, original_status) = match &self.status_map {
+            Some(status_map) => (
+                map_status(
+                    status_map,
+                    self.status,
+                    self.short_output.as_deref().unwrap_or_default(),
+                ),
+                self.status,
+            ),
+            None => (self.status, None),
+        };
+
+        let short_output = self.short_output.unwrap_or_default();
+        let long_output = self.long_output.unwrap_or_default();
+        let performance_data = self.performance_data.unwrap_or_default();
+        let mut plugin_output = short_output.clone();
+        if !performance_data.is_empty() {
+            plugin_output = format!("{}|{}", plugin_output, performance_data);
+        }
+        if !long_output.is_empty() {
+            plugin_output = format!("{}\n{}", plugin_output, long_output);
+        }
+
         CheckResult {
             name: escape_chars(&self.name.unwrap_or_default()),
             command: escape_chars(&self.command.clone().unwrap_or_default()),
@@ -177,70 +411,100 @@ impl CheckResultBuilder {
                 Some(s) => escape_chars(s),
                 None => self.command.unwrap_or_default(),
             },
-            status: self.status,
-            short_output: escape_chars(&self.short_output.unwrap_or_default()),
-            long_output: escape_chars(&self.long_output.unwrap_or_default()),
-            performance_data: self.performance_data.unwrap_or_default(),
+            status,
+            original_status,
+            short_output: escape_chars(&short_output),
+            long_output: escape_chars(&long_output),
+            performance_data,
+            plugin_output,
             execution_time: self.execution_time.unwrap_or_default(),
+            resource_usage: self.resource_usage,
+            cache_age: None,
+            cache_ttl: None,
             variables_found: self.variables_found,
             variables_not_found: self.variables_not_found,
+            thresholds: self.thresholds,
+            worst_status: self.worst_status,
         }
     }
 }
 
 impl CheckResults {
     pub fn process(&self) -> ProcessedCheckResults {
+        self.process_with(&ProcessOptions::default())
+    }
+
+    pub fn process_with(&self, options: &ProcessOptions) -> ProcessedCheckResults {
         let mut processed_results = ProcessedCheckResults::with_capacity(self.0.len());
         for r in self.0.iter() {
-            processed_results.extend(ProcessedCheckResults::from_check_result(r));
+            processed_results.extend(ProcessedCheckResults::from_check_result_with(r, options));
         }
         processed_results
     }
+
+    /// The resources used by all checks together.
+    pub fn total_resource_usage(&self) -> ResourceUsage {
+        ResourceUsage::total(self.0.iter().filter_map(|r| r.resource_usage.as_ref()))
+    }
 }
 
 impl ProcessedCheckResult {
-    fn add_performance_data(mut self, perf: &str) -> Self {
-        self.label = label(perf);
-        self.value = value(perf);
-        self.uom = uom(perf);
-        self.warn = warn(perf);
-        self.crit = crit(perf);
-        self.min = min(perf);
-        self.max = max(perf);
+    fn add_performance_data(mut self, perf: &PerfDatum) -> Self {
+        self.label = Some(escape_chars(&perf.label));
+        self.value = perf.value;
+        self.uom = perf.uom.clone();
+        self.warn = perf.warn.clone();
+        self.crit = perf.crit.clone();
+        self.min = perf.min.map(|m| m.to_string());
+        self.max = perf.max.map(|m| m.to_string());
+
+        // Metrics in an unknown unit are passed through as they are.
+        let unit = perf.uom.as_deref().and_then(Unit::from_uom);
+        let normalize = |v: f64| unit.map_or(v, |u| u.normalize(v)).to_string();
+        let normalize_range = |range: &String| match unit {
+            Some(u) => u.normalize_range(range),
+            None => Some(range.clone()),
+        };
+        self.normalized_value = perf.value.map(normalize);
+        self.normalized_uom = unit.map(|u| u.base.to_string()).or(perf.uom.clone());
+        self.normalized_warn = perf.warn.as_ref().and_then(normalize_range);
+        self.normalized_crit = perf.crit.as_ref().and_then(normalize_range);
+        self.normalized_min = perf.min.map(normalize);
+        self.normalized_max = perf.max.map(normalize);
         self
     }
 
-    fn status_from_perfdata(mut self) -> Self {
-        if self.status.is_some() {
-            return self;
+    /// An entry that couldn't be parsed gets a row of its own, with the reason as its output.
+    fn add_invalid_performance_data(mut self, error: &PerfDataError) -> Self {
+        self.label = Some(escape_chars(error.entry()));
+        self.short_output = escape_chars(&error.to_string());
+        self
+    }
+
+    fn add_perf_entry(self, entry: &Result<PerfDatum, PerfDataError>) -> Self {
+        match entry {
+            Ok(perf) => self.add_performance_data(perf).status_from_perfdata(),
+            Err(e) => self.add_invalid_performance_data(e),
         }
+    }
 
-        if self.value.is_none() {
+    /// Evaluate the value against the crit and warn ranges. The state and the reason are set
+    /// on the row, and the status as well when the row has none yet. A range that can't be
+    /// parsed makes the metric UNKNOWN.
+    fn status_from_perfdata(mut self) -> Self {
+        let Some(value) = self.value else {
             return self;
-        }
+        };
 
-        if let Some(c) = self.crit.as_ref() {
-            if perfdata::ThresholdRange::from_str(c.as_str())
-                .unwrap()
-                .is_alert(self.value.unwrap())
-            {
-                self.status = Some(2);
-                return self;
-            }
+        let (status, reason) = evaluate_thresholds(value, &self.warn, &self.crit);
+        if self.warn.is_some() || self.crit.is_some() {
+            self.threshold_state = Some(status_name(status).to_string());
+            self.threshold_reason = reason.map(|r| escape_chars(&r));
         }
-
-        if let Some(w) = self.warn.as_ref() {
-            if perfdata::ThresholdRange::from_str(w.as_str())
-                .unwrap()
-                .is_alert(self.value.unwrap())
-            {
-                self.status = Some(1);
-                return self;
-            }
+        if self.status.is_none() {
+            self.status = Some(status);
         }
 
-        self.status = Some(0);
-
         self
     }
 
@@ -265,38 +529,113 @@ impl ProcessedCheckResult {
             ..ProcessedCheckResult::default()
         }
     }
+
+    fn with_options(
+        mut self,
+        check_result: Option<&CheckResult>,
+        options: &ProcessOptions,
+    ) -> Self {
+        if options.resource_usage {
+            let usage = check_result.and_then(|r| r.resource_usage());
+            self.user_cpu_time = Some(
+                usage
+                    .map(|u| format_cpu_time(u.user_cpu))
+                    .unwrap_or_default(),
+            );
+            self.system_cpu_time = Some(
+                usage
+                    .map(|u| format_cpu_time(u.system_cpu))
+                    .unwrap_or_default(),
+            );
+            self.max_rss = Some(usage.map(|u| format_max_rss(u.max_rss)).unwrap_or_default());
+        }
+        if options.original_status {
+            self.original_status = Some(
+                check_result
+                    .and_then(|r| r.original_status())
+                    .map(|s| s.to_string())
+                    .unwrap_or_default(),
+            );
+        }
+        if options.normalized_units {
+            for column in [
+                &mut self.normalized_value,
+                &mut self.normalized_uom,
+                &mut self.normalized_warn,
+                &mut self.normalized_crit,
+                &mut self.normalized_min,
+                &mut self.normalized_max,
+            ] {
+                *column = Some(column.take().unwrap_or_default());
+            }
+        } else {
+            self.normalized_value = None;
+            self.normalized_uom = None;
+            self.normalized_warn = None;
+            self.normalized_crit = None;
+            self.normalized_min = None;
+            self.normalized_max = None;
+        }
+        if options.threshold_state {
+            self.threshold_state = Some(self.threshold_state.unwrap_or_default());
+            self.threshold_reason = Some(self.threshold_reason.unwrap_or_default());
+        } else {
+            self.threshold_state = None;
+            self.threshold_reason = None;
+        }
+        if options.result_age {
+            match check_result {
+                Some(r) => {
+                    let age = r.cache_age().unwrap_or_default();
+                    self.result_age = Some(format!("{} s", age.as_secs()));
+                    self.stale = Some(r.is_stale().to_string());
+                }
+                None => {
+                    self.result_age = Some(String::new());
+                    self.stale = Some(String::new());
+                }
+            }
+        }
+        self
+    }
 }
 
 impl ProcessedCheckResultsExt for ProcessedCheckResults {
     fn from_check_result(check_result: &CheckResult) -> Self {
-        let perf_binding = check_result.performance_data();
-        let perf_metrics: Vec<String> = shellwords::split(&perf_binding)
-            .unwrap_or_else(|_| panic!("Invalid perfdata, unable to split: '{}'", &perf_binding));
-        let perf_count = perf_metrics.len();
-        let mut results = ProcessedCheckResults::with_capacity(perf_count + 1);
-
-        // Special handling for 0 or 1 performance metric
-        if perf_count < 2 {
-            let mut c = ProcessedCheckResult::main_entry_from_check_result(check_result);
-            if perf_count == 1 {
-                c = c.add_performance_data(&perf_metrics[0]);
-                c = c.status_from_perfdata();
-            }
-            results.push(c);
+        Self::from_check_result_with(check_result, &ProcessOptions::default())
+    }
+
+    fn from_check_result_with(check_result: &CheckResult, options: &ProcessOptions) -> Self {
+        let perf_entries = check_result.perf_entries();
+        let mut main_entry = ProcessedCheckResult::main_entry_from_check_result(check_result);
+        main_entry.status = check_result.status_with(&perf_entries);
+
+        let mut results = ProcessedCheckResults::with_capacity(perf_entries.len() + 1);
+
+        // Special handling for a single valid performance metric
+        if let [Ok(perf)] = perf_entries.as_slice() {
+            results.push(
+                main_entry
+                    .add_performance_data(perf)
+                    .status_from_perfdata()
+                    .with_options(Some(check_result), options),
+            );
             return results;
         }
 
-        // Handle 2+ performance metrics
-        results.push(ProcessedCheckResult::main_entry_from_check_result(
-            check_result,
-        ));
-
-        for p in &perf_metrics {
-            let label = label(p).unwrap_or_default();
-            let mut entry =
-                ProcessedCheckResult::secondary_entry_from_check_result(check_result, &label);
-            entry = entry.add_performance_data(p).status_from_perfdata();
-            results.push(entry);
+        // Handle 0 or 2+ performance metrics, or metrics that couldn't be parsed
+        results.push(main_entry.with_options(Some(check_result), options));
+
+        for entry in &perf_entries {
+            let label = match entry {
+                Ok(perf) => escape_chars(&perf.label),
+                Err(e) => escape_chars(e.entry()),
+            };
+            results.push(
+                ProcessedCheckResult::secondary_entry_from_check_result(check_result, &label)
+                    .add_perf_entry(entry)
+                    .with_options(None, options),
+            );
         }
 
         results
@@ -318,60 +657,77 @@ impl ProcessedCheckResultsExt for ProcessedCheckResults {
     }
 }
 
-fn label(perf: &str) -> Option<String> {
-    Regex::new("([^=]+)=([^=]+)")
-        .unwrap()
-        .captures(perf)
-        .and_then(|cap| cap.get(1))
-        .map(|m| m.as_str().trim_matches('\'').to_string())
-}
-
-fn value(perf: &str) -> Option<f64> {
-    Regex::new("([^=;]+)=?([0-9.]+)")
-        .unwrap()
-        .captures(perf)
-        .and_then(|cap| cap.get(2))
-        .and_then(|m| f64::from_str(m.as_str()).ok())
-}
-
-fn uom(perf: &str) -> Option<String> {
-    Regex::new("([0-9'=]+)([^0-9'=; ]+)([ ;]|$)")
-        .unwrap()
-        .captures(perf)
-        .and_then(|cap| cap.get(2))
-        .map(|m| m.as_str().to_string())
+/// The name of a plugin status, where anything but 0 to 2 is UNKNOWN.
+pub(crate) fn status_name(status: i32) -> &'static str {
+    match status {
+        0 => "OK",
+        1 => "WARNING",
+        2 => "CRITICAL",
+        _ => "UNKNOWN",
+    }
 }
 
-fn warn(perf: &str) -> Option<String> {
-    Regex::new("([^;]*;)([^;]+)(.*)")
-        .unwrap()
-        .captures(perf)
-        .and_then(|cap| cap.get(2))
-        .map(|m| m.as_str().to_string())
+/// The worst of the status and the states of the metrics with thresholds, where CRITICAL is
+/// worse than WARNING, which is worse than UNKNOWN.
+fn worst_status(
+    status: Option<i32>,
+    perf_entries: &[Result<PerfDatum, PerfDataError>],
+    thresholds: &Thresholds,
+) -> Option<i32> {
+    let severity = |status: &i32| match status {
+        0 => 0,
+        2 => 3,
+        1 => 2,
+        _ => 1,
+    };
+    perf_entries
+        .iter()
+        .flatten()
+        .filter(|perf| thresholds.contains_key(&perf.label))
+        .filter_map(|perf| {
+            perf.value
+                .map(|value| evaluate_thresholds(value, &perf.warn, &perf.crit).0)
+        })
+        .chain(status)
+        .max_by_key(severity)
 }
 
-fn crit(perf: &str) -> Option<String> {
-    Regex::new("([^;]*;)([^;]+;)([^;]+)(.*)")
-        .unwrap()
-        .captures(perf)
-        .and_then(|cap| cap.get(3))
-        .map(|m| m.as_str().to_string())
+/// The status of a value for the warn and crit ranges, and why it isn't OK.
+fn evaluate_thresholds(
+    value: f64,
+    warn: &Option<String>,
+    crit: &Option<String>,
+) -> (i32, Option<String>) {
+    for (range, name, status) in [(crit, "crit", 2), (warn, "warn", 1)] {
+        let Some(range) = range else {
+            continue;
+        };
+        match Threshold::from_str(range) {
+            Ok(threshold) => {
+                if let Some(reason) = threshold.reason(value, name) {
+                    return (status, Some(reason));
+                }
+            }
+            Err(e) => {
+                return (
+                    3,
+                    Some(format!(
+                        "Invalid {} range '{}': {}",
+                        name, e.range, e.reason
+                    )),
+                )
+            }
+        }
+    }
+    (0, None)
 }
 
-fn min(perf: &str) -> Option<String> {
-    Regex::new("([^;]*;)([^;]+;)([^;]+;)([^;]+)(.*)")
-        .unwrap()
-        .captures(perf)
-        .and_then(|cap| cap.get(4))
-        .map(|m| m.as_str().to_string())
+pub fn format_cpu_time(cpu_time: std::time::Duration) -> String {
+    format!("{:.4} s", cpu_time.as_secs_f64())
 }
 
-fn max(perf: &str) -> Option<String> {
-    Regex::new("([^;]*;)([^;]+;)([^;]+;)([^;]+;)([^;]+)(.*)")
-        .unwrap()
-        .captures(perf)
-        .and_then(|cap| cap.get(5))
-        .map(|m| m.as_str().to_string())
+pub fn format_max_rss(max_rss: u64) -> String {
+    format!("{} KiB", max_rss)
 }
 
 fn escape_commas(s: &str) -> String {
@@ -382,7 +738,7 @@ fn escape_newlines(s: &str) -> String {
     s.replace('\n', "\\n")
 }
 
-fn escape_chars(s: &str) -> String {
+pub(crate) fn escape_chars(s: &str) -> String {
     escape_newlines(&escape_commas(s))
 }
 
@@ -402,27 +758,48 @@ fn extract_short_output(output: &str) -> String {
     }
 }
 
-// Admittedly a simplified version which doesn't handle multi-line performance data mixed with the
-// output.
+/// The lines after the first, up to the first `|`, which starts the performance data that may
+/// continue over the remaining lines in the plugin API 3.x format.
 fn extract_long_output(output: &str) -> String {
-    output
-        .lines()
-        .skip(1)
-        .collect::<Vec<&str>>()
-        .join("\n")
+    let long = output
+        .split_once('\n')
+        .map(|(_, rest)| rest)
+        .unwrap_or_default();
+    long.split('|')
+        .next()
+        .unwrap_or_default()
         .trim()
         .to_string()
 }
 
+/// The performance data after the `|` on the first line, followed by the performance data after
+/// the first `|` in the long output, as Nagios core combines them.
 fn extract_performance_data(output: &str) -> Option<String> {
     if output.contains("Usage: check")
         || output.contains("[-h|--help]")
         || output.contains("usage: check")
     {
-        None
-    } else {
-        output.find('|').map(|i| output[i + 1..].trim().to_string())
-    }
+        return None;
+    }
+
+    let (first_line, long) = output.split_once('\n').unwrap_or((output, ""));
+    let segments = [first_line, long]
+        .into_iter()
+        .filter_map(|s| s.split_once('|').map(|(_, perf)| perf))
+        .collect::<Vec<_>>();
+    if segments.is_empty() {
+        return None;
+    }
+
+    Some(
+        segments
+            .iter()
+            .flat_map(|s| s.lines())
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
 }
 
 #[cfg(test)]
@@ -432,9 +809,135 @@ mod util_test {
 
     #[test]
     fn test_perf_label() {
-        assert_eq!(label("label=foo"), Some("label".to_string()));
-        assert_eq!(label("'split label'=foo"), Some("split label".to_string()));
+        let label = |perf: &str| {
+            ProcessedCheckResult::default()
+                .add_perf_entry(&perf.parse::<PerfDatum>())
+                .label
+        };
+        assert_eq!(label("label=1"), Some("label".to_string()));
+        assert_eq!(
+            label("'split label'=foo"),
+            Some("'split label'=foo".to_string())
+        );
         assert_eq!(label("'Load Average'=1"), Some("Load Average".to_string()));
+        assert_eq!(label("'a,b'=1"), Some("a\\,b".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_perfdata_rows() {
+        let result = CheckResultBuilder::new()
+            .name("load")
+            .status(0)
+            .parse_output("LOAD OK|load1=0,5;5;10 load5=high")
+            .build();
+
+        let rows = ProcessedCheckResults::from_check_result(&result);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1].name, "\tload#load1");
+        assert_eq!(rows[1].value, Some(0.5));
+        assert_eq!(rows[1].warn, Some("5".to_string()));
+        assert_eq!(rows[1].status, Some(0));
+        assert_eq!(rows[2].name, "\tload#load5=high");
+        assert_eq!(
+            rows[2].short_output,
+            "Invalid perfdata load5=high: the value is not a number"
+        );
+        assert_eq!(rows[2].status, None);
+    }
+
+    #[test]
+    fn test_threshold_columns() {
+        let result = CheckResultBuilder::new()
+            .name("disk")
+            .status(2)
+            .parse_output("DISK CRITICAL|used=93%;80;90 free=7%;20:10;@0:5 inodes=10 temp=40;@~:50")
+            .build();
+
+        let options = ProcessOptions {
+            threshold_state: true,
+            ..ProcessOptions::default()
+        };
+        let rows = ProcessedCheckResults::from_check_result_with(&result, &options);
+        let columns = |row: &ProcessedCheckResult| {
+            (
+                row.status,
+                row.threshold_state.clone().unwrap_or_default(),
+                row.threshold_reason.clone().unwrap_or_default(),
+            )
+        };
+        assert_eq!(columns(&rows[0]), (Some(2), String::new(), String::new()));
+        assert_eq!(
+            columns(&rows[1]),
+            (
+                Some(2),
+                "CRITICAL".to_string(),
+                "value 93 > crit 90".to_string()
+            )
+        );
+        assert_eq!(
+            columns(&rows[2]),
+            (
+                Some(3),
+                "UNKNOWN".to_string(),
+                "Invalid warn range '20:10': the start is greater than the end".to_string()
+            )
+        );
+        assert_eq!(columns(&rows[3]), (Some(0), String::new(), String::new()));
+        assert_eq!(
+            columns(&rows[4]),
+            (
+                Some(1),
+                "WARNING".to_string(),
+                "value 40 inside warn @~:50".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_threshold_overrides() {
+        let thresholds = Thresholds::from([(
+            "load1".to_string(),
+            crate::threshold::MetricThresholds {
+                warn: Some("5".to_string()),
+                crit: Some("10".to_string()),
+            },
+        )]);
+        let builder = CheckResultBuilder::new()
+            .name("load")
+            .status(0)
+            .parse_output("LOAD OK|load1=7;15;20 load5=30;15;20")
+            .thresholds(&thresholds);
+
+        let rows = ProcessedCheckResults::from_check_result(&builder.clone().build());
+        assert_eq!(rows[0].status, Some(0));
+        assert_eq!(rows[1].warn, Some("5".to_string()));
+        assert_eq!(rows[1].crit, Some("10".to_string()));
+        assert_eq!(rows[1].status, Some(1));
+        assert_eq!(rows[2].crit, Some("20".to_string()));
+        assert_eq!(rows[2].status, Some(2));
+
+        // Only the metrics with thresholds in the check count towards the worst status.
+        let rows = ProcessedCheckResults::from_check_result(&builder.worst_status(true).build());
+        assert_eq!(rows[0].status, Some(1));
+    }
+
+    #[test]
+    fn test_worst_status() {
+        let thresholds = Thresholds::from([
+            ("a".to_string(), Default::default()),
+            ("b".to_string(), Default::default()),
+        ]);
+        let perf = |data: &str| parse_perfdata(data);
+
+        assert_eq!(
+            worst_status(Some(0), &perf("a=1;0 b=1"), &thresholds),
+            Some(1)
+        );
+        assert_eq!(worst_status(Some(3), &perf("a=1;0"), &thresholds), Some(1));
+        assert_eq!(worst_status(Some(3), &perf("a=1;;0"), &thresholds), Some(2));
+        assert_eq!(worst_status(Some(2), &perf("a=1;0"), &thresholds), Some(2));
+        assert_eq!(worst_status(Some(0), &perf("c=1;0"), &thresholds), Some(0));
+        assert_eq!(worst_status(None, &perf("a=1"), &thresholds), Some(0));
     }
 
     #[test]
@@ -472,16 +975,36 @@ mod util_test {
         assert_eq!(extract_long_output("hello"), "");
         assert_eq!(extract_long_output("hello\nworld"), "world");
         assert_eq!(extract_long_output("hello\nworld\n"), "world");
-        assert_eq!(extract_long_output("hello\nworld|foo=1\n"), "world|foo=1");
+        assert_eq!(extract_long_output("hello\nworld|foo=1\n"), "world");
+        assert_eq!(extract_long_output("hello\nworld|foo=1;;;\n"), "world");
+        assert_eq!(extract_long_output("hello\nworld\n|"), "world");
+        assert_eq!(extract_long_output("hello\nworld\n|foo"), "world");
+        assert_eq!(extract_long_output("hello|a=1\nworld\n|foo|bar"), "world");
+        assert_eq!(
+            extract_long_output("hello\nline 1\nline 2|foo=1\nbar=2"),
+            "line 1\nline 2"
+        );
+    }
+
+    #[test]
+    fn test_extract_performance_data() {
+        assert_eq!(extract_performance_data("hello"), None);
+        assert_eq!(extract_performance_data("hello\nworld"), None);
+        assert_eq!(
+            extract_performance_data("hello|a=1\nworld"),
+            Some("a=1".to_string())
+        );
+        assert_eq!(
+            extract_performance_data("hello\nworld|b=2\nc=3\n"),
+            Some("b=2 c=3".to_string())
+        );
         assert_eq!(
-            extract_long_output("hello\nworld|foo=1;;;\n"),
-            "world|foo=1;;;"
+            extract_performance_data("DISK OK|a=1 b=2\nline 1\nline 2|c=3\nd=4;5;6\n"),
+            Some("a=1 b=2 c=3 d=4;5;6".to_string())
         );
-        assert_eq!(extract_long_output("hello\nworld\n|"), "world\n|");
-        assert_eq!(extract_long_output("hello\nworld\n|foo"), "world\n|foo");
         assert_eq!(
-            extract_long_output("hello\nworld\n|foo|bar"),
-            "world\n|foo|bar"
+            extract_performance_data("Usage: check_foo [-h|--help]"),
+            None
         );
     }
 }
@@ -0,0 +1,356 @@
+//! Remote execution through the system `ssh` client, like `check_by_ssh`. The command of the
+//! check is not passed to `ssh` as an argument, but written to the standard input of a shell on
+//! the remote host, so that decrypted secrets never show up in the local process list.
+
+use crate::check::TimeoutMessage;
+use crate::executor::{collect_output, CollectedOutput, Executor, ExecutorOutput, Invocation};
+use crate::process::ChildProcess;
+use crate::variable::shell_quote;
+use futures::future::BoxFuture;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::process::{Command, Stdio};
+
+pub const SSH_EXECUTOR: &str = "ssh";
+pub const DEFAULT_SSH_PROGRAM: &str = "ssh";
+/// The exit status of `ssh` when it failed to connect or authenticate, rather than the status
+/// of the remote command.
+const SSH_ERROR_STATUS: i32 = 255;
+/// The shell on the remote host that reads the command from its standard input.
+const REMOTE_SHELL: &str = "/bin/sh";
+
+/// The remote host a check runs on, and how to log in to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SshTarget {
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// The private key to log in with, passed to `ssh -i`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Options passed to `ssh -o`, such as `StrictHostKeyChecking=accept-new`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl SshTarget {
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn key(mut self, key: &str) -> Self {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    pub fn option(mut self, option: &str) -> Self {
+        self.options.push(option.to_string());
+        self
+    }
+
+    /// The arguments of `ssh`, which never contain the command. Password prompts are disabled,
+    /// and connecting may take at most the timeout of the check.
+    pub fn ssh_args(&self, timeout_secs: u64) -> Vec<String> {
+        let mut args = vec![
+            "-T".to_string(),
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-o".to_string(),
+            format!("ConnectTimeout={}", timeout_secs.max(1)),
+        ];
+        if let Some(user) = &self.user {
+            args.extend(["-l".to_string(), user.clone()]);
+        }
+        if let Some(port) = self.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(key) = &self.key {
+            args.extend(["-i".to_string(), key.clone()]);
+        }
+        for option in &self.options {
+            args.extend(["-o".to_string(), option.clone()]);
+        }
+        args.extend([
+            "--".to_string(),
+            self.host.clone(),
+            REMOTE_SHELL.to_string(),
+        ]);
+        args
+    }
+}
+
+/// The script run by the remote shell. It exports the environment of the invocation, and kills
+/// the command when the timeout of the check has passed, since killing the local `ssh` doesn't
+/// stop it.
+pub fn remote_script(invocation: &Invocation) -> String {
+    let mut script = String::new();
+    for (name, value) in &invocation.env {
+        script.push_str(&format!("export {}={}\n", name, shell_quote(value)));
+    }
+    let command = invocation
+        .argv
+        .iter()
+        .map(|word| shell_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ");
+    script.push_str(&format!(
+        "{} </dev/null &\n\
+         pid=$!\n\
+         (sleep {}; kill -9 $pid) >/dev/null 2>&1 &\n\
+         watchdog=$!\n\
+         wait $pid\n\
+         status=$?\n\
+         kill $watchdog >/dev/null 2>&1\n\
+         exit $status\n",
+        command,
+        invocation.timeout.as_secs().max(1)
+    ));
+    script
+}
+
+/// Runs the command of a check on the remote host of the check with the system `ssh` client.
+/// The status of the remote command is the status of the check, and its output is parsed as
+/// plugin output.
+#[derive(Clone, Debug)]
+pub struct SshExecutor {
+    program: String,
+}
+
+impl Default for SshExecutor {
+    fn default() -> Self {
+        Self::new(DEFAULT_SSH_PROGRAM)
+    }
+}
+
+impl SshExecutor {
+    /// An executor running the given `ssh` program instead of the one in the `PATH`.
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_string(),
+        }
+    }
+}
+
+impl Executor for SshExecutor {
+    fn execute<'a>(&'a self, invocation: &'a Invocation) -> BoxFuture<'a, ExecutorOutput> {
+        Box::pin(run_ssh(&self.program, invocation))
+    }
+}
+
+async fn run_ssh(program: &str, invocation: &Invocation) -> ExecutorOutput {
+    let Some(target) = &invocation.ssh else {
+        return ExecutorOutput::message(3, "SSH UNKNOWN - The check has no remote host");
+    };
+
+    let mut command = Command::new(program);
+    command
+        .args(target.ssh_args(invocation.timeout.as_secs()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    debug!(
+        "Running {} on {} through {}",
+        invocation.name, target.host, program
+    );
+
+    let script = remote_script(invocation);
+    let collected = match ChildProcess::spawn(&mut command) {
+        Ok(mut child) => {
+            collect_output(&mut child, Some(script.as_bytes()), invocation.timeout).await
+        }
+        Err(e) => Err(e),
+    };
+
+    let CollectedOutput {
+        status,
+        stdout,
+        stderr,
+        ..
+    } = match collected {
+        Ok(CollectedOutput {
+            timed_out: true, ..
+        }) => {
+            return ExecutorOutput::message(
+                3,
+                &TimeoutMessage::from(invocation.timeout).to_string(),
+            );
+        }
+        Ok(collected) => collected,
+        Err(e) => {
+            debug!("Failed to execute {}: {}", program, e);
+            return ExecutorOutput::message(
+                3,
+                &format!("SSH UNKNOWN - Failed to execute {}: {}", program, e),
+            );
+        }
+    };
+
+    match status.code() {
+        Some(code @ 0..=3) => ExecutorOutput::plugin(code, &stdout),
+        Some(SSH_ERROR_STATUS) => ExecutorOutput::message(
+            3,
+            &format!(
+                "SSH UNKNOWN - Failed to run the command on {}: {}",
+                target.host,
+                first_line(&stderr)
+            ),
+        ),
+        Some(code) => ExecutorOutput::message(
+            3,
+            &format!(
+                "SSH UNKNOWN - The remote command exited with status {}: {}",
+                code,
+                first_line(&stderr)
+            ),
+        )
+        .with_long_output(stdout.trim()),
+        None => ExecutorOutput::message(3, "SSH UNKNOWN - ssh was killed by a signal"),
+    }
+}
+
+fn first_line(s: &str) -> &str {
+    s.lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("no error message")
+}
+
+#[cfg(test)]
+mod ssh_test {
+    use super::*;
+    use crate::check::CheckBuilder;
+    use crate::executor::register_executor;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    /// A stand-in for `ssh` that records its arguments and runs the script locally.
+    fn fake_ssh(dir: &std::path::Path, status: Option<i32>) -> String {
+        let path = dir.join("ssh");
+        let body = match status {
+            Some(status) => format!(
+                "echo 'ssh: connect to host: Connection refused' >&2\nexit {}",
+                status
+            ),
+            None => "exec /bin/sh".to_string(),
+        };
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\necho \"$@\" > \"$(dirname \"$0\")/args\"\n{}\n",
+                body
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_ssh_args() {
+        let target = SshTarget::new("db1.example.com")
+            .user("nagios")
+            .port(2222)
+            .key("/etc/xtender/id_ed25519")
+            .option("StrictHostKeyChecking=accept-new");
+
+        assert_eq!(
+            target.ssh_args(10).join(" "),
+            "-T -o BatchMode=yes -o ConnectTimeout=10 -l nagios -p 2222 \
+             -i /etc/xtender/id_ed25519 -o StrictHostKeyChecking=accept-new \
+             -- db1.example.com /bin/sh"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_runs_command_through_ssh() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        register_executor("fake-ssh", SshExecutor::new(&fake_ssh(dir.path(), None)));
+
+        let result = CheckBuilder::new()
+            .name("remote")
+            .command(
+                "sh -c 'echo \"LOAD WARNING - $1 on $NAGIOS_CHECK_NAME|load=5\"; exit 1' sh secret",
+            )
+            .ssh(SshTarget::new("db1.example.com").user("nagios"))
+            .executor("fake-ssh")
+            .timeout(5)
+            .build()?
+            .run_async()
+            .await;
+
+        assert_eq!(result.status(), Some(1));
+        assert_eq!(result.short_output(), "LOAD WARNING - secret on remote");
+        assert_eq!(result.performance_data(), "load=5");
+        let args = std::fs::read_to_string(dir.path().join("args"))?;
+        assert!(args.contains("-l nagios"));
+        assert!(!args.contains("secret"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ssh_errors_are_unknown() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        register_executor(
+            "failing-ssh",
+            SshExecutor::new(&fake_ssh(dir.path(), Some(255))),
+        );
+
+        let result = CheckBuilder::new()
+            .name("remote")
+            .command("true")
+            .ssh(SshTarget::new("db1.example.com"))
+            .executor("failing-ssh")
+            .build_raw()
+            .run_async()
+            .await;
+
+        assert_eq!(result.status(), Some(3));
+        assert_eq!(
+            result.short_output(),
+            "SSH UNKNOWN - Failed to run the command on db1.example.com: \
+             ssh: connect to host: Connection refused"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_command_is_killed_after_timeout() {
+        let invocation = Invocation {
+            name: "sleeping".to_string(),
+            argv: vec!["sleep".to_string(), "10".to_string()],
+            timeout: Duration::from_secs(1),
+            limits: Default::default(),
+            env: vec![],
+            ssh: Some(SshTarget::new("localhost")),
+        };
+
+        let start = std::time::Instant::now();
+        let output = tokio::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(remote_script(&invocation))
+            .output()
+            .await
+            .unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(output.status.code(), Some(137));
+    }
+}
@@ -0,0 +1,146 @@
+//! Rules that change the status of a check, for plugins whose exit codes don't mean what they
+//! should, such as a legacy script exiting with 1 on a critical problem. The first rule that
+//! matches the exit code and the short output of the check gives its status.
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// A regular expression matched against the output of a check.
+#[derive(Clone, Debug)]
+pub struct OutputPattern(Regex);
+
+impl OutputPattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern).map(Self)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn regex(&self) -> &Regex {
+        &self.0
+    }
+
+    pub fn is_match(&self, output: &str) -> bool {
+        self.0.is_match(output)
+    }
+}
+
+impl PartialEq for OutputPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for OutputPattern {}
+
+impl Serialize for OutputPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for OutputPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Self::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Give the check the status when both its exit code and its short output match, if given.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputPattern>,
+    pub status: i32,
+}
+
+pub type StatusMap = Vec<StatusRule>;
+
+impl StatusRule {
+    pub fn matches(&self, status: Option<i32>, short_output: &str) -> bool {
+        self.exit.is_none_or(|exit| status == Some(exit))
+            && self
+                .output
+                .as_ref()
+                .is_none_or(|pattern| pattern.is_match(short_output))
+    }
+}
+
+/// The status given by the first matching rule, or the status itself if none matches.
+pub fn map_status(map: &[StatusRule], status: Option<i32>, short_output: &str) -> Option<i32> {
+    map.iter()
+        .find(|rule| rule.matches(status, short_output))
+        .map_or(status, |rule| Some(rule.status))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatusParseError(String);
+
+impl std::error::Error for StatusParseError {}
+
+impl fmt::Display for StatusParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid status '{}': expected 0 to 3, OK, WARNING, CRITICAL or UNKNOWN",
+            self.0
+        )
+    }
+}
+
+/// A status given as a number from 0 to 3 or by its name, such as `CRITICAL`.
+pub fn parse_status(s: &str) -> Result<i32, StatusParseError> {
+    match s.trim().to_uppercase().as_str() {
+        "0" | "OK" => Ok(0),
+        "1" | "WARNING" => Ok(1),
+        "2" | "CRITICAL" => Ok(2),
+        "3" | "UNKNOWN" => Ok(3),
+        _ => Err(StatusParseError(s.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod status_map_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn rule(exit: Option<i32>, output: Option<&str>, status: i32) -> StatusRule {
+        StatusRule {
+            exit,
+            output: output.map(|o| OutputPattern::new(o).unwrap()),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_map_status() {
+        let map = vec![
+            rule(None, Some("(?i)maintenance"), 0),
+            rule(Some(1), None, 2),
+            rule(Some(3), Some("^Timed out"), 1),
+        ];
+
+        assert_eq!(map_status(&map, Some(1), "DISK WARNING"), Some(2));
+        assert_eq!(map_status(&map, Some(2), "In Maintenance"), Some(0));
+        assert_eq!(map_status(&map, Some(3), "Timed out after 5 s"), Some(1));
+        assert_eq!(map_status(&map, Some(3), "No such file"), Some(3));
+        assert_eq!(map_status(&map, None, "maintenance"), Some(0));
+        assert_eq!(map_status(&[], Some(2), ""), Some(2));
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status("2"), Ok(2));
+        assert_eq!(parse_status("Warning"), Ok(1));
+        assert_eq!(parse_status(" UNKNOWN "), Ok(3));
+        assert_eq!(
+            parse_status("4").unwrap_err().to_string(),
+            "Invalid status '4': expected 0 to 3, OK, WARNING, CRITICAL or UNKNOWN"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Threshold ranges in the format of the Nagios plugin guidelines, used to evaluate the warn and
+//! crit ranges of performance data. A range `start:end` alerts when the value is outside of it,
+//! and inside of it when prefixed with `@`. `10` is short for `0:10`, `10:` has no end, and a
+//! start of `~` is negative infinity.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// The warn and crit ranges of a metric, set in a template.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricThresholds {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crit: Option<String>,
+}
+
+/// Thresholds of metrics by label.
+pub type Thresholds = BTreeMap<String, MetricThresholds>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Threshold {
+    pub start: f64,
+    pub end: f64,
+    /// Alert when the value is inside of the range instead of outside of it.
+    pub inside: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThresholdParseError {
+    pub range: String,
+    pub reason: &'static str,
+}
+
+impl std::error::Error for ThresholdParseError {}
+
+impl fmt::Display for ThresholdParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid range '{}': {}", self.range, self.reason)
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = ThresholdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason| ThresholdParseError {
+            range: s.to_string(),
+            reason,
+        };
+        let range = s.trim();
+        let (inside, range) = match range.strip_prefix('@') {
+            Some(range) => (true, range),
+            None => (false, range),
+        };
+        if range.is_empty() {
+            return Err(error("the range is empty"));
+        }
+
+        let (start, end) = match range.split_once(':') {
+            Some((start, end)) => (start, end),
+            None => ("", range),
+        };
+        let start = match start {
+            "" => 0.0,
+            "~" => f64::NEG_INFINITY,
+            start => number(start).ok_or_else(|| error("the start is not a number"))?,
+        };
+        let end = match end {
+            "" => f64::INFINITY,
+            end => number(end).ok_or_else(|| error("the end is not a number"))?,
+        };
+        if start > end {
+            return Err(error("the start is greater than the end"));
+        }
+
+        Ok(Self { start, end, inside })
+    }
+}
+
+fn number(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.inside {
+            write!(f, "@")?;
+        }
+        match (self.start, self.end) {
+            (start, end) if start == 0.0 && end.is_finite() && !self.inside => {
+                write!(f, "{}", end)
+            }
+            (start, end) => {
+                if start.is_finite() {
+                    write!(f, "{}:", start)?;
+                } else {
+                    write!(f, "~:")?;
+                }
+                if end.is_finite() {
+                    write!(f, "{}", end)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Threshold {
+    pub fn is_alert(&self, value: f64) -> bool {
+        let in_range = self.start <= value && value <= self.end;
+        in_range == self.inside
+    }
+
+    /// Why the value alerts, such as `value 93 > crit 90`, where the name is the kind of
+    /// threshold. `None` if it doesn't alert.
+    pub fn reason(&self, value: f64, name: &str) -> Option<String> {
+        if !self.is_alert(value) {
+            None
+        } else if self.inside {
+            Some(format!("value {} inside {} {}", value, name, self))
+        } else if value < self.start {
+            Some(format!("value {} < {} {}", value, name, self.start))
+        } else {
+            Some(format!("value {} > {} {}", value, name, self.end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod threshold_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn alerts(range: &str, values: &[f64]) -> Vec<bool> {
+        let threshold = Threshold::from_str(range).unwrap();
+        values.iter().map(|v| threshold.is_alert(*v)).collect()
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(
+            alerts("10", &[-1.0, 0.0, 10.0, 10.5]),
+            [true, false, false, true]
+        );
+        assert_eq!(alerts("10:", &[9.9, 10.0, 1e9]), [true, false, false]);
+        assert_eq!(alerts("~:10", &[-1e9, 10.0, 11.0]), [false, false, true]);
+        assert_eq!(
+            alerts("10:20", &[9.0, 10.0, 20.0, 21.0]),
+            [true, false, false, true]
+        );
+        assert_eq!(
+            alerts("@10:20", &[9.0, 10.0, 20.0, 21.0]),
+            [false, true, true, false]
+        );
+        assert_eq!(
+            alerts("-5.5:-1e-1", &[-6.0, -0.2, 0.0]),
+            [true, false, true]
+        );
+        assert_eq!(alerts("@~:0", &[-3.0, 0.5]), [true, false]);
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        let error = |range: &str| Threshold::from_str(range).unwrap_err().to_string();
+        assert_eq!(error(""), "Invalid range '': the range is empty");
+        assert_eq!(error("@"), "Invalid range '@': the range is empty");
+        assert_eq!(
+            error("20:10"),
+            "Invalid range '20:10': the start is greater than the end"
+        );
+        assert_eq!(error("~"), "Invalid range '~': the end is not a number");
+        assert_eq!(error("1:~"), "Invalid range '1:~': the end is not a number");
+        assert_eq!(error("@@5"), "Invalid range '@@5': the end is not a number");
+        assert_eq!(
+            error("x:5"),
+            "Invalid range 'x:5': the start is not a number"
+        );
+    }
+
+    #[test]
+    fn test_reason() {
+        let reason =
+            |range: &str, value: f64| Threshold::from_str(range).unwrap().reason(value, "crit");
+        assert_eq!(reason("90", 93.0), Some("value 93 > crit 90".to_string()));
+        assert_eq!(reason("10:", 2.5), Some("value 2.5 < crit 10".to_string()));
+        assert_eq!(
+            reason("@5:10", 7.0),
+            Some("value 7 inside crit @5:10".to_string())
+        );
+        assert_eq!(
+            reason("@~:10", 7.0),
+            Some("value 7 inside crit @~:10".to_string())
+        );
+        assert_eq!(reason("90", 50.0), None);
+    }
+}
@@ -0,0 +1,114 @@
+//! Normalization of the units of measurement of performance data, so that values of the same
+//! kind are in the same unit no matter which plugin printed them. Sizes are in bytes, where a
+//! kilobyte is 1024 bytes like in `check_disk`, and times in seconds. Percentages and counters
+//! are already in their base unit.
+
+use crate::threshold::Threshold;
+use std::str::FromStr;
+
+/// How to turn a value in some unit of measurement into a value in its base unit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unit {
+    /// The base unit, such as `B` for `MB`.
+    pub base: &'static str,
+    multiplier: f64,
+    divisor: f64,
+}
+
+impl Unit {
+    const fn new(base: &'static str, multiplier: f64, divisor: f64) -> Self {
+        Self {
+            base,
+            multiplier,
+            divisor,
+        }
+    }
+
+    /// The unit of measurement with the given name, if it's known. Plugins print kilobytes as
+    /// both `KB` and `kB`, but the other prefixes must be uppercase, as `mB` would be millibytes,
+    /// and the `B` too, as `b` is bits. The binary forms such as `KiB` are accepted as well.
+    pub fn from_uom(uom: &str) -> Option<Self> {
+        let unit = match uom {
+            "s" => Self::new("s", 1.0, 1.0),
+            "ms" => Self::new("s", 1.0, 1e3),
+            "us" | "µs" => Self::new("s", 1.0, 1e6),
+            "%" => Self::new("%", 1.0, 1.0),
+            "c" => Self::new("c", 1.0, 1.0),
+            "B" => Self::new("B", 1.0, 1.0),
+            "KB" | "kB" | "KiB" => Self::new("B", 1024.0, 1.0),
+            "MB" | "MiB" => Self::new("B", 1024.0 * 1024.0, 1.0),
+            "GB" | "GiB" => Self::new("B", 1024.0 * 1024.0 * 1024.0, 1.0),
+            "TB" | "TiB" => Self::new("B", 1024.0 * 1024.0 * 1024.0 * 1024.0, 1.0),
+            _ => return None,
+        };
+        Some(unit)
+    }
+
+    /// The value in the base unit.
+    pub fn normalize(&self, value: f64) -> f64 {
+        value * self.multiplier / self.divisor
+    }
+
+    /// The threshold range with both ends in the base unit, or `None` if it isn't a valid
+    /// range.
+    pub fn normalize_range(&self, range: &str) -> Option<String> {
+        let threshold = Threshold::from_str(range).ok()?;
+        Some(
+            Threshold {
+                start: self.normalize(threshold.start),
+                end: self.normalize(threshold.end),
+                inside: threshold.inside,
+            }
+            .to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod uom_test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn normalize(value: f64, uom: &str) -> (f64, &'static str) {
+        let unit = Unit::from_uom(uom).unwrap();
+        (unit.normalize(value), unit.base)
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(512.0, "B"), (512.0, "B"));
+        assert_eq!(normalize(2.0, "KB"), (2048.0, "B"));
+        assert_eq!(normalize(1.5, "kB"), (1536.0, "B"));
+        assert_eq!(normalize(2643.0, "MB"), (2771386368.0, "B"));
+        assert_eq!(normalize(1.5, "GB"), (1610612736.0, "B"));
+        assert_eq!(normalize(1.0, "TB"), (1099511627776.0, "B"));
+        assert_eq!(normalize(4.0, "KiB"), (4096.0, "B"));
+        assert_eq!(normalize(2.0, "MiB"), (2097152.0, "B"));
+        assert_eq!(normalize(1.0, "GiB"), (1073741824.0, "B"));
+        assert_eq!(normalize(1.0, "TiB"), (1099511627776.0, "B"));
+        assert_eq!(normalize(245.0, "ms"), (0.245, "s"));
+        assert_eq!(normalize(1500.0, "us"), (0.0015, "s"));
+        assert_eq!(normalize(3.0, "s"), (3.0, "s"));
+        assert_eq!(normalize(93.5, "%"), (93.5, "%"));
+        assert_eq!(normalize(12.0, "c"), (12.0, "c"));
+        assert_eq!(Unit::from_uom("B/s"), None);
+        assert_eq!(Unit::from_uom("S"), None);
+        assert_eq!(Unit::from_uom("mB"), None);
+        assert_eq!(Unit::from_uom("gB"), None);
+        assert_eq!(Unit::from_uom("kiB"), None);
+        assert_eq!(Unit::from_uom("Mb"), None);
+        assert_eq!(Unit::from_uom("b"), None);
+        assert_eq!(Unit::from_uom("kb"), None);
+    }
+
+    #[test]
+    fn test_normalize_range() {
+        let mb = Unit::from_uom("MB").unwrap();
+        let ms = Unit::from_uom("ms").unwrap();
+        assert_eq!(mb.normalize_range("10"), Some("10485760".to_string()));
+        assert_eq!(mb.normalize_range("1:"), Some("1048576:".to_string()));
+        assert_eq!(ms.normalize_range("~:250"), Some("~:0.25".to_string()));
+        assert_eq!(ms.normalize_range("@100:200"), Some("@0.1:0.2".to_string()));
+        assert_eq!(ms.normalize_range("20:10"), None);
+    }
+}
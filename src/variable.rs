@@ -1,9 +1,11 @@
+use crate::macros::is_runtime_macro;
+use crate::resource::resource_value;
 use hex::decode;
 use lazy_static::lazy_static;
 use log::debug;
 use once_cell::sync::OnceCell;
 use openssl::symm::{Cipher, Crypter, Mode};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fmt;
 use std::str::FromStr;
@@ -45,7 +47,7 @@ impl VariablesExt for Variables {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize)]
 pub enum Variable {
     Found(VariableName, VariableValue, SecretVariableValue),
     NotFound(VariableName),
@@ -58,6 +60,8 @@ pub enum VariableError {
     EnvVarError(VariableName),
     DecryptionError(openssl::error::ErrorStack),
     NoKeyFileError(VariableName),
+    /// An encrypted variable where its value would be visible, such as in the SSH settings.
+    SecretNotAllowed(VariableName, &'static str),
 }
 
 impl Error for VariableError {}
@@ -94,6 +98,11 @@ impl fmt::Display for VariableError {
                 write!(f, "Failed to compile VariableString Regex")
             }
             VariableError::ParseError(err) => write!(f, "Parse error: {}", err),
+            VariableError::SecretNotAllowed(variable_name, place) => write!(
+                f,
+                "The variable \"{}\" is encrypted, which isn't allowed in {}",
+                variable_name, place
+            ),
         }
     }
 }
@@ -104,7 +113,12 @@ impl FromStr for Variable {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let key_file = KEY_FILE.read().unwrap();
 
-        let env_var_value = std::env::var(s);
+        // $USERn$ macros come from the Nagios resource file if one is loaded, and from the
+        // environment otherwise.
+        let env_var_value = match resource_value(s) {
+            Some(value) => Ok(value),
+            None => std::env::var(s),
+        };
 
         if let Ok(value) = env_var_value {
             if potentially_encrypted(&value) {
@@ -147,6 +161,16 @@ impl Variable {
         }
     }
 
+    /// A copy of the variable without its decrypted value.
+    pub fn without_secret_value(&self) -> Self {
+        match self {
+            Variable::Found(name, value, _secret_value) => {
+                Variable::Found(name.clone(), value.clone(), None)
+            }
+            Variable::NotFound(name) => Variable::NotFound(name.clone()),
+        }
+    }
+
     pub fn to_secret_string(&self) -> String {
         match self {
             Variable::Found(name, _value, secret_value) => {
@@ -157,7 +181,7 @@ impl Variable {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize)]
 pub enum VariableKind {
     Public(Variable),
     Secret(Variable),
@@ -176,16 +200,28 @@ impl VariableString {
     pub fn clear_string(&self) -> Option<String> {
         self.clear_string.clone()
     }
-}
 
-impl FromStr for VariableString {
-    type Err = VariableError;
+    /// Like `from_str`, but every substituted value is quoted for `/bin/sh` so that it can never
+    /// be interpreted as anything other than a single word by the shell.
+    pub fn from_str_shell_quoted(s: &str) -> Result<Self, VariableError> {
+        Self::parse(s, true)
+    }
+
+    fn parse(s: &str, shell_quoted: bool) -> Result<Self, VariableError> {
+        let quote = |v: &str| {
+            if shell_quoted {
+                shell_quote(v)
+            } else {
+                v.to_string()
+            }
+        };
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
         let variable_re = regex::Regex::new(VARIABLE_RE)?;
         let variable_names = variable_re
             .captures_iter(s)
             .filter_map(|c| c.get(1).map(|m| m.as_str().to_string()))
+            // Runtime macros are resolved when the check runs.
+            .filter(|name| !is_runtime_macro(name))
             .collect::<Vec<VariableName>>();
 
         let mut clear_string: String = s.to_string();
@@ -204,8 +240,10 @@ impl FromStr for VariableString {
                             None => Some(clear_string.replace(&format!("${}$", name), "***")),
                             Some(s) => Some(s.replace(&format!("${}$", name), "***")),
                         };
-                        clear_string = clear_string
-                            .replace(&format!("${}$", name), &secret_value.clone().unwrap());
+                        clear_string = clear_string.replace(
+                            &format!("${}$", name),
+                            &quote(secret_value.as_ref().unwrap()),
+                        );
                         found_variables.push(VariableKind::Secret(Variable::Found(
                             name.to_string(),
                             value,
@@ -215,17 +253,14 @@ impl FromStr for VariableString {
                     Variable::Found(name, value, secret_value) => {
                         // Since the variable is not secret, replace the clear value even in the
                         // obfuscated string.
+                        let quoted_value = quote(value.as_ref().unwrap());
                         obfuscated_string = match obfuscated_string {
-                            None => Some(
-                                clear_string
-                                    .replace(&format!("${}$", name), value.as_ref().unwrap()),
-                            ),
-                            Some(s) => {
-                                Some(s.replace(&format!("${}$", name), value.as_ref().unwrap()))
+                            None => {
+                                Some(clear_string.replace(&format!("${}$", name), &quoted_value))
                             }
+                            Some(s) => Some(s.replace(&format!("${}$", name), &quoted_value)),
                         };
-                        clear_string =
-                            clear_string.replace(&format!("${}$", name), value.as_ref().unwrap());
+                        clear_string = clear_string.replace(&format!("${}$", name), &quoted_value);
                         found_variables.push(VariableKind::Public(Variable::Found(
                             name.to_string(),
                             value,
@@ -268,6 +303,19 @@ impl FromStr for VariableString {
     }
 }
 
+impl FromStr for VariableString {
+    type Err = VariableError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, false)
+    }
+}
+
+/// Quote a string for safe use as a single word in a POSIX shell command line.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
 #[derive(Debug)]
 pub struct KeyFile {
     _salt: String,
@@ -397,6 +445,7 @@ fn decrypt_str(s: &str, k: &KeyFile) -> Result<String, openssl::error::ErrorStac
 mod variable_test {
     use super::*;
     use pretty_assertions::assert_eq;
+    use serial_test::serial;
     #[test]
     fn test_replace_variables_in_str() {
         std::env::set_var("FOO", "bar");
@@ -456,6 +505,28 @@ mod variable_test {
         assert_eq!("hello bar $MISSING$ qux", r.unwrap().clear_string.unwrap());
     }
 
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("bar"), "'bar'");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$(rm -rf /); `id`"), "'$(rm -rf /); `id`'");
+    }
+
+    #[test]
+    fn test_replace_variables_in_str_shell_quoted() {
+        std::env::set_var("SHELL_QUOTED_VAR", "foo; echo injected");
+
+        let r = VariableString::from_str_shell_quoted("echo $SHELL_QUOTED_VAR$ | wc -l").unwrap();
+
+        assert_eq!(r.clear_string.unwrap(), "echo 'foo; echo injected' | wc -l");
+        assert_eq!(
+            r.obfuscated_string.unwrap(),
+            "echo 'foo; echo injected' | wc -l"
+        );
+    }
+
     #[test]
     fn test_valid_keyfile_from_str() {
         let valid_string = r#"salt=89A6A795C9CCECB5
@@ -572,6 +643,19 @@ key=26D6EDD53A0AFA8FA1AA3FBCD2FFF2A0BF4809A4E04511F629FC732C2A42A8FC
 iv =472A3557ADDD2525AD4E555738636A67
 "#;
 
+    /// Unloads the key file and the resource file when a test that loaded them ends, even if it
+    /// fails, so that they don't leak into other tests.
+    struct LoadedGlobals;
+
+    impl Drop for LoadedGlobals {
+        fn drop(&mut self) {
+            *KEY_FILE.write().unwrap_or_else(|e| e.into_inner()) = None;
+            *crate::resource::RESOURCES
+                .write()
+                .unwrap_or_else(|e| e.into_inner()) = None;
+        }
+    }
+
     #[test]
     fn test_decrypt_str() {
         let kf = KeyFile::from_str(TEST_KEY_FILE).unwrap();
@@ -587,7 +671,9 @@ iv =472A3557ADDD2525AD4E555738636A67
     }
 
     #[test]
+    #[serial]
     fn test_mixed_variables_from_str() {
+        let _globals = LoadedGlobals;
         let kf = KeyFile::from_str(TEST_KEY_FILE);
 
         match kf {
@@ -664,7 +750,9 @@ iv =472A3557ADDD2525AD4E555738636A67
     }
 
     #[test]
+    #[serial]
     fn test_mixed_variables_to_string() {
+        let _globals = LoadedGlobals;
         let kf = KeyFile::from_str(TEST_KEY_FILE);
 
         match kf {
@@ -720,4 +808,33 @@ iv =472A3557ADDD2525AD4E555738636A67
             "ENCRYPTED_VAR_1=***,ENCRYPTED_VAR_2=***,UNENCRYPTED_VAR=\"bar\""
         );
     }
+
+    #[test]
+    #[serial]
+    fn test_user_macros_from_resource_file() {
+        let _globals = LoadedGlobals;
+        *KEY_FILE.write().unwrap() = Some(KeyFile::from_str(TEST_KEY_FILE).unwrap());
+        *crate::resource::RESOURCES.write().unwrap() = Some(
+            crate::resource::NagiosResources::from_str(
+                "$USER1$=/usr/lib/nagios/plugins\n$USER3$=+encs+BCC9E963342C9CFEFB45093F3437A680\n",
+            )
+            .unwrap(),
+        );
+
+        let variable_string =
+            VariableString::from_str("$USER1$/check_db -p $USER3$ -u $USER4$").unwrap();
+
+        assert_eq!(
+            variable_string.clear_string().unwrap(),
+            "/usr/lib/nagios/plugins/check_db -p 12345 -u $USER4$"
+        );
+        assert_eq!(
+            variable_string.obfuscated_string.unwrap(),
+            "/usr/lib/nagios/plugins/check_db -p *** -u $USER4$"
+        );
+        assert_eq!(
+            variable_string.variables_not_found.unwrap().to_string(),
+            "USER4"
+        );
+    }
 }
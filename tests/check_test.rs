@@ -1,4 +1,5 @@
 use geneos_xtender::check::*;
+use geneos_xtender::limit::ResourceLimits;
 use pretty_assertions::{assert_eq, assert_ne};
 
 #[test]
@@ -11,6 +12,20 @@ fn test_run_check() {
     assert_eq!(hello_world_output, "hello world");
 }
 
+#[tokio::test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn test_run_inside_runtime() {
+    let p = Check::new("Hello World", "echo hello world", None, 2);
+    assert_eq!(p.run().short_output(), "hello world");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn test_run_inside_multi_threaded_runtime() {
+    let p = Check::new("Hello World", "echo hello world", None, 2);
+    assert_eq!(p.run().short_output(), "hello world");
+}
+
 #[test]
 #[should_panic]
 fn test_run_invalid_command() {
@@ -46,6 +61,72 @@ async fn test_run_all() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[tokio::test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn test_run_all_beyond_blocking_pool_size() -> Result<(), Box<dyn std::error::Error>> {
+    // More concurrent checks than the default size of Tokio's blocking thread pool (512).
+    let checks = (0..600)
+        .map(|i| {
+            CheckBuilder::new()
+                .name(&format!("Sleep {}", i))
+                .command("sleep 1")
+                .build()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let start_time = std::time::Instant::now();
+    let results = run_all_checks_in_parallel(checks).await?;
+    let elapsed_time = start_time.elapsed();
+
+    assert_eq!(results.0.len(), 600);
+    assert!(results.0.iter().all(|r| r.status() == Some(0)));
+    println!("Elapsed time: {:?}", elapsed_time);
+    assert!(elapsed_time < std::time::Duration::from_secs(5));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn test_run_all_with_max_parallel() -> Result<(), Box<dyn std::error::Error>> {
+    let checks = (0..4)
+        .map(|i| {
+            CheckBuilder::new()
+                .name(&format!("Sleep {}", i))
+                .command(&format!("sh -c 'sleep 1; echo {}'", i))
+                .build()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let start_time = std::time::Instant::now();
+    let results = run_all_checks_with_max_parallel(checks, 2).await?;
+    let elapsed_time = start_time.elapsed();
+
+    assert_eq!(
+        results
+            .0
+            .iter()
+            .map(|r| r.short_output())
+            .collect::<Vec<_>>(),
+        vec!["0", "1", "2", "3"]
+    );
+    println!("Elapsed time: {:?}", elapsed_time);
+    assert!(elapsed_time >= std::time::Duration::from_secs(2));
+    assert!(elapsed_time < std::time::Duration::from_secs(3));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_run_check_with_large_output() {
+    // Far more output than fits in a pipe buffer.
+    let check = Check::new("Large output", "seq 1 200000", None, 5);
+    let r = check.run();
+    assert_eq!(r.status(), Some(0));
+    assert_eq!(r.short_output(), "1");
+}
+
 #[test]
 fn test_invalid_variable_in_name() -> Result<(), Box<dyn std::error::Error>> {
     let c = CheckBuilder::new()
@@ -81,3 +162,76 @@ fn test_check_run_timed_out() {
     assert_eq!(result.status(), Some(3));
     assert_eq!(result.short_output(), "UNKNOWN: Timed out after 0 seconds");
 }
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_run_check_in_shell() -> Result<(), Box<dyn std::error::Error>> {
+    let check = CheckBuilder::new()
+        .name("Pipeline")
+        .command("printf 'hello\\nworld\\n' | tail -n 1")
+        .shell(DEFAULT_SHELL)
+        .build()?;
+    let r = check.run();
+    assert_eq!(r.status(), Some(0));
+    assert_eq!(r.short_output(), "world");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_run_check_with_args() -> Result<(), Box<dyn std::error::Error>> {
+    let check = CheckBuilder::new()
+        .name("Args")
+        .command("printf")
+        .args(&["%s|%s".to_string(), "a 'b' c".to_string(), "d".to_string()])
+        .build()?;
+    let r = check.run();
+    assert_eq!(r.status(), Some(0));
+    assert_eq!(r.short_output(), "a 'b' c");
+    assert_eq!(r.performance_data(), "d");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_run_check_exceeding_cpu_time_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let check = CheckBuilder::new()
+        .name("Busy loop")
+        .command("while :; do :; done")
+        .shell(DEFAULT_SHELL)
+        .timeout(10)
+        .limits(ResourceLimits {
+            cpu_time: Some(1),
+            ..ResourceLimits::default()
+        })
+        .build()?;
+    let r = check.run();
+    assert_eq!(r.status(), Some(3));
+    assert_eq!(
+        r.short_output(),
+        "UNKNOWN: CPU time limit of 1 second exceeded"
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_run_check_exceeding_open_files_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let check = CheckBuilder::new()
+        .name("Open files")
+        .command("exec 3</dev/null 4</dev/null 5</dev/null 6</dev/null 7</dev/null || exit 3")
+        .shell("/bin/bash")
+        .limits(ResourceLimits {
+            open_files: Some(5),
+            ..ResourceLimits::default()
+        })
+        .build()?;
+    let r = check.run();
+    assert_eq!(r.status(), Some(3));
+    assert_eq!(r.short_output(), "UNKNOWN: Open files limit of 5 exceeded");
+
+    Ok(())
+}
@@ -1,13 +1,12 @@
 use assert_cmd::prelude::*; // Add methods on commands
 use predicates::prelude::*;
 use pretty_assertions::assert_eq;
-use serde::__private::from_utf8_lossy; // Used for writing assertions
 use serial_test::serial;
 use std::fs::File;
 use std::io::Write;
 use std::process::Command; // Run programs
 use std::time::Instant;
-use tempfile::tempdir;
+use tempfile::{tempdir, NamedTempFile};
 
 const CSV_HEADER_COLUMNS: &str = "name,status,shortOutput,label,value,uom,warn,crit,min,max,command,performanceDataString,longOutput,executionTime,variablesFound,variablesNotFound";
 const ENCRYPTED_VAR_EXAMPLE1: &str = r"+encs+BCC9E963342C9CFEFB45093F3437A680";
@@ -44,6 +43,16 @@ set -e
 echo "CRITICAL: This plugin exits with code 2"
 exit 2
 "#;
+const SAMPLE_YAML_ARGS: &str = r#"
+---
+- name: test_args
+  command: printf
+  args:
+    - "[%s][%s]"
+    - $UNENCRYPTED_TEST_VAR_1$
+    - $ENCRYPTED_TEST_VAR_1$
+...
+"#;
 const SAMPLE_YAML_DASH_ARG: &str = r#"
 ---
 - name: hello
@@ -144,6 +153,14 @@ const SAMPLE_YAML_RANGE_VAR: &str = r#"
     printf '%s %s' Hello !!A:1..3!!
 ...
 "#;
+const SAMPLE_YAML_SHELL_PIPELINE: &str = r#"
+---
+- name: test_shell_pipeline
+  shell: true
+  command: |
+    echo $ENCRYPTED_TEST_VAR_1$ $UNENCRYPTED_TEST_VAR_1$ | tr -d l
+...
+"#;
 const SAMPLE_YAML_SHORT_SLEEP_CMD: &str = r#"
 ---
 - name: test_1
@@ -433,6 +450,515 @@ fn test_command_from_option_with_range_variable_and_timeout(
     Ok(())
 }
 
+#[test]
+#[serial]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_command_run_in_shell_with_quoted_vars() -> Result<(), Box<dyn std::error::Error>> {
+    unsafe {
+        std::env::set_var("ENCRYPTED_TEST_VAR_1", ENCRYPTED_VAR_EXAMPLE1);
+        std::env::set_var("UNENCRYPTED_TEST_VAR_1", "Hello; echo injected");
+    }
+
+    let dir = tempdir()?;
+    let key_file_path = dir.path().join("keyfile");
+    let mut key_file = File::create(&key_file_path)?;
+    writeln!(key_file, "{}", VALID_KEY_FILE_CONTENTS)?;
+
+    let file_1_path = dir.path().join("file_1.yaml");
+    let mut file_1 = File::create(&file_1_path)?;
+    writeln!(file_1, "{}", SAMPLE_YAML_SHELL_PIPELINE)?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.arg("-k").arg(key_file_path).arg("--").arg(&file_1_path);
+
+    cmd.assert()
+        .success()
+        // The pipeline is run by the shell, while the variable value stays a single word.
+        .stdout(predicate::str::contains(
+            "test_shell_pipeline,0,12345 Heo; echo injected,",
+        ))
+        .stdout(predicate::str::contains(
+            ",echo *** 'Hello; echo injected' | tr -d l,",
+        ))
+        .stdout(predicate::str::contains("injected\n").not())
+        .stdout(predicate::str::contains("12345 |").not());
+
+    drop(file_1);
+    dir.close()?;
+
+    unsafe {
+        std::env::remove_var("ENCRYPTED_TEST_VAR_1");
+        std::env::remove_var("UNENCRYPTED_TEST_VAR_1");
+    }
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_command_with_args_list() -> Result<(), Box<dyn std::error::Error>> {
+    unsafe {
+        std::env::set_var("ENCRYPTED_TEST_VAR_1", ENCRYPTED_VAR_EXAMPLE1);
+        std::env::set_var("UNENCRYPTED_TEST_VAR_1", "it's \"two words\"");
+    }
+
+    let dir = tempdir()?;
+    let key_file_path = dir.path().join("keyfile");
+    let mut key_file = File::create(&key_file_path)?;
+    writeln!(key_file, "{}", VALID_KEY_FILE_CONTENTS)?;
+
+    let file_1_path = dir.path().join("file_1.yaml");
+    let mut file_1 = File::create(&file_1_path)?;
+    writeln!(file_1, "{}", SAMPLE_YAML_ARGS)?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.arg("-k").arg(key_file_path).arg("--").arg(&file_1_path);
+
+    cmd.assert()
+        .success()
+        // The value with spaces and quotes is passed as one argument.
+        .stdout(predicate::str::contains(
+            "test_args,0,[it's \"two words\"][12345],",
+        ))
+        .stdout(predicate::str::contains(
+            ",printf '[%s][%s]' 'it'\\''s \"two words\"' ***,",
+        ))
+        .stdout(predicate::str::contains(
+            ",ENCRYPTED_TEST_VAR_1=***\\,UNENCRYPTED_TEST_VAR_1=",
+        ));
+
+    drop(file_1);
+    dir.close()?;
+
+    unsafe {
+        std::env::remove_var("ENCRYPTED_TEST_VAR_1");
+        std::env::remove_var("UNENCRYPTED_TEST_VAR_1");
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_command_with_global_cpu_time_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("--limit-cpu-time")
+        .arg("1")
+        .arg("-c")
+        .arg("sh -c 'while :; do :; done'")
+        .arg("-n")
+        .arg("busy_loop")
+        .arg("-t")
+        .arg("10");
+
+    cmd.assert().success().stdout(predicate::str::contains(
+        "busy_loop,3,UNKNOWN: CPU time limit of 1 second exceeded,",
+    ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_budget_with_history() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let template_path = dir.path().join("template.yaml");
+    let history_path = dir.path().join("history.json");
+    let mut template = File::create(&template_path)?;
+    writeln!(template, "{}", SAMPLE_YAML_MULTIPLE_CHECKS)?;
+
+    Command::cargo_bin("xtender")?
+        .arg("--history")
+        .arg(&history_path)
+        .arg("--")
+        .arg(&template_path)
+        .assert()
+        .success();
+
+    Command::cargo_bin("xtender")?
+        .arg("budget")
+        .arg("--sample-interval")
+        .arg("8")
+        .arg("--max-parallel")
+        .arg("1")
+        .arg("--history")
+        .arg(&history_path)
+        .arg("--")
+        .arg(&template_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Checks: 2\nMax parallel: 1\n"))
+        .stdout(predicate::str::contains("Worst case: 10.0 s\n"))
+        .stdout(predicate::str::contains(
+            "Warning: The worst case is 2.0 s longer than the sample interval",
+        ))
+        .stdout(predicate::str::contains(
+            "from the history of 2 of 2 checks",
+        ))
+        .stdout(predicate::str::contains(
+            "  test_with_multiple_yaml_file_1 ",
+        ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_dry_run_prints_plan_without_running() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let marker_path = dir.path().join("ran");
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("--dry-run")
+        .arg("-c")
+        .arg(format!("touch {} !!A:1..2!!", marker_path.display()))
+        .arg("-n")
+        .arg("touch !!A:1..2!!")
+        .arg("-t")
+        .arg("7");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Checks: 2\n"))
+        .stdout(predicate::str::is_match(r"\ntouch 1 +touch \S+ 1 +7 s\n")?)
+        .stdout(predicate::str::is_match(r"\ntouch 2 +touch \S+ 2 +7 s\n")?);
+
+    assert!(!marker_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_dry_run_as_json() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("--dry-run")
+        .arg("json")
+        .arg("-c")
+        .arg("echo $DRY_RUN_MISSING_VAR$")
+        .arg("-n")
+        .arg("dry");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let plan: serde_json::Value = serde_json::from_slice(&output)?;
+
+    assert_eq!(plan["checks"][0]["name"], "dry");
+    assert_eq!(plan["checks"][0]["command"], "echo $DRY_RUN_MISSING_VAR$");
+    assert_eq!(plan["checks"][0]["timeout"], 5);
+    assert_eq!(
+        plan["checks"][0]["variablesNotFound"],
+        serde_json::json!(["DRY_RUN_MISSING_VAR"])
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_overlapping_run_with_lock() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let xtender = |command: &str, on_locked: &str| -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("xtender")?;
+        cmd.arg("--state-dir")
+            .arg(dir.path())
+            .arg("--lock-name")
+            .arg("sampler")
+            .arg("--on-locked")
+            .arg(on_locked)
+            .arg("-c")
+            .arg(command)
+            .arg("-n")
+            .arg("locked")
+            .arg("-t")
+            .arg("10");
+        Ok(cmd)
+    };
+
+    // A completed run leaves its output behind for --on-locked cached.
+    xtender("echo first", "headline")?.assert().success();
+
+    let mut previous_run = xtender("sleep 3", "headline")?.spawn()?;
+    let start = Instant::now();
+    while !std::fs::read_to_string(dir.path().join("sampler.lock"))?
+        .trim()
+        .eq(&previous_run.id().to_string())
+    {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    xtender("echo second", "headline")?
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "<!>lockStatus,previous run still active",
+        ))
+        .stdout(predicate::str::contains("locked,").not());
+
+    xtender("echo second", "cached")?
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "<!>lockStatus,previous run still active",
+        ))
+        .stdout(predicate::str::contains("\nlocked,0,first,"));
+
+    xtender("echo third", "wait")?
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("lockStatus").not())
+        .stdout(predicate::str::contains("\nlocked,0,third,"));
+
+    assert!(previous_run.wait()?.success());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_daemon_writes_results_to_file() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let template_path = dir.path().join("template.yaml");
+    let output_path = dir.path().join("results.csv");
+    let mut template = File::create(&template_path)?;
+    writeln!(template, "{}", SAMPLE_YAML_MULTIPLE_CHECKS)?;
+
+    let mut daemon = Command::cargo_bin("xtender")?
+        .arg("daemon")
+        .arg("--output")
+        .arg(&output_path)
+        .arg("--jitter")
+        .arg("0")
+        .arg("--")
+        .arg(&template_path)
+        .spawn()?;
+
+    let start = Instant::now();
+    let mut output = String::new();
+    while start.elapsed() < std::time::Duration::from_secs(10) {
+        output = std::fs::read_to_string(&output_path).unwrap_or_default();
+        if output.lines().count() == 5 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    // SAFETY: Sending SIGTERM to the child that was just spawned.
+    unsafe { libc::kill(daemon.id() as libc::pid_t, libc::SIGTERM) };
+    let status = daemon.wait()?;
+
+    assert!(status.success());
+    assert!(output.starts_with(CSV_HEADER_COLUMNS));
+    assert!(output.contains("<!>templatesNotFound,\n"));
+    assert!(output.contains("\ntest_with_multiple_yaml_file_1,0,Hello world!,"));
+    assert!(output.contains("\ntest_with_multiple_yaml_file_2,0,Hello world!,"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nrpe_server_answers_queries() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let template_path = dir.path().join("template.yaml");
+    let mut template = File::create(&template_path)?;
+    writeln!(template, "{}", SAMPLE_YAML_MULTIPLE_CHECKS)?;
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+
+    let mut server = Command::cargo_bin("xtender")?
+        .arg("nrpe-server")
+        .arg("--no-tls")
+        .arg("--listen")
+        .arg(format!("127.0.0.1:{}", port))
+        .arg("--")
+        .arg(&template_path)
+        .spawn()?;
+
+    let start = Instant::now();
+    let mut response = None;
+    while start.elapsed() < std::time::Duration::from_secs(10) {
+        if let Ok(stream) = tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+            response = Some(
+                geneos_xtender::nrpe::query(
+                    stream,
+                    "localhost",
+                    None,
+                    3,
+                    "test_with_multiple_yaml_file_2",
+                )
+                .await?,
+            );
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    // SAFETY: Sending SIGTERM to the child that was just spawned.
+    unsafe { libc::kill(server.id() as libc::pid_t, libc::SIGTERM) };
+    let status = server.wait()?;
+
+    let response = response.expect("The NRPE server didn't start");
+    assert!(status.success());
+    assert_eq!(response.result_code, 0);
+    assert_eq!(response.buffer_string(), "Hello world!");
+
+    Ok(())
+}
+
+#[test]
+fn test_nrpe_server_requires_tls_certificate() -> Result<(), Box<dyn std::error::Error>> {
+    Command::cargo_bin("xtender")?
+        .arg("nrpe-server")
+        .arg("--")
+        .arg("network-base")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--tls-cert"));
+
+    Ok(())
+}
+
+/// Write a self-signed certificate for localhost and its key to `<name>.pem` and `<name>.key` in
+/// the directory.
+fn write_self_signed_certificate(
+    dir: &std::path::Path,
+    name: &str,
+) -> Result<(std::path::PathBuf, std::path::PathBuf), Box<dyn std::error::Error>> {
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut subject = X509NameBuilder::new()?;
+    subject.append_entry_by_nid(Nid::COMMONNAME, "localhost")?;
+    let subject = subject.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&subject)?;
+    builder.set_issuer_name(&subject)?;
+    builder.set_pubkey(&key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(30)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    let cert_path = dir.join(format!("{}.pem", name));
+    let key_path = dir.join(format!("{}.key", name));
+    std::fs::write(&cert_path, builder.build().to_pem()?)?;
+    std::fs::write(&key_path, key.private_key_to_pem_pkcs8()?)?;
+    Ok((cert_path, key_path))
+}
+
+#[test]
+fn test_checks_dispatched_to_agent() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let (agent_cert, agent_key) = write_self_signed_certificate(dir.path(), "agent")?;
+    let (controller_cert, controller_key) =
+        write_self_signed_certificate(dir.path(), "controller")?;
+    let template_path = dir.path().join("zones.yaml");
+    let mut template = File::create(&template_path)?;
+    writeln!(
+        template,
+        r#"
+- name: local
+  command: echo local
+- name: in zone a
+  agent: zone-a
+  command: "sh -c 'echo \"PING WARNING from agent|rta=20ms\"; exit 1'"
+"#
+    )?;
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+
+    let mut agent = Command::cargo_bin("xtender")?
+        .arg("agent")
+        .arg("--listen")
+        .arg(format!("127.0.0.1:{}", port))
+        .arg("--tls-cert")
+        .arg(&agent_cert)
+        .arg("--tls-key")
+        .arg(&agent_key)
+        .arg("--tls-ca")
+        .arg(&controller_cert)
+        .spawn()?;
+
+    let start = Instant::now();
+    while std::net::TcpStream::connect(("127.0.0.1", port)).is_err()
+        && start.elapsed() < std::time::Duration::from_secs(10)
+    {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    let output = Command::cargo_bin("xtender")?
+        .arg("--agent")
+        .arg(format!("zone-a=localhost:{}", port))
+        .arg("--agent-cert")
+        .arg(&controller_cert)
+        .arg("--agent-key")
+        .arg(&controller_key)
+        .arg("--agent-ca")
+        .arg(&agent_cert)
+        .arg("--")
+        .arg(&template_path)
+        .output()?;
+
+    // SAFETY: Sending SIGTERM to the child that was just spawned.
+    unsafe { libc::kill(agent.id() as libc::pid_t, libc::SIGTERM) };
+    agent.wait()?;
+
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("\nlocal,0,local,"));
+    assert!(stdout.contains("\nin zone a,1,PING WARNING from agent,"));
+    assert!(stdout.contains(",rta,20.0,ms,"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_command_with_interval_served_from_cache() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let run = || -> Result<String, Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin("xtender")?
+            .arg("--state-dir")
+            .arg(dir.path())
+            .arg("-c")
+            .arg("date +%s%N")
+            .arg("-n")
+            .arg("cached")
+            .arg("-i")
+            .arg("3600")
+            .output()?;
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    let first = run()?;
+    let second = run()?;
+    let short_output = |s: &str| {
+        s.lines()
+            .find(|l| l.starts_with("cached,"))
+            .and_then(|l| l.split(',').nth(2))
+            .map(|o| o.to_string())
+    };
+
+    assert!(first.contains(",executionTime,resultAge,stale,variablesFound,"));
+    assert!(first.contains(",0 s,false,"));
+    // Served from the cache well within the interval, so it isn't stale.
+    assert!(second.contains(" s,false,"));
+    assert_eq!(short_output(&first), short_output(&second));
+
+    Ok(())
+}
+
 #[test]
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 fn test_display_correct_execution_time() {
@@ -453,6 +979,169 @@ fn test_display_correct_execution_time() {
     dir.close().unwrap();
 }
 
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_display_resource_usage() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("--resource-usage")
+        .arg("-c")
+        .arg("echo hello")
+        .arg("-n")
+        .arg("usage");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            ",executionTime,userCpuTime,systemCpuTime,maxRss,variablesFound,",
+        ))
+        .stdout(predicate::str::contains(
+            "<!>templatesNotFound,\n<!>totalUserCpuTime,",
+        ))
+        .stdout(predicate::str::contains("<!>totalSystemCpuTime,"))
+        .stdout(predicate::str::contains("<!>largestMaxRss,"))
+        .stdout(predicate::str::is_match(
+            r"\nusage,0,hello,.* s,[0-9.]+ s,[0-9.]+ s,[0-9]+ KiB,,",
+        )?);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_display_threshold_state() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("--threshold-state")
+        .arg("-c")
+        .arg("echo 'DISK|used=93%;80;90 free=7%;20:10'")
+        .arg("-n")
+        .arg("disk");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            ",min,max,thresholdState,thresholdReason,command,",
+        ))
+        .stdout(predicate::str::contains(
+            "\tdisk#used,2,,used,93.0,%,80,90,,,CRITICAL,value 93 > crit 90,",
+        ))
+        .stdout(predicate::str::contains(
+            "\tdisk#free,3,,free,7.0,%,20:10,,,,UNKNOWN,\
+             Invalid warn range '20:10': the start is greater than the end,",
+        ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_display_normalized_units() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("--normalize-units")
+        .arg("-c")
+        .arg("echo 'PING OK|rta=20ms;100;500;0'")
+        .arg("-n")
+        .arg("ping");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            ",max,normalizedValue,normalizedUom,normalizedWarn,normalizedCrit,normalizedMin,normalizedMax,command,",
+        ))
+        .stdout(predicate::str::contains(
+            "\nping,0,PING OK,rta,20.0,ms,100,500,0,,0.02,s,0.1,0.5,0,,echo",
+        ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_template_threshold_overrides() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let template_path = dir.path().join("thresholds.yaml");
+    std::fs::write(
+        &template_path,
+        r#"
+- name: load
+  command: echo 'LOAD OK|load1=7 load5=3;2;4 load15=1;0.5;2'
+  thresholds:
+    load1: { warn: "5", crit: "10" }
+    load15: { crit: "3" }
+  worst_status: true
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("--threshold-state").arg("--").arg(&template_path);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("\nload,1,LOAD OK,"))
+        .stdout(predicate::str::contains(
+            "\tload#load1,1,,load1,7.0,,5,10,,,WARNING,value 7 > warn 5,",
+        ))
+        .stdout(predicate::str::contains(
+            "\tload#load5,1,,load5,3.0,,2,4,,,WARNING,value 3 > warn 2,",
+        ))
+        // Both ranges of the plugin are replaced, even if the template only sets one.
+        .stdout(predicate::str::contains(
+            "\tload#load15,0,,load15,1.0,,,3,,,OK,,",
+        ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_template_status_map() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let template_path = dir.path().join("status_map.yaml");
+    std::fs::write(
+        &template_path,
+        r#"
+- name: legacy
+  command: sh -c 'echo FAILED; exit 1'
+  status_map:
+    - exit: 1
+      status: CRITICAL
+- name: maintenance
+  command: sh -c 'echo In maintenance; exit 2'
+  status_map:
+    - output: maintenance
+      status: OK
+- name: unmapped
+  command: echo fine
+- name: not installed
+  command: sh -c 'echo missing; exit 127'
+  status_map:
+    - exit: 127
+      status: WARNING
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("--").arg(&template_path);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "name,status,originalStatus,shortOutput,",
+        ))
+        .stdout(predicate::str::contains("\nlegacy,2,1,FAILED,"))
+        .stdout(predicate::str::contains(
+            "\nmaintenance,0,2,In maintenance,",
+        ))
+        .stdout(predicate::str::contains("\nunmapped,0,,fine,"))
+        .stdout(predicate::str::contains("\nnot installed,1,127,missing,"));
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 #[cfg(any(target_os = "linux", target_os = "macos"))]
@@ -808,7 +1497,7 @@ fn test_output_template_from_opspack_json() -> Result<(), Box<dyn std::error::Er
     cmd.arg("-o").arg(file_1_path);
 
     let binding = cmd.assert().success();
-    let output_string = from_utf8_lossy(&binding.get_output().stdout);
+    let output_string = String::from_utf8_lossy(&binding.get_output().stdout);
 
     assert_eq!(SAMPLE_OPSPACK_AS_TEMPLATE, output_string);
 
@@ -1126,6 +1815,29 @@ fn test_success_single_command_as_option() -> Result<(), Box<dyn std::error::Err
     Ok(())
 }
 
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_multi_line_performance_data() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("-c")
+        .arg("printf 'DISK OK|root=10%%\\n/var ok\\n/tmp ok|var=20%%\\ntmp=30%%\\n'")
+        .arg("-n")
+        .arg("disks");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("disks,0,DISK OK,,,,,,,,printf"))
+        .stdout(predicate::str::contains(
+            "root=10% var=20% tmp=30%,/var ok\\n/tmp ok,",
+        ))
+        .stdout(predicate::str::contains("\tdisks#root,0,,root,10.0,%,"))
+        .stdout(predicate::str::contains("\tdisks#var,0,,var,20.0,%,"))
+        .stdout(predicate::str::contains("\tdisks#tmp,0,,tmp,30.0,%,"));
+
+    Ok(())
+}
+
 #[test]
 fn test_success_single_entry_yaml() -> Result<(), Box<dyn std::error::Error>> {
     let dir = tempdir()?;
@@ -1176,3 +1888,252 @@ fn test_yaml_args_dash_not_breaking_parsing() -> Result<(), Box<dyn std::error::
 
     Ok(())
 }
+
+#[test]
+fn test_builtin_file_check() -> Result<(), Box<dyn std::error::Error>> {
+    let file = NamedTempFile::new()?;
+    let mut cmd = Command::cargo_bin("xtender")?;
+
+    cmd.arg("-c")
+        .arg(format!(
+            "builtin:file --file {} --critical-size 1000",
+            file.path().display()
+        ))
+        .arg("-n")
+        .arg("file");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("\nfile,0,FILE OK - "))
+        .stdout(predicate::str::contains(" size=0B;;1000;0,"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_user_macros_from_resource_file() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let key_file_path = dir.path().join("keyfile");
+    let mut key_file = File::create(&key_file_path)?;
+    writeln!(key_file, "{}", VALID_KEY_FILE_CONTENTS)?;
+
+    let resource_path = dir.path().join("resource.cfg");
+    let mut resource_file = File::create(&resource_path)?;
+    writeln!(resource_file, "# Path to the plugins")?;
+    writeln!(resource_file, "$USER1$=/bin")?;
+    writeln!(resource_file, "$USER3$={}", ENCRYPTED_VAR_EXAMPLE1)?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.arg("-k")
+        .arg(&key_file_path)
+        .arg("--resource-file")
+        .arg(&resource_path)
+        .arg("-c")
+        .arg("$USER1$/echo $USER3$")
+        .arg("-n")
+        .arg("macros");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("\nmacros,0,12345,"))
+        .stdout(predicate::str::contains(",/bin/echo ***,"))
+        .stdout(predicate::str::contains(",USER1=\"/bin\"\\,USER3=***,"));
+
+    Ok(())
+}
+
+/// A directory with a stand-in for `ssh`, which records its arguments and runs the script it's
+/// given on stdin locally, to put first in the `PATH`.
+fn fake_ssh_path(dir: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let ssh_path = dir.join("ssh");
+    std::fs::write(
+        &ssh_path,
+        "#!/bin/sh\necho \"$@\" > \"$(dirname \"$0\")/ssh_args\"\nexec /bin/sh\n",
+    )?;
+    std::fs::set_permissions(&ssh_path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    ))
+}
+
+#[test]
+fn test_command_over_ssh_keeps_secrets_off_argv() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let key_file_path = dir.path().join("keyfile");
+    let mut key_file = File::create(&key_file_path)?;
+    writeln!(key_file, "{}", VALID_KEY_FILE_CONTENTS)?;
+    let resource_path = dir.path().join("resource.cfg");
+    let mut resource_file = File::create(&resource_path)?;
+    writeln!(resource_file, "$USER3$={}", ENCRYPTED_VAR_EXAMPLE1)?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.env("PATH", fake_ssh_path(dir.path())?)
+        .arg("-k")
+        .arg(&key_file_path)
+        .arg("--resource-file")
+        .arg(&resource_path)
+        .arg("-c")
+        .arg("echo $USER3$")
+        .arg("-n")
+        .arg("remote")
+        .arg("--ssh-host")
+        .arg("db1.example.com")
+        .arg("--ssh-user")
+        .arg("nagios")
+        .arg("--ssh-option")
+        .arg("StrictHostKeyChecking=accept-new");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("\nremote,0,12345,"))
+        .stdout(predicate::str::contains(",echo ***,"));
+
+    let ssh_args = std::fs::read_to_string(dir.path().join("ssh_args"))?;
+    assert!(ssh_args.contains("-l nagios -o StrictHostKeyChecking=accept-new -- db1.example.com"));
+    assert!(!ssh_args.contains("12345"));
+
+    Ok(())
+}
+
+#[test]
+fn test_template_check_over_ssh() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let template_path = dir.path().join("remote.yaml");
+    let mut template = File::create(&template_path)?;
+    writeln!(
+        template,
+        r#"
+- name: remote load
+  command: "sh -c 'echo \"LOAD CRITICAL on $CHECK_NAME$|load1=12\"; exit 2'"
+  ssh:
+    host: db1.example.com
+    port: 2222
+    key: /etc/xtender/id_ed25519
+- name: remote uptime
+  command: echo up
+  ssh: db2.example.com
+"#
+    )?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.env("PATH", fake_ssh_path(dir.path())?)
+        .arg("--")
+        .arg(&template_path);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "\nremote load,2,LOAD CRITICAL on remote load,",
+        ))
+        .stdout(predicate::str::contains("\nremote uptime,0,up,"));
+
+    Ok(())
+}
+
+#[test]
+fn test_encrypted_variable_in_ssh_settings() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let key_file_path = dir.path().join("keyfile");
+    std::fs::write(&key_file_path, VALID_KEY_FILE_CONTENTS)?;
+    let template_path = dir.path().join("remote.yaml");
+    std::fs::write(
+        &template_path,
+        r#"
+- name: remote uptime
+  command: echo up
+  ssh:
+    host: db1.example.com
+    user: $SSH_ENCRYPTED_USER$
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.env("SSH_ENCRYPTED_USER", ENCRYPTED_VAR_EXAMPLE1)
+        .env("PATH", fake_ssh_path(dir.path())?)
+        .arg("-k")
+        .arg(&key_file_path)
+        .arg("--")
+        .arg(&template_path);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "The variable \"SSH_ENCRYPTED_USER\" is encrypted, which isn't allowed in the SSH settings",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_resource_file() -> Result<(), Box<dyn std::error::Error>> {
+    let mut resource_file = NamedTempFile::new()?;
+    writeln!(resource_file, "$HOSTADDRESS$=127.0.0.1")?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.arg("--resource-file")
+        .arg(resource_file.path())
+        .arg("-c")
+        .arg("echo $USER1$")
+        .arg("-n")
+        .arg("macros");
+
+    cmd.assert().failure();
+
+    Ok(())
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn test_runtime_macros() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let template_path = dir.path().join("macros.yaml");
+    let mut template = File::create(&template_path)?;
+    writeln!(
+        template,
+        r#"- name: macro check !!A:1..2!!
+  timeout: 7
+  command: |
+    sh -c 'printf "%s %s %s" "$0" "$NAGIOS_CHECK_NAME" "$NAGIOS_RANGE_A"' $TIMEOUT$-$PLUGIN_TIMEOUT$-$TEMPLATE_NAME$-$RANGE_A$-$PLUGIN_DIR$"#
+    )?;
+
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.arg("--plugin-dir")
+        .arg("/plugins")
+        .arg("--")
+        .arg(&template_path);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "\nmacro check 1,0,7-6-macros-1-/plugins macro check 1 1,",
+        ))
+        .stdout(predicate::str::contains(
+            "\nmacro check 2,0,7-6-macros-2-/plugins macro check 2 2,",
+        ))
+        .stdout(predicate::str::contains("TIMEOUT").not());
+
+    Ok(())
+}
+
+#[test]
+fn test_plugin_dir_from_environment_is_a_variable() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("xtender")?;
+    cmd.env("PLUGIN_DIR", "/env/plugins")
+        .env("MACRO_LOOKALIKE", "$TIMEOUT$")
+        .arg("-c")
+        .arg("echo $PLUGIN_DIR$ $MACRO_LOOKALIKE$ $TIMEOUT$")
+        .arg("-n")
+        .arg("plugin dir");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "\nplugin dir,0,/env/plugins $TIMEOUT$ 5,",
+        ))
+        .stdout(predicate::str::contains("PLUGIN_DIR"));
+
+    Ok(())
+}
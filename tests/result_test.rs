@@ -1,5 +1,6 @@
 use geneos_xtender::result::*;
 use pretty_assertions::assert_eq;
+use std::time::Duration;
 
 const COLUMNS: &str = "name,status,shortOutput,label,value,uom,warn,crit,min,max,command,performanceDataString,longOutput,executionTime,variablesFound,variablesNotFound";
 
@@ -116,3 +117,184 @@ fn test_check_results_as_csv_warning_check_with_multiple_perfdata() {
 
     test_check_results_as_csv(c, e);
 }
+
+#[test]
+fn test_check_results_as_csv_with_resource_usage_columns() {
+    let c = CheckResultBuilder::new()
+        .name("Usage")
+        .command("echo usage")
+        .status(0)
+        .parse_output("usage|a=1 b=2")
+        .with_resource_usage(geneos_xtender::process::ResourceUsage {
+            user_cpu: std::time::Duration::from_millis(12),
+            system_cpu: std::time::Duration::from_millis(3),
+            max_rss: 2048,
+        })
+        .build();
+
+    let options = ProcessOptions {
+        resource_usage: true,
+        ..ProcessOptions::default()
+    };
+
+    let csv_results = CheckResults(vec![c])
+        .process_with(&options)
+        .as_csv_string()
+        .unwrap();
+
+    let e = vec![
+        "name,status,shortOutput,label,value,uom,warn,crit,min,max,command,performanceDataString,longOutput,executionTime,userCpuTime,systemCpuTime,maxRss,variablesFound,variablesNotFound",
+        "Usage,0,usage,,,,,,,,echo usage,a=1 b=2,,,0.0120 s,0.0030 s,2048 KiB,,",
+        "\tUsage#a,0,,a,1.0,,,,,,,,,,,,,,",
+        "\tUsage#b,0,,b,2.0,,,,,,,,,,,,,,",
+    ];
+
+    assert_eq!(csv_results.lines().collect::<Vec<_>>(), e);
+}
+
+#[test]
+fn test_result_age_columns() {
+    let fresh = CheckResultBuilder::new()
+        .name("Fresh")
+        .command("echo fresh")
+        .status(0)
+        .parse_output("fresh")
+        .build();
+    let cached = CheckResultBuilder::new()
+        .name("Cached")
+        .command("echo cached")
+        .status(0)
+        .parse_output("cached|a=1")
+        .build()
+        .with_cache_age(Duration::from_secs(30), Duration::from_secs(60));
+    let stale = CheckResultBuilder::new()
+        .name("Stale")
+        .command("echo stale")
+        .status(0)
+        .parse_output("stale")
+        .build()
+        .with_cache_age(Duration::from_secs(90), Duration::from_secs(60));
+
+    let options = ProcessOptions {
+        result_age: true,
+        ..ProcessOptions::default()
+    };
+
+    let csv_results = CheckResults(vec![fresh, cached, stale])
+        .process_with(&options)
+        .as_csv_string()
+        .unwrap();
+
+    let e = vec![
+        "name,status,shortOutput,label,value,uom,warn,crit,min,max,command,performanceDataString,longOutput,executionTime,resultAge,stale,variablesFound,variablesNotFound",
+        "Fresh,0,fresh,,,,,,,,echo fresh,,,,0 s,false,,",
+        "Cached,0,cached,a,1.0,,,,,,echo cached,a=1,,,30 s,false,,",
+        "Stale,0,stale,,,,,,,,echo stale,,,,90 s,true,,",
+    ];
+
+    assert_eq!(csv_results.lines().collect::<Vec<_>>(), e);
+}
+
+#[test]
+fn test_original_status_column() {
+    let status_map = vec![geneos_xtender::status_map::StatusRule {
+        exit: Some(1),
+        output: None,
+        status: 2,
+    }];
+    let mapped = CheckResultBuilder::new()
+        .name("Legacy")
+        .command("legacy.sh")
+        .status(1)
+        .short_output("FAILED")
+        .status_map(&status_map)
+        .build();
+    let unmapped = CheckResultBuilder::new()
+        .name("Other")
+        .command("echo ok")
+        .status(0)
+        .short_output("ok")
+        .build();
+
+    assert_eq!(mapped.status(), Some(2));
+    assert_eq!(mapped.original_status(), Some(1));
+
+    let options = ProcessOptions {
+        original_status: true,
+        ..ProcessOptions::default()
+    };
+
+    let csv_results = CheckResults(vec![mapped, unmapped])
+        .process_with(&options)
+        .as_csv_string()
+        .unwrap();
+
+    let e = vec![
+        "name,status,originalStatus,shortOutput,label,value,uom,warn,crit,min,max,command,performanceDataString,longOutput,executionTime,variablesFound,variablesNotFound",
+        "Legacy,2,1,FAILED,,,,,,,,legacy.sh,,,,,",
+        "Other,0,,ok,,,,,,,,echo ok,,,,,",
+    ];
+
+    assert_eq!(csv_results.lines().collect::<Vec<_>>(), e);
+}
+
+#[test]
+fn test_metrics_extracted_from_output() {
+    let rules = vec![geneos_xtender::extract::ExtractRule::new(
+        geneos_xtender::status_map::OutputPattern::new(r"Queue depth is (?P<value>\d+)").unwrap(),
+    )
+    .label("queue_depth")
+    .warn("100")
+    .crit("500")];
+    let c = CheckResultBuilder::new()
+        .name("Queue")
+        .command("queue.sh")
+        .status(0)
+        .parse_output("OK - Queue depth is 421|consumers=3")
+        .extract(&rules)
+        .build();
+
+    let csv_results = CheckResults(vec![c]).process().as_csv_string().unwrap();
+
+    let e = vec![
+        COLUMNS,
+        "Queue,0,OK - Queue depth is 421,,,,,,,,queue.sh,consumers=3 queue_depth=421;100;500,,,,",
+        "\tQueue#consumers,0,,consumers,3.0,,,,,,,,,,,",
+        "\tQueue#queue_depth,1,,queue_depth,421.0,,100,500,,,,,,,,",
+    ];
+
+    assert_eq!(csv_results.lines().collect::<Vec<_>>(), e);
+}
+
+#[test]
+fn test_normalized_unit_columns() {
+    let c = CheckResultBuilder::new()
+        .name("Disk")
+        .command("check_disk")
+        .status(0)
+        .parse_output(
+            "DISK OK|/=2643MB;5948;5958;0;5968 time=245ms;~:500;@1000:2000 load=1.5;2;3 rate=12B/s",
+        )
+        .build();
+
+    let options = ProcessOptions {
+        normalized_units: true,
+        ..ProcessOptions::default()
+    };
+
+    let csv_results = CheckResults(vec![c])
+        .process_with(&options)
+        .as_csv_string()
+        .unwrap();
+
+    let e = vec![
+        "name,status,shortOutput,label,value,uom,warn,crit,min,max,normalizedValue,normalizedUom,normalizedWarn,normalizedCrit,normalizedMin,normalizedMax,command,performanceDataString,longOutput,executionTime,variablesFound,variablesNotFound",
+        "Disk,0,DISK OK,,,,,,,,,,,,,,check_disk,/=2643MB;5948;5958;0;5968 time=245ms;~:500;@1000:2000 load=1.5;2;3 rate=12B/s,,,,",
+        "\tDisk#/,0,,/,2643.0,MB,5948,5958,0,5968,2771386368,B,6236930048,6247415808,0,6257901568,,,,,,",
+        "\tDisk#time,0,,time,245.0,ms,~:500,@1000:2000,,,0.245,s,~:0.5,@1:2,,,,,,,,",
+        "\tDisk#load,0,,load,1.5,,2,3,,,1.5,,2,3,,,,,,,,",
+        "\tDisk#rate,0,,rate,12.0,B/s,,,,,12,B/s,,,,,,,,,,",
+    ];
+
+    assert_eq!(csv_results.lines().collect::<Vec<_>>(), e);
+}